@@ -53,7 +53,7 @@ DEFAULT_CRATE_OPT := 2
 #
 # Set the number of disks that we should be launching
 #
-        NDISKS=1
+        NDISKS=2
 
 # Switches for non-required components. If you wish to try implementing
 # some extra features in Weenix, there are some pre-designed features
@@ -75,6 +75,7 @@ DEFAULT_CRATE_OPT := 2
            PIPES=0 # pipe(2) functionality
          SHADOWD=0 # shadow page cleanup
           COLORS=1 # Should debug messages have colors
+         LOCKDEP=0 # Check the order kernel mutexes are taken in for possible deadlocks
 
 # These are some options that can be used for stress testing stuff
        SMALL_PID=0 # Make a process id be a u8, so it will overflow and run out quickly.
@@ -103,6 +104,7 @@ DEFAULT_CRATE_OPT := 2
 							 REAL_SPIN_ONCE \
 							 TEST_LOW_MEMORY \
 							 COLORS \
+							 LOCKDEP \
 							 SMALL_PID
 
 # As above, but not booleans
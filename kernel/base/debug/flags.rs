@@ -75,6 +75,10 @@ dbg_modes!{
     (PCACHE,      39, color::BMAGENTA,"pinnable cache system"),
     (KSHELL,      40, color::BBLUE   ,"Kshell messages"),
     (TIME,        41, color::NORMAL,  "Timing message"),
+    (SIGNAL,      42, color::BYELLOW, "signal delivery"),
+    (LOCKDEP,     43, color::BRED,    "lock order checking"),
+    (RMAP,        44, color::CYAN,    "reverse mappings of user pages"),
+    (SWAP,        45, color::BBLUE,   "swapping anonymous memory"),
 
     (DANGER,      62, color::RED,     "A likely very dangerous operation"),
 
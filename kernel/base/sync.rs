@@ -15,6 +15,10 @@ const INITIALIZED  : usize = 2;
 
 /// A once implementation that will have other threads spin when the initialization is being done.
 /// Use only with extreeme caution. Prefer procs::sync::Once if possible.
+///
+/// This relies on a thread never being switched away from in the middle of the initialization
+/// unless it blocks. Even with UPREEMPT this holds since threads are only ever preempted on their
+/// way back to user mode.
 pub struct SpinOnce {
     /// What the current state is.
     state: AtomicUsize,
@@ -47,11 +51,11 @@ impl SpinOnce {
             if is_enabled!(REAL_SPIN_ONCE) {
                 dbg!(debug::DANGER | debug::SCHED,
                     "Entered SpinOnce::try_it and it was initializing, \
-                     Since no kernel preempt this might spin forever!");
+                     Since kernel code is never preempted this might spin forever!");
                 while self.state.load(SeqCst) != INITIALIZING { }
             } else {
                 kpanic!("Entered SpinOnce::try_it and it was initializing, \
-                         Since no kernel preempt this would probably spin forever!");
+                         Since kernel code is never preempted this would probably spin forever!");
             }
             false
         }
@@ -7,7 +7,7 @@ use DeviceId;
 use base::cell::*;
 use base::{io, kernel};
 use blockdev::disk::dma;
-use procs::interrupt;
+use procs::{interrupt, resource};
 use procs::sync::*;
 use base::errno::{KResult, self};
 use libc::c_void;
@@ -24,7 +24,7 @@ mod irqs {
 
 const BLOCK_SIZE : usize = page::SIZE;
 pub const DISK_MAJOR : u8 = 1;
-pub const NDISKS : usize = 1;
+pub const NDISKS : usize = 2;
 const IDENT_BUFSIZE : usize = 256;
 
 #[repr(u8)]
@@ -275,7 +275,9 @@ impl Debug for ATADisk {
     }
 }
 
-impl ::blockdev::BlockDevice for SafeCell<ATADisk> {}
+impl ::blockdev::BlockDevice for SafeCell<ATADisk> {
+    fn num_blocks(&self) -> usize { self.get_ref().size / self.get_ref().sectors_per_block }
+}
 
 impl ATADisk {
     fn create(channel: Channel, is_master: bool, size: usize, sectors_per_block: usize) -> ATADisk {
@@ -374,6 +376,7 @@ impl RDeviceMut<[u8; page::SIZE]> for ATADisk {
         for i in 0..buf.len() {
             try!(self.read_single(offset + i, &mut buf[i]));
         }
+        resource::count_blocks_in(buf.len());
         Ok(buf.len())
     }
 }
@@ -386,6 +389,7 @@ impl WDeviceMut<[u8; page::SIZE]> for ATADisk {
             dbg!(debug::DISK, "starting write of page {} to block {}", i, offset + i);
             try!(self.write_single(offset + i, &buf[i]));
         }
+        resource::count_blocks_out(buf.len());
         Ok(buf.len())
     }
 }
@@ -9,5 +9,7 @@ pub fn init_stage2() {
     dma::init_stage2();
 }
 
+pub use self::ata::DISK_MAJOR;
+
 mod ata;
 mod dma;
@@ -3,9 +3,11 @@
 
 use std::rc::*;
 use std::collections::*;
+use base::errno::{self, KResult};
 use mm::page;
-use super::{DeviceId, Device};
+use super::{DeviceId, Device, RDevice, WDevice};
 use umem::mmobj::*;
+use umem::swap;
 
 pub fn init_stage1() { disk::init_stage1(); }
 pub fn init_stage2() {
@@ -14,7 +16,13 @@ pub fn init_stage2() {
 }
 pub fn init_stage3() {}
 
-pub trait BlockDevice : Device<[u8; page::SIZE]> + MMObj {}
+pub trait BlockDevice : Device<[u8; page::SIZE]> + MMObj {
+    /// How many blocks the device holds.
+    fn num_blocks(&self) -> usize;
+}
+
+/// The device we swap to unless told otherwise, the second ATA disk.
+pub const SWAP_DEVICE : DeviceId = DeviceId_static!(disk::DISK_MAJOR, 1);
 
 /// What we give out to those who want block devices.
 pub type ExternBlockDevice = Rc<Box<BlockDevice>>;
@@ -38,8 +46,36 @@ pub fn lookup(dev: DeviceId) -> Option<ExternBlockDevice> { get_device_tree().ge
 pub fn register(id: DeviceId, dev: Box<BlockDevice>) -> bool {
     block_interrupts!({
         let m = get_device_tree();
-        if m.contains_key(&id) { false } else { m.insert(id, Rc::new(dev)).is_none() }
+        if m.contains_key(&id) {
+            false
+        } else {
+            match alloc!(try m.insert(id, Rc::new(dev))) {
+                Ok(old) => old.is_none(),
+                Err(_) => { m.remove(&id); false },
+            }
+        }
     })
 }
 
+/// A block device being used for swap. Swap lives below us so it only gets to see it as a device.
+struct SwapDisk(ExternBlockDevice);
+
+impl RDevice<[u8; page::SIZE]> for SwapDisk {
+    fn read_from(&self, offset: usize, buf: &mut [[u8; page::SIZE]]) -> KResult<usize> { self.0.read_from(offset, buf) }
+}
+
+impl WDevice<[u8; page::SIZE]> for SwapDisk {
+    fn write_to(&self, offset: usize, buf: &[[u8; page::SIZE]]) -> KResult<usize> { self.0.write_to(offset, buf) }
+}
+
+impl Device<[u8; page::SIZE]> for SwapDisk {}
+
+/// Start swapping to the whole of the block device `id`. Anything on it is overwritten.
+pub fn swapon(id: DeviceId) -> KResult<()> {
+    let dev = try!(lookup(id).ok_or(errno::ENODEV));
+    let nblocks = dev.num_blocks();
+    let sd = try!(alloc!(try box SwapDisk(dev)).or(Err(errno::ENOMEM)));
+    swap::swapon(id, sd as Box<Device<[u8; page::SIZE]> + 'static>, nblocks)
+}
+
 mod disk;
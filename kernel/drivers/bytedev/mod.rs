@@ -5,7 +5,7 @@ use super::{DeviceId, Device, WDevice};
 use std::collections::*;
 use std::fmt;
 
-mod tty;
+pub mod tty;
 
 /// Do initialization that does not require allocating memory.
 pub fn init_stage1() {
@@ -51,7 +51,14 @@ pub fn lookup(dev: DeviceId) -> Option<&'static (Device<u8> + 'static)> {
 
 pub fn register(id: DeviceId, dev: Box<Device<u8> + 'static>) -> bool {
     let m = get_device_tree();
-    if m.contains_key(&id) { false } else { m.insert(id, dev).is_none() }
+    if m.contains_key(&id) {
+        false
+    } else {
+        match alloc!(try m.insert(id, dev)) {
+            Ok(old) => old.is_none(),
+            Err(_) => { m.remove(&id); false },
+        }
+    }
 }
 
 pub struct ByteWriter<'a>(pub &'a mut Device<u8>);
@@ -74,6 +74,7 @@ const SHIFT_SCANCODES  : &'static str = "\x00\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\
                                                 "\x00\x00\x00",
                                                 " ");*/
 
+#[derive(Clone, Copy, Debug)]
 pub enum Event {
     Normal(u8), Switch(u8), ScrollUp, ScrollDown,
 }
@@ -3,7 +3,11 @@
 //! The Reenix tty support module.
 
 use base::cell::*;
-use base::errno::KResult;
+use base::errno::{self, KResult};
+use procs::kproc::ProcId;
+use procs::{interrupt, session, signal};
+use procs::workqueue::{self, Work};
+use DeviceId;
 use RDeviceMut;
 use WDeviceMut;
 
@@ -19,6 +23,8 @@ pub fn init_stage2() {
     virtterm::init_stage2();
     ldisc::init_stage2();
     // TODO TTY INIT
+    let w : Box<Work> = box Work::new(run_key_events, 0);
+    unsafe { KEY_WORK = ::std::mem::transmute(w); }
     keyboard::get_keyboard().set_handler(handle_keyboard_input);
     create_ttys();
     unsafe { TTYS[CUR_TTY_ID as usize].as_mut().expect("One of the ttys is null").set_active(); }
@@ -72,9 +78,10 @@ static mut CUR_TTY_ID : u8 = 0;
 static mut TTYS : [*mut TTY; (NUM_TTYS as usize)] = [0 as *mut TTY; (NUM_TTYS as usize)];
 fn create_ttys() {
     for i in 0..NUM_TTYS {
-        let t = box SafeCell::new(TTY::create(box virtterm::VirtualTerminal::create(), box ldisc::LineDiscipline::create()));
+        let id = DeviceId::create(TTY_MAJOR, i);
+        let t = box SafeCell::new(TTY::create(id, box virtterm::VirtualTerminal::create(), box ldisc::LineDiscipline::create()));
         unsafe { TTYS[i as usize] = (&*t.get_ref()) as *const TTY as *mut TTY; }
-        super::register(::DeviceId::create(TTY_MAJOR, i), t);
+        super::register(id, t);
     }
 }
 
@@ -93,20 +100,111 @@ fn get_current_tty() -> &'static mut TTY {
     unsafe { TTYS[n as usize].as_mut().expect("One of the ttys is null") }
 }
 
+fn get_tty(dev: DeviceId) -> Option<&'static mut TTY> {
+    if dev.get_major() != TTY_MAJOR || dev.get_minor() >= NUM_TTYS {
+        None
+    } else {
+        unsafe { TTYS[dev.get_minor() as usize].as_mut() }
+    }
+}
+
+/// Make `dev` the controlling terminal of the current session, which the current process must be
+/// the leader of. This is what TIOCSCTTY does.
+pub fn set_controlling_tty(dev: DeviceId) -> KResult<()> {
+    let t = try!(get_tty(dev).ok_or(errno::ENOTTY));
+    if t.get_session().is_some() {
+        return Err(errno::EPERM);
+    }
+    try!(session::set_ctty(dev));
+    t.session = Some((current_proc!()).get_sid());
+    Ok(())
+}
+
+/// Get the foreground process group of `dev`, which must be our controlling terminal.
+pub fn tcgetpgrp(dev: DeviceId) -> KResult<ProcId> {
+    let sid = (current_proc!()).get_sid();
+    if session::get_ctty(sid) != Some(dev) {
+        return Err(errno::ENOTTY);
+    }
+    session::get_foreground(sid).ok_or(errno::ENOTTY)
+}
+
+/// Set the foreground process group of `dev`, which must be our controlling terminal.
+pub fn tcsetpgrp(dev: DeviceId, pgid: ProcId) -> KResult<()> {
+    if session::get_ctty((current_proc!()).get_sid()) != Some(dev) {
+        return Err(errno::ENOTTY);
+    }
+    session::set_foreground(pgid)
+}
+
+/// The char that sends SIGINT to the foreground group (^C).
+const INTR_CHAR : u8 = 0x03;
+/// The char that sends SIGQUIT to the foreground group (^\).
+const QUIT_CHAR : u8 = 0x1c;
+/// The char that sends SIGTSTP to the foreground group (^Z).
+const SUSP_CHAR : u8 = 0x1a;
+
 struct TTY {
+    id         : DeviceId,
     driver     : Box<TTYDriver + 'static>,
     discipline : Box<TTYLineDiscipline + 'static>,
+    /// The session we are the controlling terminal of.
+    session    : Option<ProcId>,
 }
 
 impl TTY {
-    pub fn create(driver: Box<TTYDriver + 'static>, disc: Box<TTYLineDiscipline + 'static>) -> TTY {
-        TTY { driver: driver, discipline : disc }
+    pub fn create(id: DeviceId, driver: Box<TTYDriver + 'static>, disc: Box<TTYLineDiscipline + 'static>) -> TTY {
+        TTY { id: id, driver: driver, discipline : disc, session: None }
+    }
+
+    /// The session we are the controlling terminal of. We find out the session has gone away by
+    /// it no longer having us.
+    fn get_session(&mut self) -> Option<ProcId> {
+        if let Some(sid) = self.session {
+            if session::get_ctty(sid) != Some(self.id) {
+                self.session = None;
+            }
+        }
+        self.session
     }
 
-    /// This function is called from the interrupt handler to take in the recieved char and echo it
+    /// Send a signal to our foreground process group, if we have one.
+    fn signal_foreground(&mut self, sig: signal::Signal) {
+        if let Some(pgid) = self.get_session().and_then(|sid| { session::get_foreground(sid) }) {
+            dbg!(debug::TERM, "{:?} sending signal {} to group {:?}", self.id, sig, pgid);
+            let _ = signal::killpg(pgid, sig);
+        }
+    }
+
+    /// This function is called from the system workqueue to take in the recieved char and echo it
     /// to the driver.
     fn handle_char(&mut self, chr: u8) {
-        self.driver.echo(self.discipline.recieve_char(chr));
+        let sig = match chr {
+            INTR_CHAR => signal::SIGINT,
+            QUIT_CHAR => signal::SIGQUIT,
+            SUSP_CHAR => signal::SIGTSTP,
+            _ => { self.driver.echo(self.discipline.recieve_char(chr)); return; },
+        };
+        self.driver.echo(match chr { INTR_CHAR => "^C\n", QUIT_CHAR => "^\\\n", _ => "^Z\n" });
+        self.signal_foreground(sig);
+    }
+
+    /// Check that the current process may read from us. Processes in the background of their
+    /// controlling terminal get SIGTTIN, or EIO if they will not notice it or their group is
+    /// orphaned, since then nobody would ever continue them.
+    fn check_read_allowed(&mut self) -> KResult<()> {
+        let sid = (current_proc!()).get_sid();
+        if self.get_session() != Some(sid) || session::is_foreground() {
+            return Ok(());
+        }
+        let pgid = (current_proc!()).get_pgid();
+        if (current_proc!()).is_ignored(signal::SIGTTIN) || (current_thread!()).sigmask.contains(signal::SIGTTIN) ||
+           session::is_orphaned(pgid) {
+            return Err(errno::EIO);
+        }
+        dbg!(debug::TERM, "background group {:?} tried to read {:?}", pgid, self.id);
+        let _ = signal::killpg(pgid, signal::SIGTTIN);
+        Err(errno::EINTR)
     }
     /// This function asks the driver to scroll.
     fn scroll(&mut self, dir: ScrollDirection) { self.driver.scroll(dir) }
@@ -120,6 +218,7 @@ impl TTY {
 impl RDeviceMut<u8> for TTY {
     #[allow(unused_variables)]
     fn read_from(&mut self, offset : usize, buf: &mut [u8]) -> KResult<usize> {
+        try!(self.check_read_allowed());
         let blocker = self.driver.block_io();
         let res = self.discipline.read_from(offset, buf);
         drop(blocker);
@@ -137,13 +236,62 @@ impl WDeviceMut<u8> for TTY {
     }
 }
 
+/// How many key events can be waiting to be handled.
+const NUM_EVENTS : usize = 32;
+
+/// Key events the keyboard interrupt has given us that have not been handled yet. They are only
+/// touched at or above the keyboard's IPL.
+static mut EVENTS : [Option<keyboard::Event>; NUM_EVENTS] = [None; NUM_EVENTS];
+static mut EVENTS_HEAD : usize = 0;
+static mut EVENTS_LEN : usize = 0;
+
+/// The work that hands waiting key events to the ttys.
+static mut KEY_WORK : *mut Work = 0 as *mut Work;
+
+fn get_key_work() -> &'static mut Work { unsafe { KEY_WORK.as_mut().expect("key event work is null") } }
+
+/// This is called from the keyboard interrupt handler. Echoing and sending signals is too much to do
+/// there, so the event is left for the system workqueue unless it is not running yet.
 extern "Rust" fn handle_keyboard_input(event: keyboard::Event) {
-    let ct = get_current_tty();
-    match event {
-        keyboard::Event::Normal(chr) => ct.handle_char(chr),
-        keyboard::Event::Switch(n) => switch_tty(n),
-        keyboard::Event::ScrollUp => ct.scroll(ScrollDirection::UP),
-        keyboard::Event::ScrollDown => ct.scroll(ScrollDirection::DOWN),
+    unsafe {
+        if EVENTS_LEN == NUM_EVENTS {
+            dbg!(debug::TERM, "dropping {:?}, too many key events are waiting", event);
+            return;
+        }
+        EVENTS[(EVENTS_HEAD + EVENTS_LEN) % NUM_EVENTS] = Some(event);
+        EVENTS_LEN += 1;
+    }
+    if workqueue::is_running() {
+        workqueue::queue_work(get_key_work());
+    } else {
+        run_key_events(0);
+    }
+}
+
+/// Take the oldest waiting key event, if there is one. The keyboard is kept out only while we do.
+fn next_key_event() -> Option<keyboard::Event> {
+    let _ipl = interrupt::temporary_ipl(::std::cmp::max(interrupt::KEYBOARD, interrupt::get_ipl()));
+    unsafe {
+        if EVENTS_LEN == 0 {
+            return None;
+        }
+        let e = EVENTS[EVENTS_HEAD].take();
+        EVENTS_HEAD = (EVENTS_HEAD + 1) % NUM_EVENTS;
+        EVENTS_LEN -= 1;
+        Some(e.expect("waiting key event is missing"))
+    }
+}
+
+/// Hand all of the waiting key events to the current tty.
+fn run_key_events(_: usize) {
+    while let Some(event) = next_key_event() {
+        let ct = get_current_tty();
+        match event {
+            keyboard::Event::Normal(chr) => ct.handle_char(chr),
+            keyboard::Event::Switch(n) => switch_tty(n),
+            keyboard::Event::ScrollUp => ct.scroll(ScrollDirection::UP),
+            keyboard::Event::ScrollDown => ct.scroll(ScrollDirection::DOWN),
+        }
     }
 }
 
@@ -10,11 +10,17 @@
 #define GDT_USER_TEXT   0x18
 #define GDT_USER_DATA   0x20
 #define GDT_TSS         0x28
+#define GDT_DOUBLE_FAULT_TSS 0x30
+#define GDT_EMERGENCY_TSD    0x38
+#define GDT_USER_TLS         0x48
 
 void gdt_init(void);
 
 void gdt_set_kernel_stack(void *addr);
 
+void gdt_set_double_fault_task(void (*entry)(void), void *stack, uint16_t gs);
+void gdt_get_interrupted_task(uint32_t *eip, uint32_t *esp);
+
 void gdt_set_entry(uint32_t segment, uint32_t base, uint32_t limit,
                    uint8_t ring, int exec, int dir, int rw);
 void gdt_clear(uint32_t segment);
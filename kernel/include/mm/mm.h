@@ -10,12 +10,6 @@
 #define PTR_SIZE (sizeof(void *))
 #define PTR_MASK (PTR_SIZE - 1)
 
-/* Performs all initialization necessary for the
- * page allocation system. This should be called
- * only once at boot time before any other functions
- * in this header are called. */
-void page_init();
-
 /* This is the first step of initializing the page table system. It
  * replaces the temporary page table set up by the boot loader with
  * the page directory and first 2 page tables of the permenant page
@@ -3,6 +3,7 @@
 #include "main/io.h"
 #include "main/acpi.h"
 #include "main/cpuid.h"
+#include "main/interrupt.h"
 
 #include "mm/page.h"
 #include "mm/pagetable.h"
@@ -274,14 +275,14 @@ void apic_enable_periodic_timer(uint32_t freq) {
 	while(!(inb(0x61) & 0x20));
 	/* Stop the APIC timer */
 	*(uint32_t*)(apic->at_addr + LOCAL_APIC_LVT_TMR) = LOCAL_APIC_DISABLE;
-	/* some math */
-	cpubusfreq = ((0xffffffff - *(uint32_t*)(apic->at_addr + LOCAL_APIC_TMRINITCNT)) + 1) * 16 * 100;
-	tmp = (cpubusfreq / freq / 16) * 10000;
+	/* some math: the PIT one-shot above lasts 10ms and the timer divides the bus clock by 16 */
+	cpubusfreq = ((0xffffffff - *(uint32_t*)(apic->at_addr + LOCAL_APIC_TMRCURRCNT)) + 1) * 16 * 100;
+	tmp = cpubusfreq / freq / 16;
 	dbgq(DBG_CORE, "CPU Bus Freq: %u\n", cpubusfreq);
 	dbgq(DBG_CORE, "APIC Timer initial count %u\n", tmp);
 	/* Set up the APIC timer for periodic mode */
 	*(uint32_t*)(apic->at_addr + LOCAL_APIC_TMRINITCNT) = (tmp < 16 ? 16 : tmp);
-	*(uint32_t*)(apic->at_addr + LOCAL_APIC_LVT_TMR) = 32 | LOCAL_APIC_TMR_PERIODIC;
+	*(uint32_t*)(apic->at_addr + LOCAL_APIC_LVT_TMR) = INTR_APICTIMER | LOCAL_APIC_TMR_PERIODIC;
 	*(uint32_t*)(apic->at_addr + LOCAL_APIC_TMRDIV) = 0x03;
 }
 
@@ -49,6 +49,8 @@ struct gdt_location {
 
 static struct gdt_entry gdt[GDT_COUNT];
 static struct tss_entry tss;
+/* The task we switch to on a double fault, so that it runs on a stack we know is good. */
+static struct tss_entry df_tss;
 static struct gdt_location gdtl = {
         .gl_size = GDT_COUNT * 8,
         .gl_offset = (uint32_t) &gdt
@@ -96,6 +98,40 @@ void gdt_set_kernel_stack(void *addr)
         tss.ts_esp0 = (uint32_t)addr;
 }
 
+void gdt_set_double_fault_task(void (*entry)(void), void *stack, uint16_t gs)
+{
+        gdt_set_entry(GDT_DOUBLE_FAULT_TSS, (uint32_t)&df_tss, sizeof(df_tss), 0, 1, 0, 0);
+        gdt[GDT_DOUBLE_FAULT_TSS / 8].ge_access &= ~(0b10000);
+        gdt[GDT_DOUBLE_FAULT_TSS / 8].ge_access |= 0b1;
+        gdt[GDT_DOUBLE_FAULT_TSS / 8].ge_flags &= ~(0b10000000);
+
+        /* The kernel is mapped the same in every page directory so the current one will do. */
+        uint32_t cr3;
+        __asm__ volatile("movl %%cr3, %0" : "=r"(cr3));
+
+        memset(&df_tss, 0, sizeof(df_tss));
+        df_tss.ts_cr3 = cr3;
+        df_tss.ts_eip = (uint32_t)entry;
+        df_tss.ts_eflags = 0x2; /* Interrupts off. */
+        df_tss.ts_esp = (uint32_t)stack;
+        df_tss.ts_esp0 = (uint32_t)stack;
+        df_tss.ts_cs = GDT_KERNEL_TEXT;
+        df_tss.ts_ss = GDT_KERNEL_DATA;
+        df_tss.ts_ss0 = GDT_KERNEL_DATA;
+        df_tss.ts_ds = GDT_KERNEL_DATA;
+        df_tss.ts_es = GDT_KERNEL_DATA;
+        df_tss.ts_fs = GDT_KERNEL_DATA;
+        df_tss.ts_gd = gs;
+        df_tss.ts_iopb = sizeof(df_tss);
+}
+
+void gdt_get_interrupted_task(uint32_t *eip, uint32_t *esp)
+{
+        /* The state of whatever was running when we switched tasks is saved in the normal tss. */
+        *eip = tss.ts_eip;
+        *esp = tss.ts_esp;
+}
+
 void gdt_set_entry(uint32_t segment, uint32_t base, uint32_t limit,
                    uint8_t ring, int exec, int dir, int rw)
 {
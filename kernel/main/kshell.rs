@@ -7,11 +7,12 @@ use libc::c_void;
 use mm::{alloc, page};
 use procs::args::ProcArgs;
 use procs::interrupt;
-use procs::kproc::{KProc, self};
+use procs::kproc::{KProc, ProcStatus, self};
 use std::cell::*;
 use std::collections::*;
 use std::fmt::{self, Write};
 use std::str::{FromStr, from_utf8};
+use umem::swap;
 
 /// Just a wraper to writeln! or panic.
 macro_rules! twriteln {
@@ -124,6 +125,14 @@ static NFUNCS : &'static [KFunction<'static>] = &[
     KFunc!("pid", "prints current pid", do_pid),
     KFunc!("cancel", "cancels a pid", do_cancel),
     KFunc!("time-mutex", "runs mutex time comparison", do_time_mutex),
+    KFunc!("sched", "prints or sets the scheduling policy", do_sched),
+    KFunc!("nice", "prints or sets the nice value of a pid", do_nice),
+    KFunc!("uptime", "prints the time since boot and the wall-clock time", do_uptime),
+    KFunc!("sleep", "sleeps for the given number of seconds", do_sleep),
+    KFunc!("kstack", "prints how much kernel stack has been used", do_kstack),
+    KFunc!("swapon", "starts swapping to the given block device, the second disk by default", do_swapon),
+    KFunc!("swapoff", "brings everything back in from swap and stops swapping", do_swapoff),
+    KFunc!("swap-stats", "prints how much swap is in use", do_swapstats),
 ];
 
 impl<'a> KShell<'a> {
@@ -209,6 +218,44 @@ impl<'a> fmt::Debug for Showwer<'a> {
 fn do_memstats(io: &mut Device<u8>, _: &[&str]) -> KResult<()> {
     twriteln!(io, "{:?}", Showwer(alloc::get_stats()));
     alloc::stats_print();
+    do_swapstats(io, &[])
+}
+
+fn do_swapon(io: &mut Device<u8>, argv: &[&str]) -> KResult<()> {
+    let id = match argv.len() {
+        1 => blockdev::SWAP_DEVICE,
+        3 => match (FromStr::from_str(argv[1]), FromStr::from_str(argv[2])) {
+            (Ok(major), Ok(minor)) => DeviceId::create(major, minor),
+            _ => {
+                twriteln!(io, "Illegal device {:?} {:?}", argv[1], argv[2]);
+                return Ok(());
+            },
+        },
+        _ => {
+            twriteln!(io, "Usage: swapon [major minor]");
+            return Ok(());
+        },
+    };
+    if let Err(e) = blockdev::swapon(id) {
+        twriteln!(io, "Unable to swap to {:?}: {:?}", id, e);
+    }
+    Ok(())
+}
+
+fn do_swapoff(io: &mut Device<u8>, _: &[&str]) -> KResult<()> {
+    if let Err(e) = swap::swapoff() {
+        twriteln!(io, "Unable to stop swapping: {:?}", e);
+    }
+    Ok(())
+}
+
+fn do_swapstats(io: &mut Device<u8>, _: &[&str]) -> KResult<()> {
+    match swap::usage() {
+        Some((id, used, total)) => {
+            twriteln!(io, "swap on {:?}: {}k used of {}k", id, used * page::SIZE / 1024, total * page::SIZE / 1024);
+        },
+        None => { twriteln!(io, "not swapping"); },
+    }
     Ok(())
 }
 
@@ -329,7 +376,7 @@ fn do_cancel(io: &mut Device<u8>, argv: &[&str]) -> KResult<()> {
     } else {
         match KProc::get_proc(&pid) {
             Some(p) => {
-                p.borrow_mut().kill(exit_status);
+                p.borrow_mut().kill(ProcStatus::exited(exit_status));
                 twriteln!(io, "canceled process {:?} with status {:?}", pid, exit_status);
             },
             None => {
@@ -340,6 +387,98 @@ fn do_cancel(io: &mut Device<u8>, argv: &[&str]) -> KResult<()> {
     return Ok(());
 }
 
+/// Print or change the scheduling policy.
+fn do_sched(io: &mut Device<u8>, argv: &[&str]) -> KResult<()> {
+    use procs::sched;
+    if argv.len() > 2 {
+        twriteln!(io, "Usage: sched [policy]");
+        return Ok(());
+    }
+    if let Some(name) = argv.get(1) {
+        if sched::set_policy(*name).is_err() {
+            twriteln!(io, "Unknown policy {:?}, choices are {:?}", name, sched::POLICIES);
+            return Ok(());
+        }
+    }
+    twriteln!(io, "scheduling policy is {}", sched::get_policy());
+    Ok(())
+}
+
+/// Print or change the nice value of a process.
+fn do_nice(io: &mut Device<u8>, argv: &[&str]) -> KResult<()> {
+    use procs::sched;
+    use procs::kproc::ProcId;
+    if argv.len() < 2 || argv.len() > 3 {
+        twriteln!(io, "Usage: nice pid [value]");
+        return Ok(());
+    }
+    let pid = match FromStr::from_str(argv[1]) {
+        Ok(v) => ProcId(v),
+        Err(_) => {
+            twriteln!(io, "Illegal pid number {:?}, Usage: nice pid [value]", argv[1]);
+            return Ok(());
+        }
+    };
+    if let Some(v) = argv.get(2) {
+        let val = match FromStr::from_str(*v) {
+            Ok(v) => v,
+            Err(_) => {
+                twriteln!(io, "Illegal nice value {:?}", v);
+                return Ok(());
+            }
+        };
+        if let Err(e) = sched::setpriority(pid, val) {
+            twriteln!(io, "Unable to set nice of {:?}: {:?}", pid, e);
+            return Ok(());
+        }
+    }
+    match sched::getpriority(pid) {
+        Ok(n) => { twriteln!(io, "nice of {:?} is {}", pid, n); },
+        Err(e) => { twriteln!(io, "Unable to get nice of {:?}: {:?}", pid, e); },
+    }
+    Ok(())
+}
+
+/// Print how long we have been up and what time it is.
+fn do_uptime(io: &mut Device<u8>, _: &[&str]) -> KResult<()> {
+    use procs::time;
+    let up = time::monotonic();
+    let now = time::gettimeofday();
+    twriteln!(io, "up {}.{:02} seconds ({} ticks), it is {} seconds since the epoch",
+              up.tv_sec, up.tv_nsec / 10000000, time::ticks(), now.tv_sec);
+    Ok(())
+}
+
+/// Print how much of the kernel stack we and every thread before us have used.
+fn do_kstack(io: &mut Device<u8>, _: &[&str]) -> KResult<()> {
+    use procs::kthread;
+    let stack = &(current_thread!()).kstack;
+    twriteln!(io, "this thread has used {} of {} bytes of stack", stack.high_water(), stack.num_pages() * page::SIZE);
+    twriteln!(io, "the most any exited thread has used is {} bytes", kthread::max_stack_use());
+    Ok(())
+}
+
+/// Sleep for some number of seconds.
+fn do_sleep(io: &mut Device<u8>, argv: &[&str]) -> KResult<()> {
+    use procs::time;
+    if argv.len() != 2 {
+        twriteln!(io, "Usage: sleep seconds");
+        return Ok(());
+    }
+    let secs = match FromStr::from_str(argv[1]) {
+        Ok(v) => v,
+        Err(_) => {
+            twriteln!(io, "Illegal number of seconds {:?}", argv[1]);
+            return Ok(());
+        }
+    };
+    let mut rem = time::Timespec { tv_sec: 0, tv_nsec: 0 };
+    if let Err(e) = time::nanosleep(&time::Timespec { tv_sec: secs, tv_nsec: 0 }, Some(&mut rem)) {
+        twriteln!(io, "sleep interrupted with {:?} left: {:?}", rem, e);
+    }
+    Ok(())
+}
+
 fn do_bdread(io: &mut Device<u8>, argv: &[&str]) -> KResult<()> {
     use std::str::from_utf8;
     if argv.len() != 2 {
@@ -105,8 +105,14 @@ pub extern "C" fn bootstrap(_: i32, _: *mut c_void) -> *mut c_void {
     dbg!(debug::CORE, "  bss:  {:p}-{:p}", &kernel::start_bss, &kernel::end_bss);
 
     pagetable::template_init();
-    interrupt::register(interrupt::PAGE_FAULT, page_fault_temp);
+    if !cfg!(VM) {
+        // With VM umem handles page faults.
+        interrupt::register(interrupt::PAGE_FAULT, page_fault_temp);
+    }
     kproc::start_idle_proc(idle_proc_run, 0, 0 as *mut c_void);
+    // Nothing else is running yet so there is nothing to clean up.
+    dbg!(debug::CORE|debug::MM, "out of memory before the idle process could start, halting");
+    kernel::halt();
 }
 
 fn shutdown() -> ! {
@@ -138,24 +144,14 @@ extern "C" fn idle_proc_run(_: i32, _: *mut c_void) -> *mut c_void {
         Ok(v) => { kpanic!("Unable to create init proc at {:?}, got one at {:?} instead", ProcId(1), v); },
         x => { kpanic!("Unable to create init proc {:?}", x); }
     }
-    // let pageoutd_id = match KProc::new("PageOutD".to_string(), umem::pageoutd_run, 0, 0 as *mut c_void) {
-    //     Ok(v) => v,
-    //     Err(_) => { kpanic!("Unable to make pageoutd!"); }
-    // };
-    // dbg!(debug::CORE, "pageoutd is {:?}", pageoutd_id);
-
     match KProc::waitpid(Pid(ProcId(1)), 0) {
-        Ok((pid, pst)) => { dbg!(debug::CORE, "init Returned {:?}, 0x{:x}", pid, pst); },
+        Ok((pid, pst)) => { dbg!(debug::CORE, "init Returned {:?}, {:?}", pid, pst); },
         Err(errno) => {dbg!(debug::CORE, "init returned errno {:?}", errno);}
     }
 
-    // let pgd = KProc::get_proc(&pageoutd_id).expect("Pageoutd was reaped!?");
-    // pgd.borrow_mut().kill(0);
-    // drop(pgd);
-    // match KProc::waitpid(Pid(pageoutd_id), 0) {
-    //     Ok((pid, pst)) => { dbg!(debug::CORE, "pagetoutd Returned {:?}, 0x{:x}", pid, pst); },
-    //     Err(errno) => {kpanic!("pageoutd returned errno {:?}", errno); }
-    // }
+    if cfg!(LOCKDEP) {
+        dbg!(debug::CORE, "lockdep found {} problems with how locks were used", procs::lockdep::problems());
+    }
     shutdown();
 }
 
@@ -186,12 +182,15 @@ extern "C" fn init_proc_run(_: i32, _: *mut c_void) -> *mut c_void {
     interrupt::enable();
     dbg!(debug::CORE, "got into process {:?} and thread {:?}", current_proc!(), current_thread!());
 
+    if procs::workqueue::start().is_err() {
+        kpanic!("Unable to start the system workqueue");
+    }
     //KProc::new("test_proc".to_string(), run_test, 0, 0 as *mut c_void).unwrap();
     kshell::start(0);
     loop {
         let x = KProc::waitpid(kproc::Any, 0);
         match x {
-            Ok((pid, pst)) => { dbg!(debug::CORE, "{:?} Returned {:?} (0x{:x})", pid, pst, pst); },
+            Ok((pid, pst)) => { dbg!(debug::CORE, "{:?} Returned {:?}", pid, pst); },
             Err(errno) => {
                 dbg!(debug::CORE, "returned errno {:?}", errno);
                 if errno == errno::ECHILD {
@@ -199,6 +198,12 @@ extern "C" fn init_proc_run(_: i32, _: *mut c_void) -> *mut c_void {
                 }
             }
         }
+        // Once only the workers are left there is nothing more for them to do.
+        let mut others = 0;
+        (current_proc!()).each_child(|pid, _| { if !procs::workqueue::is_system_worker(pid) { others += 1; } });
+        if others == 0 && procs::workqueue::is_running() {
+            procs::workqueue::shutdown();
+        }
     }
     return 0 as *mut c_void;
 }
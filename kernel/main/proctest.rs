@@ -4,8 +4,14 @@ use libc::c_void;
 use procs::args::ProcArgs;
 use procs::kproc::{self, ProcStatus, ProcId, KProc};
 use procs::sync::*;
-use procs::{interrupt, kthread};
-use std::intrinsics::transmute;
+use procs::{interrupt, kthread, signal, time, session, resource, workqueue, sched, lockdep, futex, preempt, tls};
+use base::errno;
+use umem::mmobj::{self, MMObj};
+use umem::anon::AnonObj;
+use umem::shadow::{self, ShadowObj};
+use umem::{mman, rmap, swap, vmmap};
+use std::intrinsics::{transmute, volatile_load, volatile_store};
+use std::cell::Cell;
 use std::mem::transmute_copy;
 use std::rc::*;
 
@@ -54,6 +60,7 @@ extern "C" fn speed_test_fn(high: i32, mtx: *mut c_void) -> *mut c_void {
 
 pub fn time_mutex(high: i32, thrs: usize) {
     dbg!(debug::TIME, "START speedmutex high: {}, cnt: {}", high, thrs);
+    let start = time::ticks();
     let mut pids = Vec::with_capacity(thrs);
     let mtx = Mutex::<i32>::new("speed mutex", 0);
     for _ in 0..thrs {
@@ -66,7 +73,8 @@ pub fn time_mutex(high: i32, thrs: usize) {
     }
     let val = mtx.lock().unwrap();
     assert!(*val == high);
-    dbg!(debug::TIME, "END speedmutex high: {}, cnt: {}", high, thrs);
+    dbg!(debug::TIME, "END speedmutex high: {}, cnt: {}, took {:?}", high, thrs,
+         time::Timespec::from_ticks(time::ticks() - start));
 }
 
 fn do_run(single: bool) -> (usize, usize) {
@@ -80,7 +88,7 @@ fn do_run(single: bool) -> (usize, usize) {
                 Ok(cnt1) => {
                     match kproc::KProc::waitpid(kproc::Pid(cnt1), 0) {
                         Ok((_, status)) => {
-                            if status == GOOD as kproc::ProcStatus {
+                            if status == ProcStatus::exited(GOOD as isize) {
                                 dbg!(debug::TESTPASS, "Test {:?} {:?} passes", total, stringify!($name));
                                 pass += 1;
                             } else {
@@ -119,6 +127,54 @@ fn do_run(single: bool) -> (usize, usize) {
     basic_test!(orphan_procs, 1);
     basic_test!(orphan_procs, 3);
     basic_test!(orphan_procs, 5);
+    basic_test!(signal_other, 0);
+    basic_test!(signal_other, 4);
+    basic_test!(signal_interrupts_wait);
+    basic_test!(wait_nohang);
+    basic_test!(sleep_for, 1);
+    basic_test!(sleep_for, 10);
+    basic_test!(wait_times_out);
+    basic_test!(alarm_kills);
+    basic_test!(futex_value_changed);
+    basic_test!(futex_times_out);
+    basic_test!(futex_wake_count, 1);
+    basic_test!(futex_wake_count, 4);
+    basic_test!(new_session);
+    basic_test!(signal_group);
+    basic_test!(nproc_limit);
+    basic_test!(thread_area);
+    basic_test!(run_work, 1);
+    basic_test!(run_work, 10);
+    basic_test!(semaphore_handoff, 1);
+    basic_test!(semaphore_handoff, 5);
+    if cfg!(UPREEMPT) {
+        basic_test!(spinners_share_cpu);
+    }
+    basic_test!(semaphore_woken_cancelled);
+    basic_test!(rwlock_consistent, 2);
+    basic_test!(rwlock_consistent, 6);
+    basic_test!(condvar_wakes);
+    basic_test!(fair_mutex_order, 5);
+    basic_test!(mutex_lends_priority);
+    basic_test!(fifo_order);
+    basic_test!(mlfq_order);
+    basic_test!(shadow_copies);
+    basic_test!(swap_round_trip);
+    basic_test!(pages_coalesce);
+    basic_test!(pageout_refills);
+    basic_test!(vmmap_edits);
+    basic_test!(rmap_follows_page);
+    // Tests that touch user memory need a VM build to fault it in.
+    if cfg!(VM) {
+        basic_test!(futex_in_mapping);
+        basic_test!(private_page_faults);
+        basic_test!(mman_areas);
+        basic_test!(brk_moves);
+        basic_test!(oom_picks_biggest);
+    }
+    basic_test!(lock_inversion_found);
+    // This should stay last so it sees everything the other tests did.
+    basic_test!(no_lock_problems);
     (pass, total)
 }
 
@@ -166,7 +222,7 @@ extern "C" fn send_ignored_intr(_: i32, _: *mut c_void) -> *mut c_void {
 }
 
 extern "C" fn kill_self(_: i32, _: *mut c_void) -> *mut c_void {
-    (current_proc_mut!()).kill(GOOD as isize);
+    (current_proc_mut!()).kill(ProcStatus::exited(GOOD as isize));
     BAD
 }
 
@@ -211,7 +267,7 @@ extern "C" fn to_kill(n: i32, p: *mut c_void) -> *mut c_void {
     }
     let pid : Box<ProcId> = unsafe { transmute(p) };
     let prc = &*KProc::get_proc(&*pid).expect("there is no process of that pid");
-    prc.borrow_mut().kill(GOOD as ProcStatus);
+    prc.borrow_mut().kill(ProcStatus::exited(GOOD as isize));
     dbg!(debug::TEST, "to_die thread killed");
     GOOD
 }
@@ -234,13 +290,385 @@ extern "C" fn kill_other(n: i32, _: *mut c_void) -> *mut c_void {
         Ok(e) => e,
         Err(e) => { dbg!(debug::TESTFAIL, "Waitpid returned {:?}", e); return BAD; }
     };
-    if sv == (GOOD as ProcStatus) && tv == (GOOD as ProcStatus) {
+    if sv == ProcStatus::exited(GOOD as isize) && tv == ProcStatus::exited(GOOD as isize) {
         return GOOD;
     } else {
         return BAD;
     }
 }
 
+extern "C" fn signal_other(n: i32, _: *mut c_void) -> *mut c_void {
+    let target = match kproc::KProc::new("signal target".to_string(), to_die, 0, 0 as *mut c_void) {
+        Ok(p) => p,
+        _ => { return BAD; },
+    };
+    for _ in 0..n {
+        kthread::kyield();
+    }
+    // This one is ignored so it should not do anything.
+    if signal::kill(target, signal::SIGCHLD).is_err() || signal::kill(target, signal::SIGTERM).is_err() {
+        return BAD;
+    }
+    match KProc::waitpid(kproc::Pid(target), 0) {
+        Ok((_, v)) if v == ProcStatus::signaled(signal::SIGTERM) => GOOD,
+        Ok((_, v)) => { dbg!(debug::TESTFAIL, "signaled process exited with {:?}", v); BAD },
+        Err(e) => { dbg!(debug::TESTFAIL, "Waitpid returned {:?}", e); BAD },
+    }
+}
+
+extern "C" fn wait_for_signal(_: i32, _: *mut c_void) -> *mut c_void {
+    // We will never actually get to user mode so the handler is never run.
+    let act = signal::SigAction { handler: 0x1000, .. signal::SigAction::new() };
+    if signal::sigaction(signal::SIGUSR1, Some(act)).is_err() {
+        return BAD;
+    }
+    let q = WQueue::new();
+    match q.wait() {
+        Err(_) if (current_thread!()).interrupted_errno() == errno::EINTR => GOOD,
+        _ => BAD,
+    }
+}
+
+extern "C" fn signal_interrupts_wait(_: i32, _: *mut c_void) -> *mut c_void {
+    let target = match kproc::KProc::new("signal waiter".to_string(), wait_for_signal, 0, 0 as *mut c_void) {
+        Ok(p) => p,
+        _ => { return BAD; },
+    };
+    for _ in 0..4 {
+        kthread::kyield();
+    }
+    if signal::kill(target, signal::SIGUSR1).is_err() {
+        return BAD;
+    }
+    match KProc::waitpid(kproc::Pid(target), 0) {
+        Ok((_, v)) => v.exit_status() as *mut c_void,
+        Err(e) => { dbg!(debug::TESTFAIL, "Waitpid returned {:?}", e); BAD },
+    }
+}
+
+extern "C" fn wait_nohang(_: i32, _: *mut c_void) -> *mut c_void {
+    match KProc::try_waitpid(kproc::Any, 0) {
+        Err(errno::ECHILD) => {},
+        x => { dbg!(debug::TESTFAIL, "try_waitpid with no children returned {:?}", x); return BAD; },
+    }
+    let target = match kproc::KProc::new("nohang target".to_string(), to_die, 0, 0 as *mut c_void) {
+        Ok(p) => p,
+        _ => { return BAD; },
+    };
+    match KProc::try_waitpid(kproc::Pid(target), kproc::WUNTRACED) {
+        Ok(None) => {},
+        x => { dbg!(debug::TESTFAIL, "try_waitpid on a running child returned {:?}", x); return BAD; },
+    }
+    if signal::kill(target, signal::SIGKILL).is_err() {
+        return BAD;
+    }
+    match KProc::waitpid(kproc::Pid(target), 0) {
+        Ok((_, v)) if v.if_signaled() && v.term_sig() == signal::SIGKILL => GOOD,
+        x => { dbg!(debug::TESTFAIL, "waitpid on a killed child returned {:?}", x); BAD },
+    }
+}
+
+extern "C" fn sleep_for(ticks: i32, _: *mut c_void) -> *mut c_void {
+    let start = time::ticks();
+    let req = time::Timespec::from_ticks(ticks as u64);
+    if time::nanosleep(&req, None).is_err() {
+        return BAD;
+    }
+    let slept = time::ticks() - start;
+    if slept >= ticks as u64 { GOOD } else {
+        dbg!(debug::TESTFAIL, "asked to sleep {} ticks but only slept {}", ticks, slept);
+        BAD
+    }
+}
+
+extern "C" fn wait_times_out(_: i32, _: *mut c_void) -> *mut c_void {
+    let q = WQueue::new();
+    match q.wait_until(time::ticks() + 5) {
+        Err(errno::ETIMEDOUT) => GOOD,
+        x => { dbg!(debug::TESTFAIL, "wait_until on an unsignaled queue returned {:?}", x); BAD },
+    }
+}
+
+/// Where the futex tests put the page they share.
+const FUTEX_TEST_ADDR : usize = 0x10000000;
+
+/// Map the kernel page at `kaddr` to `FUTEX_TEST_ADDR` in the current process, as if it were user
+/// memory, or take it back out if `kaddr` is None.
+fn share_futex_page(kaddr: Option<usize>) -> bool {
+    use mm::pagetable;
+    let pd = (current_proc_mut!()).get_pagedir_mut();
+    match kaddr {
+        Some(k) => {
+            let flags = (pagetable::PRESENT | pagetable::USER | pagetable::WRITE) as u32;
+            let paddr = pd.virt_to_phys(k);
+            unsafe { pd.map(FUTEX_TEST_ADDR, paddr, flags, flags).is_ok() }
+        },
+        None => { unsafe { pd.unmap(FUTEX_TEST_ADDR); } true },
+    }
+}
+
+/// Run `f` with a zeroed page shared at `FUTEX_TEST_ADDR`, passing it the page's kernel address.
+fn with_futex_page<F>(f: F) -> *mut c_void where F: FnOnce(usize) -> *mut c_void {
+    use mm::page;
+    let kaddr = match unsafe { page::alloc::<u32>() } { Ok(p) => p as usize, Err(_) => { return BAD; } };
+    unsafe { *(kaddr as *mut u32) = 0; }
+    let res = if share_futex_page(Some(kaddr)) {
+        let r = f(kaddr);
+        share_futex_page(None);
+        r
+    } else {
+        BAD
+    };
+    unsafe { page::free(kaddr as *mut c_void); }
+    res
+}
+
+extern "C" fn futex_value_changed(_: i32, _: *mut c_void) -> *mut c_void {
+    with_futex_page(|kaddr| {
+        unsafe { *(kaddr as *mut u32) = 1; }
+        match futex::futex(FUTEX_TEST_ADDR, futex::FUTEX_WAIT, 0, None) {
+            Err(errno::EAGAIN) => GOOD,
+            x => { dbg!(debug::TESTFAIL, "waiting for 0 on a word holding 1 returned {:?}", x); BAD },
+        }
+    })
+}
+
+extern "C" fn futex_times_out(_: i32, _: *mut c_void) -> *mut c_void {
+    with_futex_page(|_| {
+        let t = time::Timespec::from_ticks(5);
+        match futex::futex(FUTEX_TEST_ADDR, futex::FUTEX_WAIT, 0, Some(&t)) {
+            Err(errno::ETIMEDOUT) => GOOD,
+            x => { dbg!(debug::TESTFAIL, "waiting with nobody to wake us returned {:?}", x); BAD },
+        }
+    })
+}
+
+extern "C" fn futex_waiter(_: i32, kaddr: *mut c_void) -> *mut c_void {
+    if !share_futex_page(Some(kaddr as usize)) {
+        return BAD;
+    }
+    let r = futex::futex(FUTEX_TEST_ADDR, futex::FUTEX_WAIT, 0, None);
+    share_futex_page(None);
+    if r == Ok(0) { GOOD } else { dbg!(debug::TESTFAIL, "futex waiter got {:?}", r); BAD }
+}
+
+/// Have `n` processes wait on a word, then wake all but one of them, then ask to wake more than are
+/// left, checking how many each wake says it woke.
+extern "C" fn futex_wake_count(n: i32, _: *mut c_void) -> *mut c_void {
+    with_futex_page(|kaddr| {
+        let mut waiters = Vec::new();
+        for _ in 0..n {
+            match kproc::KProc::new("futex waiter".to_string(), futex_waiter, 0, kaddr as *mut c_void) {
+                Ok(p) => { waiters.push(p); },
+                Err(_) => { break; },
+            }
+            // Let it start waiting.
+            kthread::kyield();
+        }
+        let all = waiters.len() == n as usize;
+        let most = futex::futex(FUTEX_TEST_ADDR, futex::FUTEX_WAKE, (n - 1) as u32, None);
+        let rest = futex::futex(FUTEX_TEST_ADDR, futex::FUTEX_WAKE, n as u32, None);
+        let none = futex::futex(FUTEX_TEST_ADDR, futex::FUTEX_WAKE, n as u32, None);
+        let mut good = true;
+        for p in waiters.drain() {
+            match KProc::waitpid(kproc::Pid(p), 0) {
+                Ok((_, s)) if s == ProcStatus::exited(GOOD as isize) => {},
+                _ => { good = false; },
+            }
+        }
+        let want = n as usize;
+        if all && good && most == Ok(want - 1) && rest == Ok(1) && none == Ok(0) {
+            GOOD
+        } else {
+            dbg!(debug::TESTFAIL, "waking {} waiters woke {:?} then {:?} then {:?}, waiters all good: {}",
+                 n, most, rest, none, good);
+            BAD
+        }
+    })
+}
+
+/// Wait on words in a page that has been mapped but not touched yet, which has to be brought in
+/// rather than turned away.
+extern "C" fn futex_in_mapping(_: i32, _: *mut c_void) -> *mut c_void {
+    use mm::page;
+    use mm::memman::{prot, map};
+    let addr = match mman::mmap(0, page::SIZE, prot::READ | prot::WRITE, map::PRIVATE | map::ANON, None, 0) {
+        Ok(a) => a,
+        Err(_) => { return BAD; },
+    };
+    let changed = futex::futex(addr, futex::FUTEX_WAIT, 1, None);
+    let t = time::Timespec::from_ticks(2);
+    let timed = futex::futex(addr + 4, futex::FUTEX_WAIT, 0, Some(&t));
+    let _ = mman::munmap(addr, page::SIZE);
+    if changed == Err(errno::EAGAIN) && timed == Err(errno::ETIMEDOUT) {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "waiting on a fresh mapping returned {:?} and {:?}", changed, timed);
+        BAD
+    }
+}
+
+extern "C" fn alarm_sleeper(_: i32, _: *mut c_void) -> *mut c_void {
+    if time::alarm(1) != 0 {
+        return BAD;
+    }
+    // The alarm should kill us long before this is done.
+    let req = time::Timespec { tv_sec: 10, tv_nsec: 0 };
+    let _ = time::nanosleep(&req, None);
+    BAD
+}
+
+extern "C" fn alarm_kills(_: i32, _: *mut c_void) -> *mut c_void {
+    let target = match kproc::KProc::new("alarm sleeper".to_string(), alarm_sleeper, 0, 0 as *mut c_void) {
+        Ok(p) => p,
+        _ => { return BAD; },
+    };
+    match KProc::waitpid(kproc::Pid(target), 0) {
+        Ok((_, v)) if v == ProcStatus::signaled(signal::SIGALRM) => GOOD,
+        x => { dbg!(debug::TESTFAIL, "waitpid on the alarmed child returned {:?}", x); BAD },
+    }
+}
+
+extern "C" fn new_session(_: i32, _: *mut c_void) -> *mut c_void {
+    let me = current_pid!();
+    match session::setsid() {
+        Ok(sid) if sid == me => {},
+        x => { dbg!(debug::TESTFAIL, "setsid returned {:?}", x); return BAD; },
+    }
+    if session::getsid(ProcId(0)) != Ok(me) || session::getpgid(ProcId(0)) != Ok(me) {
+        return BAD;
+    }
+    // We lead a group now so we cannot do it again.
+    match session::setsid() {
+        Err(errno::EPERM) => GOOD,
+        x => { dbg!(debug::TESTFAIL, "second setsid returned {:?}", x); BAD },
+    }
+}
+
+extern "C" fn signal_group(_: i32, _: *mut c_void) -> *mut c_void {
+    let mut kids = Vec::new();
+    for _ in 0..3 {
+        match kproc::KProc::new("group member".to_string(), to_die, 0, 0 as *mut c_void) {
+            Ok(p) => kids.push(p),
+            _ => { return BAD; },
+        }
+    }
+    let pgid = kids[0];
+    for &k in kids.iter() {
+        if let Err(e) = session::setpgid(k, pgid) {
+            dbg!(debug::TESTFAIL, "setpgid({:?}, {:?}) failed with {:?}", k, pgid, e);
+            return BAD;
+        }
+    }
+    if signal::killpg(pgid, signal::SIGTERM).is_err() {
+        return BAD;
+    }
+    for &k in kids.iter() {
+        match KProc::waitpid(kproc::Pid(k), 0) {
+            Ok((_, v)) if v == ProcStatus::signaled(signal::SIGTERM) => {},
+            x => { dbg!(debug::TESTFAIL, "waitpid on a group member returned {:?}", x); return BAD; },
+        }
+    }
+    match signal::killpg(pgid, signal::SIGTERM) {
+        Err(errno::ESRCH) => GOOD,
+        x => { dbg!(debug::TESTFAIL, "killpg on an empty group returned {:?}", x); BAD },
+    }
+}
+
+extern "C" fn nproc_limit(_: i32, _: *mut c_void) -> *mut c_void {
+    let lim = resource::RLimit::new(1, 1);
+    if resource::setrlimit(resource::RLIMIT_NPROC, lim).is_err() {
+        return BAD;
+    }
+    match resource::setrlimit(resource::RLIMIT_NPROC, resource::RLimit::new(2, 2)) {
+        Err(errno::EPERM) => {},
+        x => { dbg!(debug::TESTFAIL, "raising the hard limit returned {:?}", x); return BAD; },
+    }
+    let first = match kproc::KProc::new("first child".to_string(), to_die, 0, 0 as *mut c_void) {
+        Ok(p) => p,
+        _ => { return BAD; },
+    };
+    let second = kproc::KProc::new("second child".to_string(), to_die, 0, 0 as *mut c_void);
+    if signal::kill(first, signal::SIGKILL).is_err() || KProc::waitpid(kproc::Pid(first), 0).is_err() {
+        return BAD;
+    }
+    if second.is_ok() {
+        dbg!(debug::TESTFAIL, "created a second child over RLIMIT_NPROC");
+        return BAD;
+    }
+    match resource::getrusage(resource::RUSAGE_CHILDREN) {
+        Ok(_) => GOOD,
+        x => { dbg!(debug::TESTFAIL, "getrusage returned {:?}", x); BAD },
+    }
+}
+
+extern "C" fn thread_area(_: i32, _: *mut c_void) -> *mut c_void {
+    let mut d = tls::UserDesc { entry_number: !0, base_addr: 0x10000000, limit: 4, flags: tls::SEG_32BIT | tls::LIMIT_IN_PAGES };
+    if tls::set_thread_area(&mut d).is_err() || d.entry_number != tls::TLS_ENTRY {
+        dbg!(debug::TESTFAIL, "set_thread_area gave back {:?}", d);
+        return BAD;
+    }
+    let mut got = tls::UserDesc { entry_number: tls::TLS_ENTRY, base_addr: 0, limit: 0, flags: 0 };
+    if tls::get_thread_area(&mut got).is_err() || got.base_addr != d.base_addr || got.limit != d.limit ||
+       got.flags & (tls::SEG_32BIT | tls::LIMIT_IN_PAGES | tls::SEG_NOT_PRESENT) != tls::SEG_32BIT | tls::LIMIT_IN_PAGES {
+        dbg!(debug::TESTFAIL, "set {:?} but get_thread_area gave {:?}", d, got);
+        return BAD;
+    }
+    // Other entries, and segments that are not plain read/write data, are refused.
+    let bad = [(tls::TLS_ENTRY + 1, tls::SEG_32BIT),
+               (tls::TLS_ENTRY, tls::SEG_32BIT | 0x02),
+               (tls::TLS_ENTRY, tls::SEG_32BIT | tls::READ_EXEC_ONLY)];
+    for &(entry, flags) in bad.iter() {
+        let mut b = tls::UserDesc { entry_number: entry, base_addr: 0, limit: 1, flags: flags };
+        match tls::set_thread_area(&mut b) {
+            Err(errno::EINVAL) => {},
+            x => { dbg!(debug::TESTFAIL, "set_thread_area of {:?} returned {:?}", b, x); return BAD; },
+        }
+    }
+    let mut b = tls::UserDesc { entry_number: tls::TLS_ENTRY + 1, base_addr: 0, limit: 0, flags: 0 };
+    match tls::get_thread_area(&mut b) {
+        Err(errno::EINVAL) => {},
+        x => { dbg!(debug::TESTFAIL, "get_thread_area of entry {} returned {:?}", tls::TLS_ENTRY + 1, x); return BAD; },
+    }
+    // The refused ones did not touch what we had, and we can get rid of it.
+    let mut off = tls::UserDesc { entry_number: tls::TLS_ENTRY, base_addr: 0, limit: 0, flags: tls::SEG_NOT_PRESENT };
+    if tls::get_thread_area(&mut got).is_err() || got.base_addr != d.base_addr || tls::set_thread_area(&mut off).is_err() {
+        return BAD;
+    }
+    match tls::get_thread_area(&mut got) {
+        Ok(()) if got.flags & tls::SEG_NOT_PRESENT != 0 => GOOD,
+        x => { dbg!(debug::TESTFAIL, "cleared TLS but get_thread_area gave {:?} {:?}", x, got); BAD },
+    }
+}
+
+fn count_work(cnt: usize) {
+    unsafe { *(cnt as *mut usize) += 1; }
+}
+
+extern "C" fn run_work(n: i32, _: *mut c_void) -> *mut c_void {
+    let mut cnt : usize = 0;
+    let mut items : Vec<workqueue::Work> = (0..n).map(|_| { workqueue::Work::new(count_work, &mut cnt as *mut usize as usize) }).collect();
+    for w in items.iter_mut() {
+        if !workqueue::queue_work(w) { return BAD; }
+        // Queueing it again before it runs does nothing.
+        if w.is_pending() && workqueue::queue_work(w) { return BAD; }
+    }
+    if workqueue::system().flush().is_err() {
+        return BAD;
+    }
+    if cnt != n as usize {
+        dbg!(debug::TESTFAIL, "ran {} work items out of {}", cnt, n);
+        return BAD;
+    }
+    // Something cancelled before a worker gets to it never runs.
+    let mut extra = workqueue::Work::new(count_work, &mut cnt as *mut usize as usize);
+    let cancelled = block_interrupts!({ workqueue::queue_work(&mut extra); workqueue::system().cancel(&mut extra) });
+    if !cancelled || workqueue::system().flush_work(&extra).is_err() || cnt != n as usize {
+        return BAD;
+    }
+    GOOD
+}
+
 extern "C" fn reentrant_locks(_: i32, _: *mut c_void) -> *mut c_void {
     dbg!(debug::TEST, "Attempting to create a mutex and lock it.");
     let x = KMutex::new("test a mutex");
@@ -310,7 +738,7 @@ extern "C" fn contested_mutex(n : i32, _: *mut c_void) -> *mut c_void {
             Err(_) => { return BAD; },
         };
         dbg!(debug::TEST, "pid {:?} returned {:?}", p, v);
-        tot += v as i32;
+        tot += v.exit_status() as i32;
     }
     let ret = if tot == unsafe { cnt } {
         dbg!(debug::TESTPASS, "successfully counted to {:?} with {:?} counters", tot, n);
@@ -342,7 +770,7 @@ extern "C" fn better_mutex(n : i32, _: *mut c_void) -> *mut c_void {
             Err(_) => { return BAD; },
         };
         dbg!(debug::TEST, "pid {:?} returned {:?}", p, v);
-        tot += v as i32;
+        tot += v.exit_status() as i32;
     }
     let ret = if tot == (*x).lock().and_then(|g| { Ok(*g) }).unwrap_or(0) {
         dbg!(debug::TESTPASS, "successfully counted to {:?} with {:?} counters, using Mutex", tot, n);
@@ -379,6 +807,776 @@ extern "C" fn better_counter(h: i32, v : *mut c_void) -> *mut c_void {
     }
 }
 
+/// Count in slot `n` of the counters until the last one is set, never blocking. After each count
+/// we go through what an interrupt does on its way back to a busy user thread, so the only way
+/// anything else gets to run is the timeslice running out.
+extern "C" fn spin_count(n: i32, v: *mut c_void) -> *mut c_void {
+    let counts : Rc<[Cell<usize>; 3]> = unsafe { ProcArgs::from_arg(v).unwrap() };
+    while counts[2].get() == 0 {
+        counts[n as usize].set(counts[n as usize].get() + 1);
+        interrupt::disable();
+        preempt::preempt_if_needed();
+        interrupt::enable();
+    }
+    GOOD
+}
+
+extern "C" fn spinners_share_cpu(_: i32, _: *mut c_void) -> *mut c_void {
+    let counts : Rc<[Cell<usize>; 3]> = Rc::new([Cell::new(0), Cell::new(0), Cell::new(0)]);
+    for i in 0..2 {
+        if kproc::KProc::new("spinner".to_string(), spin_count, i, unsafe { ProcArgs::new(counts.clone()).unwrap().to_arg() }).is_err() {
+            counts[2].set(1);
+            return BAD;
+        }
+    }
+    // We only get back in once one of them has used up its slice.
+    let req = time::Timespec::from_ticks(4 * preempt::TIMESLICE as u64);
+    let slept = time::nanosleep(&req, None);
+    let (first, second) = (counts[0].get(), counts[1].get());
+    counts[2].set(1);
+    for _ in 0..2 {
+        match kproc::KProc::waitpid(kproc::Any, 0) {
+            Ok((_, v)) if v == ProcStatus::exited(GOOD as isize) => {},
+            x => { dbg!(debug::TESTFAIL, "spinner returned {:?}", x); return BAD; },
+        }
+    }
+    if slept.is_err() || first == 0 || second == 0 {
+        dbg!(debug::TESTFAIL, "spinners counted to {} and {} while we slept", first, second);
+        BAD
+    } else {
+        GOOD
+    }
+}
+
+extern "C" fn semaphore_taker(_: i32, v: *mut c_void) -> *mut c_void {
+    let s : Rc<Semaphore> = unsafe { ProcArgs::from_arg(v).unwrap() };
+    if s.down().is_ok() { GOOD } else { BAD }
+}
+
+extern "C" fn semaphore_handoff(n: i32, _: *mut c_void) -> *mut c_void {
+    let s = Rc::new(Semaphore::new(0));
+    for _ in 0..n {
+        if kproc::KProc::new("semaphore taker".to_string(), semaphore_taker, 0, unsafe { ProcArgs::new(s.clone()).unwrap().to_arg() }).is_err() {
+            return BAD;
+        }
+    }
+    kthread::kyield();
+    for _ in 0..n {
+        s.up();
+        kthread::kyield();
+    }
+    for _ in 0..n {
+        match kproc::KProc::waitpid(kproc::Any, 0) {
+            Ok((_, v)) if v == ProcStatus::exited(GOOD as isize) => {},
+            x => { dbg!(debug::TESTFAIL, "semaphore taker returned {:?}", x); return BAD; },
+        }
+    }
+    if s.value() != 0 || s.try_down() { BAD } else { GOOD }
+}
+
+extern "C" fn semaphore_woken_cancelled(_: i32, _: *mut c_void) -> *mut c_void {
+    let s = Rc::new(Semaphore::new(0));
+    let mut takers = [ProcId(0); 2];
+    for t in takers.iter_mut() {
+        *t = match kproc::KProc::new("semaphore taker".to_string(), semaphore_taker, 0, unsafe { ProcArgs::new(s.clone()).unwrap().to_arg() }) {
+            Ok(p) => p,
+            Err(_) => { return BAD; },
+        };
+        // Let it start waiting so they wait in order.
+        kthread::kyield();
+    }
+    // Wake the first taker and cancel it before it gets to run.
+    let first = match KProc::get_proc(&takers[0]) { Some(p) => p, None => { return BAD; } };
+    block_interrupts!({
+        s.up();
+        first.borrow_mut().post_signal(signal::SIGKILL);
+    });
+    drop(first);
+    for _ in 0..10 {
+        kthread::kyield();
+    }
+    // The second taker should have gotten the wakeup the first one could not use.
+    let passed = s.value() == 0;
+    if !passed {
+        dbg!(debug::TESTFAIL, "the wakeup was lost, the count is still {}", s.value());
+        s.up();
+    }
+    let (_, fv) = match KProc::waitpid(kproc::Pid(takers[0]), 0) { Ok(e) => e, Err(_) => { return BAD; } };
+    let (_, sv) = match KProc::waitpid(kproc::Pid(takers[1]), 0) { Ok(e) => e, Err(_) => { return BAD; } };
+    if passed && fv == ProcStatus::signaled(signal::SIGKILL) && sv == ProcStatus::exited(GOOD as isize) { GOOD } else { BAD }
+}
+
+extern "C" fn rwlock_user(writer: i32, v: *mut c_void) -> *mut c_void {
+    let l : Rc<RwLock<(i32, i32)>> = unsafe { ProcArgs::from_arg(v).unwrap() };
+    for _ in 0..50 {
+        if writer != 0 {
+            let mut g = l.force_write();
+            g.0 += 1;
+            kthread::kyield();
+            g.1 += 1;
+        } else {
+            let g = l.force_read();
+            let a = g.0;
+            kthread::kyield();
+            if a != g.1 || a != g.0 {
+                dbg!(debug::TESTFAIL, "reader saw a half finished write");
+                return BAD;
+            }
+        }
+        kthread::kyield();
+    }
+    GOOD
+}
+
+extern "C" fn rwlock_consistent(n: i32, _: *mut c_void) -> *mut c_void {
+    let l = Rc::new(RwLock::new("rwlock test", (0, 0)));
+    for i in 0..n {
+        if kproc::KProc::new("rwlock user".to_string(), rwlock_user, i % 2, unsafe { ProcArgs::new(l.clone()).unwrap().to_arg() }).is_err() {
+            return BAD;
+        }
+    }
+    let mut ret = GOOD;
+    for _ in 0..n {
+        match kproc::KProc::waitpid(kproc::Any, 0) {
+            Ok((_, v)) if v == ProcStatus::exited(GOOD as isize) => {},
+            _ => { ret = BAD; },
+        }
+    }
+    let writes = 50 * (n / 2);
+    let g = l.force_read();
+    if *g != (writes, writes) {
+        dbg!(debug::TESTFAIL, "expected {} writes but got {:?}", writes, *g);
+        return BAD;
+    }
+    ret
+}
+
+extern "C" fn condvar_waiter(_: i32, v: *mut c_void) -> *mut c_void {
+    let d : Rc<(Mutex<bool>, Condvar)> = unsafe { ProcArgs::from_arg(v).unwrap() };
+    let g = d.0.force_lock();
+    if d.1.wait_while(&g, |ready| { !*ready }).is_err() { BAD } else { GOOD }
+}
+
+extern "C" fn condvar_wakes(_: i32, _: *mut c_void) -> *mut c_void {
+    let d = Rc::new((Mutex::new("condvar test", false), Condvar::new()));
+    if kproc::KProc::new("condvar waiter".to_string(), condvar_waiter, 0, unsafe { ProcArgs::new(d.clone()).unwrap().to_arg() }).is_err() {
+        return BAD;
+    }
+    for _ in 0..4 {
+        kthread::kyield();
+    }
+    // A wakeup with the condition still false should put it right back to sleep.
+    d.1.notify_all();
+    kthread::kyield();
+    *d.0.force_lock() = true;
+    d.1.notify_one();
+    match kproc::KProc::waitpid(kproc::Any, 0) {
+        Ok((_, v)) if v == ProcStatus::exited(GOOD as isize) => GOOD,
+        x => { dbg!(debug::TESTFAIL, "condvar waiter returned {:?}", x); BAD },
+    }
+}
+
+extern "C" fn fair_mutex_waiter(i: i32, v: *mut c_void) -> *mut c_void {
+    let m : Rc<Mutex<Vec<i32>>> = unsafe { ProcArgs::from_arg(v).unwrap() };
+    m.force_lock().push(i);
+    GOOD
+}
+
+extern "C" fn fair_mutex_order(n: i32, _: *mut c_void) -> *mut c_void {
+    let m = Rc::new(Mutex::new_fair("fair mutex test", Vec::new()));
+    {
+        let _g = m.force_lock();
+        for i in 0..n {
+            if kproc::KProc::new("fair mutex waiter".to_string(), fair_mutex_waiter, i, unsafe { ProcArgs::new(m.clone()).unwrap().to_arg() }).is_err() {
+                return BAD;
+            }
+            // Let it get in line before the next one starts.
+            for _ in 0..4 {
+                kthread::kyield();
+            }
+        }
+    }
+    for _ in 0..n {
+        match kproc::KProc::waitpid(kproc::Any, 0) {
+            Ok((_, v)) if v == ProcStatus::exited(GOOD as isize) => {},
+            x => { dbg!(debug::TESTFAIL, "fair mutex waiter returned {:?}", x); return BAD; },
+        }
+    }
+    let order = m.force_lock();
+    if order.iter().cloned().eq(0..n) {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "fair mutex was handed out in the order {:?}", *order);
+        BAD
+    }
+}
+
+extern "C" fn priority_waiter(_: i32, v: *mut c_void) -> *mut c_void {
+    let m : Rc<Mutex<()>> = unsafe { ProcArgs::from_arg(v).unwrap() };
+    if sched::setpriority(current_pid!(), -5).is_err() {
+        return BAD;
+    }
+    let _g = m.force_lock();
+    GOOD
+}
+
+extern "C" fn mutex_lends_priority(_: i32, _: *mut c_void) -> *mut c_void {
+    let m = Rc::new(Mutex::new("priority test", ()));
+    if sched::setpriority(current_pid!(), 10).is_err() {
+        return BAD;
+    }
+    let ok = {
+        let _g = m.force_lock();
+        if kproc::KProc::new("priority waiter".to_string(), priority_waiter, 0, unsafe { ProcArgs::new(m.clone()).unwrap().to_arg() }).is_err() {
+            return BAD;
+        }
+        for _ in 0..4 {
+            kthread::kyield();
+        }
+        let lent = (current_thread!()).ctx.sched.priority();
+        if lent != -5 { dbg!(debug::TESTFAIL, "holder has priority {} instead of -5", lent); }
+        lent == -5
+    };
+    let back = (current_thread!()).ctx.sched.priority();
+    if back != 10 { dbg!(debug::TESTFAIL, "holder has priority {} after unlock instead of 10", back); }
+    let res = kproc::KProc::waitpid(kproc::Any, 0);
+    if sched::setpriority(current_pid!(), 0).is_err() {
+        return BAD;
+    }
+    match res {
+        Ok((_, v)) if v == ProcStatus::exited(GOOD as isize) && ok && back == 10 => GOOD,
+        x => { dbg!(debug::TESTFAIL, "priority waiter returned {:?}", x); BAD },
+    }
+}
+
+/// Make `n` threads that are never run, so their contexts can be handed straight to a policy.
+fn idle_threads(n: usize) -> Option<Vec<kthread::KThread>> {
+    let pd = (current_proc!()).get_pagedir();
+    let mut out = Vec::new();
+    for _ in 0..n {
+        match kthread::KThread::new(pd, to_die, 0, 0 as *mut c_void) {
+            Ok(t) => out.push(t),
+            Err(_) => { return None; },
+        }
+    }
+    Some(out)
+}
+
+extern "C" fn fifo_order(_: i32, _: *mut c_void) -> *mut c_void {
+    use procs::sched::SchedPolicy;
+    let mut thrs = match idle_threads(3) { Some(t) => t, None => { return BAD; } };
+    let mut p = sched::Fifo::new();
+    // Expiring and priorities make no difference to it.
+    thrs[0].ctx.sched.expired = true;
+    thrs[2].ctx.sched.nice = sched::NICE_MIN;
+    for t in thrs.iter_mut() {
+        p.push(&mut t.ctx);
+    }
+    for t in thrs.iter_mut() {
+        if p.pop() != Some(&mut t.ctx as *mut _) {
+            dbg!(debug::TESTFAIL, "fifo did not give back threads in the order they were added");
+            return BAD;
+        }
+    }
+    if p.len() == 0 && p.pop().is_none() { GOOD } else { BAD }
+}
+
+extern "C" fn mlfq_order(_: i32, _: *mut c_void) -> *mut c_void {
+    use procs::sched::SchedPolicy;
+    let mut thrs = match idle_threads(3) { Some(t) => t, None => { return BAD; } };
+    let mut p = sched::Mlfq::new();
+    // One that used up its slice drops a level and waits behind one that did not.
+    thrs[0].ctx.sched.expired = true;
+    p.push(&mut thrs[0].ctx);
+    p.push(&mut thrs[1].ctx);
+    let base = thrs[1].ctx.sched.level;
+    if thrs[0].ctx.sched.level != base + 1 || thrs[0].ctx.sched.expired {
+        dbg!(debug::TESTFAIL, "expired thread is at level {} with its thread at {}", thrs[0].ctx.sched.level, base);
+        return BAD;
+    }
+    // A lower nice value goes ahead of both.
+    thrs[2].ctx.sched.nice = sched::NICE_MIN;
+    p.push(&mut thrs[2].ctx);
+    let want = [2, 1];
+    for &i in want.iter() {
+        if p.pop() != Some(&mut thrs[i].ctx as *mut _) {
+            dbg!(debug::TESTFAIL, "mlfq did not pick thread {} next", i);
+            return BAD;
+        }
+    }
+    // The demoted one is passed over until a boost puts it back at its own best level, not the top.
+    for _ in 2..sched::MLFQ_BOOST_INTERVAL {
+        p.push(&mut thrs[1].ctx);
+        if p.pop() != Some(&mut thrs[1].ctx as *mut _) {
+            dbg!(debug::TESTFAIL, "demoted thread ran ahead of one at level {}", base);
+            return BAD;
+        }
+    }
+    if thrs[0].ctx.sched.level != base {
+        dbg!(debug::TESTFAIL, "boosted thread is at level {} instead of {}", thrs[0].ctx.sched.level, base);
+        return BAD;
+    }
+    if p.pop() == Some(&mut thrs[0].ctx as *mut _) && p.len() == 0 { GOOD } else { BAD }
+}
+
+extern "C" fn pages_coalesce(_: i32, _: *mut c_void) -> *mut c_void {
+    use mm::page;
+    // Nobody else can take or give back pages while we check that everything joins back up.
+    let ok = block_interrupts!({
+        let (before, free) = (page::free_blocks(), page::free_count());
+        let sizes = [1, 3, 1, 8, 2, 1];
+        let mut got = [0 as *mut c_void; 6];
+        let mut ok = true;
+        for (i, &n) in sizes.iter().enumerate() {
+            match unsafe { page::alloc_n::<c_void>(n) } {
+                Ok(p) => { got[i] = p; },
+                Err(_) => { ok = false; },
+            }
+        }
+        ok = ok && page::free_count() < free;
+        // Free them in a different order than we got them.
+        for &i in [3, 0, 5, 1, 4, 2].iter() {
+            if !got[i].is_null() {
+                unsafe { page::free_n(got[i], sizes[i] as u32); }
+            }
+        }
+        let after = page::free_blocks();
+        if after != before { dbg!(debug::TESTFAIL, "free blocks were {:?} and are now {:?}", before, after); }
+        ok && after == before && page::free_count() == free
+    });
+    if ok { GOOD } else { BAD }
+}
+
+/// Byte 0 of page `n` of `obj`, writing `val` there first if given.
+fn poke_page(obj: &Rc<Box<MMObj + 'static>>, n: usize, val: Option<u8>) -> Option<u8> {
+    let pf = match mmobj::lookup_page(obj.clone(), n, val.is_some()) { Ok(pf) => pf, Err(_) => { return None; } };
+    match val {
+        Some(v) => pf.dirty().ok().map(|p| { p[0] = v; p[0] }),
+        None => Some(pf.get_page()[0]),
+    }
+}
+
+extern "C" fn shadow_copies(_: i32, _: *mut c_void) -> *mut c_void {
+    let anon = AnonObj::create().unwrap();
+    let mid = ShadowObj::create(anon.clone()).unwrap();
+    let top = ShadowObj::create(mid.clone()).unwrap();
+    let zeroed = poke_page(&anon, 2, None);
+    poke_page(&anon, 3, Some(7));
+    poke_page(&mid, 1, Some(5));
+    let seen = poke_page(&top, 3, None);
+    poke_page(&top, 3, Some(9));
+    let below = poke_page(&anon, 3, None);
+    let copied = poke_page(&top, 3, None);
+    drop(mid);
+    let collapsed = shadow::collapse(&top).is_ok() &&
+                    top.as_shadow().map(|s| s.shadowed().get_id()) == Some(anon.get_id());
+    let moved = poke_page(&top, 1, None);
+    if zeroed == Some(0) && seen == Some(7) && below == Some(7) && copied == Some(9) && collapsed && moved == Some(5) {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "zeroed {:?}, seen {:?}, below {:?}, copied {:?}, collapsed {}, moved {:?}",
+             zeroed, seen, below, copied, collapsed, moved);
+        BAD
+    }
+}
+
+/// Use up free memory, with plenty of clean pages in the cache, until allocating leaves less than
+/// `LOW_WATER` free. Pageoutd should then throw away enough of them to get back up to `HIGH_WATER`.
+extern "C" fn pageout_refills(_: i32, _: *mut c_void) -> *mut c_void {
+    use drivers::memdev::ZeroDev;
+    use mm::page;
+    use umem::pframe::pageout::{LOW_WATER, HIGH_WATER};
+    let zero : Rc<Box<MMObj + 'static>> = Rc::new(box ZeroDev::new() as Box<MMObj + 'static>);
+    for i in 0..HIGH_WATER as usize {
+        if mmobj::lookup_page(zero.clone(), i, false).is_err() {
+            return BAD;
+        }
+    }
+    let mut held : Vec<*mut u8> = Vec::with_capacity(page::free_count() as usize);
+    while page::free_count() >= LOW_WATER {
+        match unsafe { page::alloc::<u8>() } {
+            Ok(p) => held.push(p),
+            Err(_) => { break; },
+        }
+    }
+    let low = page::free_count();
+    let got = unsafe { page::alloc::<u8>() };
+    let flushed = workqueue::system().flush().is_ok();
+    let free = page::free_count();
+    if let Ok(p) = got {
+        held.push(p);
+    }
+    for &p in held.iter() {
+        unsafe { page::free(p as *mut c_void); }
+    }
+    if got.is_ok() && flushed && low < LOW_WATER && free >= HIGH_WATER {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "allocating with {} pages free got {:?}, pageoutd left {} free", low, got.is_ok(), free);
+        BAD
+    }
+}
+
+/// Push the pages of an anonymous object out to swap and read them back in. Swap is turned on for
+/// the second disk if it is not on already, and turned back off afterwards.
+extern "C" fn swap_round_trip(_: i32, _: *mut c_void) -> *mut c_void {
+    use drivers::blockdev;
+    use umem::pframe::PFrame;
+    let started = swap::usage().is_none() && blockdev::swapon(blockdev::SWAP_DEVICE).is_ok();
+    let slots = match swap::usage() {
+        Some((_, used, _)) => used,
+        None => { dbg!(debug::TEST, "there is no disk to swap to, not testing it"); return GOOD; },
+    };
+    let anon = AnonObj::create().unwrap();
+    let filled = (0..4).all(|i| poke_page(&anon, i, Some(i as u8 + 10)).is_some());
+    let resident = |o: &Rc<Box<MMObj + 'static>>| (0..4).filter(|&i| PFrame::get_resident(o.clone(), i).is_some()).count();
+    // Other objects get their turn too, so keep going until it is ours.
+    let mut tries = 0;
+    while filled && resident(&anon) != 0 && tries < 1000 {
+        if swap::swap_out(4) == 0 {
+            break;
+        }
+        tries += 1;
+    }
+    let out = (resident(&anon), swap::usage().map(|(_, used, _)| used).unwrap_or(0));
+    let back : Vec<Option<u8>> = (0..4).map(|i| poke_page(&anon, i, None)).collect();
+    let after = swap::usage().map(|(_, used, _)| used).unwrap_or(0);
+    let good = filled && out.0 == 0 && out.1 >= slots + 4 && &back[..] == &[Some(10), Some(11), Some(12), Some(13)][..] &&
+               resident(&anon) == 4 && after <= out.1 - 4;
+    drop(anon);
+    let stopped = !started || swap::swapoff().is_ok();
+    if good && stopped {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "{} pages stayed in with {} swap slots used, read back {:?} leaving {} slots used, swapoff worked: {}",
+             out.0, out.1, back, after, stopped);
+        BAD
+    }
+}
+
+/// Cut pieces out of a `VMMap` that is not in use, change the protection across two of its areas
+/// and look for room in it from both ends.
+extern "C" fn vmmap_edits(_: i32, _: *mut c_void) -> *mut c_void {
+    use mm::memman::{prot, map};
+    use umem::vmmap::{VMArea, VMMap, Dir, LOW_PAGE, HIGH_PAGE};
+    let (rw, l) = (prot::READ | prot::WRITE, LOW_PAGE);
+    let mut m = VMMap::new((current_proc_mut!()).get_pagedir_mut());
+    let (a, b) = match (AnonObj::create(), AnonObj::create()) { (Ok(a), Ok(b)) => (a, b), _ => { return BAD; } };
+    if m.insert(VMArea::new(l + 10, 10, 0, rw, map::PRIVATE | map::ANON, a)).is_err() {
+        return BAD;
+    }
+    let layout = |m: &VMMap| -> Vec<(usize, usize, usize, isize)> { m.areas().iter().map(|a| (a.start, a.end, a.off, a.prot)).collect() };
+    // The middle, then the head, then the tail.
+    let mut good = m.remove(l + 13, 2).is_ok() &&
+                   &layout(&m)[..] == &[(l + 10, l + 13, 0, rw), (l + 15, l + 20, 5, rw)][..];
+    good = good && m.remove(l + 10, 1).is_ok() &&
+                   &layout(&m)[..] == &[(l + 11, l + 13, 1, rw), (l + 15, l + 20, 5, rw)][..];
+    good = good && m.remove(l + 19, 1).is_ok() &&
+                   &layout(&m)[..] == &[(l + 11, l + 13, 1, rw), (l + 15, l + 19, 5, rw)][..];
+    if !good {
+        dbg!(debug::TESTFAIL, "after removing pieces the areas are {:?}", layout(&m));
+        return BAD;
+    }
+
+    good = m.insert(VMArea::new(l + 20, 10, 0, rw, map::PRIVATE | map::ANON, b)).is_ok() &&
+           m.set_prot(l + 17, 6, prot::READ).is_ok() &&
+           &layout(&m)[..] == &[(l + 11, l + 13, 1, rw), (l + 15, l + 17, 5, rw), (l + 17, l + 19, 7, prot::READ),
+                                 (l + 20, l + 23, 0, prot::READ), (l + 23, l + 30, 3, rw)][..];
+    if !good {
+        dbg!(debug::TESTFAIL, "after set_prot the areas are {:?}", layout(&m));
+        return BAD;
+    }
+
+    let found = (m.find_range(11, Dir::LowHigh), m.find_range(12, Dir::LowHigh), m.find_range(2, Dir::LowHigh),
+                 m.find_range(2, Dir::HighLow), m.find_range(HIGH_PAGE - l, Dir::HighLow));
+    if found == (Some(l), Some(l + 30), Some(l), Some(HIGH_PAGE - 2), None) {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "find_range gave {:?} for the areas {:?}", found, layout(&m));
+        BAD
+    }
+}
+
+/// The start, end and protection of each area mapped in the current process, in pages.
+fn area_layout() -> Vec<(usize, usize, isize)> {
+    match vmmap::current() {
+        Ok(m) => m.areas().iter().map(|a| (a.start, a.end, a.prot)).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Whether the areas mapped in the current process are exactly `want`.
+fn areas_are(want: &[(usize, usize, isize)]) -> bool {
+    &area_layout()[..] == want
+}
+
+/// Map some anonymous memory, touch it and then change it around with mprotect, munmap and a
+/// MAP_FIXED mmap over part of it, checking the areas and the pages the object keeps each time.
+/// Map a page of an object privately, then read it and write it, checking what the fault handler
+/// put in the page table each time.
+extern "C" fn private_page_faults(_: i32, _: *mut c_void) -> *mut c_void {
+    use mm::{page, pagetable};
+    use mm::memman::{prot, map};
+    let anon = AnonObj::create().unwrap();
+    if poke_page(&anon, 0, Some(7)).is_none() {
+        return BAD;
+    }
+    let addr = match mman::mmap(0, page::SIZE, prot::READ | prot::WRITE, map::PRIVATE, Some(anon.clone()), 0) {
+        Ok(a) => a,
+        Err(e) => { dbg!(debug::TESTFAIL, "unable to map the page: {:?}", e); return BAD; },
+    };
+    let lookup = || (current_proc!()).get_pagedir().lookup(addr);
+    let before = lookup();
+    // Reading maps in the page underneath, but only so it can be read.
+    let read = unsafe { volatile_load(addr as *const u8) };
+    let after_read = lookup();
+    // Writing gets us our own copy we can write to.
+    unsafe { volatile_store(addr as *mut u8, 9); }
+    let after_write = lookup();
+    let written = unsafe { volatile_load(addr as *const u8) };
+    let below = poke_page(&anon, 0, None);
+    let unmapped = mman::munmap(addr, page::SIZE).is_ok() && lookup().is_none();
+    let good = match (before, after_read, after_write) {
+        (None, Some((rp, rf)), Some((wp, wf))) => rf & pagetable::WRITE == 0 && wf & pagetable::WRITE != 0 && rp != wp,
+        _ => false,
+    };
+    if good && read == 7 && written == 9 && below == Some(7) && unmapped {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "mapped {:?} then {:?} after reading {} then {:?} after writing {}, the object has {:?}",
+             before, after_read, read, after_write, written, below);
+        BAD
+    }
+}
+
+/// Where the rmap test maps its page. It is put in the page table directly, there is no area for it.
+const RMAP_TEST_ADDR : usize = 0x20000000;
+
+/// Map one page of an object at two places, then write protect and unmap it everywhere through its
+/// physical address alone.
+extern "C" fn rmap_follows_page(_: i32, _: *mut c_void) -> *mut c_void {
+    use mm::{page, pagetable};
+    let anon = AnonObj::create().unwrap();
+    let pf = match mmobj::lookup_page(anon.clone(), 0, true) { Ok(pf) => pf, Err(_) => { return BAD; } };
+    let kaddr = match pf.dirty() { Ok(p) => p as *mut [u8; page::SIZE] as usize, Err(_) => { return BAD; } };
+    let addrs = [RMAP_TEST_ADDR, RMAP_TEST_ADDR + 4 * page::SIZE];
+    let pd = (current_proc_mut!()).get_pagedir_mut();
+    let paddr = pd.virt_to_phys(kaddr);
+    let flags = (pagetable::PRESENT | pagetable::USER | pagetable::WRITE) as u32;
+    for &a in addrs.iter() {
+        if unsafe { rmap::map(pd, a, paddr, flags, flags) }.is_err() {
+            unsafe { rmap::unmap_range(pd, RMAP_TEST_ADDR, addrs[1] + page::SIZE); }
+            return BAD;
+        }
+    }
+    let mapped = rmap::count(paddr);
+    rmap::write_protect_all(paddr);
+    let protected = addrs.iter().all(|&a| match pd.lookup(a) {
+        Some((p, f)) => p == paddr && f & pagetable::WRITE == 0,
+        None => false,
+    });
+    let still_mapped = rmap::count(paddr);
+    rmap::unmap_all(paddr);
+    let unmapped = addrs.iter().all(|&a| pd.lookup(a).is_none());
+    if mapped == 2 && protected && still_mapped == 2 && unmapped && rmap::count(paddr) == 0 {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "page was mapped {} times, protected {} ({} mappings), unmapped {} ({} left)",
+             mapped, protected, still_mapped, unmapped, rmap::count(paddr));
+        unsafe { rmap::unmap_range(pd, RMAP_TEST_ADDR, addrs[1] + page::SIZE); }
+        BAD
+    }
+}
+
+extern "C" fn mman_areas(_: i32, _: *mut c_void) -> *mut c_void {
+    use mm::page;
+    use mm::memman::{prot, map};
+    let rw = prot::READ | prot::WRITE;
+    let addr = match mman::mmap(0, 4 * page::SIZE, rw, map::PRIVATE | map::ANON, None, 0) {
+        Ok(a) => a,
+        Err(e) => { dbg!(debug::TESTFAIL, "unable to map 4 pages: {:?}", e); return BAD; },
+    };
+    let s = addr / page::SIZE;
+    let mut good = areas_are(&[(s, s + 4, rw)]);
+    for i in 0..4 {
+        unsafe { volatile_store((addr + i * page::SIZE) as *mut u8, i as u8 + 1); }
+    }
+    let obj = match vmmap::current().ok().and_then(|m| m.lookup(s).map(|a| a.obj.clone())) {
+        Some(o) => o,
+        None => { return BAD; },
+    };
+    good = good && obj.resident() == 4;
+
+    good = good && mman::mprotect(addr + page::SIZE, page::SIZE, prot::READ).is_ok();
+    good = good && areas_are(&[(s, s + 1, rw), (s + 1, s + 2, prot::READ), (s + 2, s + 4, rw)]);
+    good = good && unsafe { volatile_load((addr + page::SIZE) as *const u8) } == 2;
+
+    good = good && mman::munmap(addr + 2 * page::SIZE, page::SIZE).is_ok();
+    good = good && areas_are(&[(s, s + 1, rw), (s + 1, s + 2, prot::READ), (s + 3, s + 4, rw)]);
+    good = good && obj.resident() == 3;
+
+    let fixed = mman::mmap(addr, 2 * page::SIZE, rw, map::PRIVATE | map::ANON | map::FIXED, None, 0);
+    good = good && fixed == Ok(addr);
+    good = good && areas_are(&[(s, s + 2, rw), (s + 3, s + 4, rw)]);
+    good = good && obj.resident() == 1;
+    good = good && unsafe { volatile_load(addr as *const u8) } == 0;
+    good = good && unsafe { volatile_load((addr + 3 * page::SIZE) as *const u8) } == 4;
+
+    good = good && mman::munmap(addr, 4 * page::SIZE).is_ok();
+    good = good && area_layout().is_empty() && obj.resident() == 0;
+    if good {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "areas are {:?}, the first object keeps {} pages", area_layout(), obj.resident());
+        BAD
+    }
+}
+
+/// Map and touch `n` anonymous pages, say we are done and then wait to be killed.
+extern "C" fn oom_hog(n: i32, v: *mut c_void) -> *mut c_void {
+    use mm::page;
+    use mm::memman::{prot, map};
+    let ready : Rc<Semaphore> = unsafe { ProcArgs::from_arg(v).unwrap() };
+    let addr = match mman::mmap(0, n as usize * page::SIZE, prot::READ | prot::WRITE, map::PRIVATE | map::ANON, None, 0) {
+        Ok(a) => a,
+        Err(_) => { ready.up(); return BAD; },
+    };
+    for i in 0..n as usize {
+        unsafe { volatile_store((addr + i * page::SIZE) as *mut u8, 1); }
+    }
+    ready.up();
+    // Nothing ever ups this, we only get out by being killed.
+    let _ = Semaphore::new(0).down();
+    BAD
+}
+
+/// Start a child using a lot of memory and one using a little, and check the oom killer goes for the
+/// big one.
+extern "C" fn oom_picks_biggest(_: i32, _: *mut c_void) -> *mut c_void {
+    use umem::oom;
+    let ready = Rc::new(Semaphore::new(0));
+    let big = match kproc::KProc::new("big oom hog".to_string(), oom_hog, 16, unsafe { ProcArgs::new(ready.clone()).unwrap().to_arg() }) {
+        Ok(p) => p,
+        Err(_) => { return BAD; },
+    };
+    let small = match kproc::KProc::new("small oom hog".to_string(), oom_hog, 1, unsafe { ProcArgs::new(ready.clone()).unwrap().to_arg() }) {
+        Ok(p) => p,
+        Err(_) => {
+            let _ = signal::kill(big, signal::SIGKILL);
+            let _ = KProc::waitpid(kproc::Pid(big), 0);
+            return BAD;
+        },
+    };
+    let touched = ready.down().is_ok() && ready.down().is_ok();
+    let killed = touched && oom::kill_something();
+    let picked = |pid: ProcId| KProc::get_proc(&pid).map(|p| p.borrow().is_killed()).unwrap_or(false);
+    let (big_picked, small_picked) = (picked(big), picked(small));
+    // Whatever happened neither of them is going anywhere on its own.
+    let _ = signal::kill(small, signal::SIGKILL);
+    if !big_picked {
+        let _ = signal::kill(big, signal::SIGKILL);
+    }
+    let status = KProc::waitpid(kproc::Pid(big), 0);
+    let _ = KProc::waitpid(kproc::Pid(small), 0);
+    match status {
+        Ok((_, st)) if killed && big_picked && !small_picked && st == ProcStatus::signaled(signal::SIGKILL) => GOOD,
+        x => {
+            dbg!(debug::TESTFAIL, "oom killer killed something: {}, picked the big one {} and the small one {}, big one ended with {:?}",
+                 killed, big_picked, small_picked, x);
+            BAD
+        },
+    }
+}
+
+/// Give ourself a heap and grow and shrink it, then check it will not grow into another mapping or
+/// past our address space limit.
+extern "C" fn brk_moves(_: i32, _: *mut c_void) -> *mut c_void {
+    use mm::page;
+    use mm::memman::{prot, map};
+    use umem::vmmap::LOW_PAGE;
+    let rw = prot::READ | prot::WRITE;
+    let s = LOW_PAGE + 100;
+    let start = s * page::SIZE;
+    (current_proc_mut!()).set_start_brk(start);
+    let mut good = mman::brk(0) == Ok(start);
+
+    good = good && mman::brk(start + 3 * page::SIZE) == Ok(start + 3 * page::SIZE) && areas_are(&[(s, s + 3, rw)]);
+    if good {
+        unsafe { volatile_store((start + 2 * page::SIZE) as *mut u8, 1); }
+    }
+    good = good && mman::brk(start + page::SIZE + 10) == Ok(start + page::SIZE + 10) && areas_are(&[(s, s + 2, rw)]);
+    good = good && mman::brk(start + page::SIZE) == Ok(start + page::SIZE) && areas_are(&[(s, s + 1, rw)]);
+    if !good {
+        dbg!(debug::TESTFAIL, "growing and shrinking the heap left {:?} with a break of {:#x}", area_layout(), mman::brk(0).unwrap_or(0));
+        return BAD;
+    }
+
+    let fixed = mman::mmap((s + 4) * page::SIZE, page::SIZE, rw, map::PRIVATE | map::ANON | map::FIXED, None, 0);
+    good = fixed.is_ok() && mman::brk(start + 6 * page::SIZE) == Err(errno::ENOMEM) && mman::brk(0) == Ok(start + page::SIZE);
+
+    // Leave room for just one more page.
+    let old = (current_proc!()).get_rlimit(resource::RLIMIT_AS);
+    (current_proc_mut!()).set_rlimit(resource::RLIMIT_AS, resource::RLimit { cur: 3 * page::SIZE, max: old.max });
+    good = good && mman::brk(start + 3 * page::SIZE) == Err(errno::ENOMEM) && mman::brk(0) == Ok(start + page::SIZE);
+    good = good && mman::brk(start + 2 * page::SIZE) == Ok(start + 2 * page::SIZE) &&
+                   areas_are(&[(s, s + 2, rw), (s + 4, s + 5, rw)]);
+    (current_proc_mut!()).set_rlimit(resource::RLIMIT_AS, old);
+
+    let cleaned = mman::brk(start).is_ok() && mman::munmap((s + 4) * page::SIZE, page::SIZE).is_ok() && areas_are(&[]);
+    if good && cleaned {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "heap limits were not kept, areas are {:?} with a break of {:#x}", area_layout(), mman::brk(0).unwrap_or(0));
+        BAD
+    }
+}
+
+/// How many lockdep problems the tests caused on purpose.
+static mut EXPECTED_LOCK_PROBLEMS : usize = 0;
+
+/// Take two locks in one order and then the other, which lockdep should complain about. Lockdep
+/// only complains about an ordering the first time it sees it so this only checks the first run.
+extern "C" fn lock_inversion_found(_: i32, _: *mut c_void) -> *mut c_void {
+    static mut RAN : bool = false;
+    if !cfg!(LOCKDEP) || unsafe { RAN } {
+        return GOOD;
+    }
+    unsafe { RAN = true; }
+    let a = KMutex::new("lockdep test a");
+    let b = KMutex::new("lockdep test b");
+    let before = lockdep::problems();
+    a.lock_nocancel();
+    b.lock_nocancel();
+    b.unlock();
+    a.unlock();
+    if lockdep::problems() != before {
+        dbg!(debug::TESTFAIL, "lockdep complained about taking 'a' then 'b'");
+        return BAD;
+    }
+    b.lock_nocancel();
+    a.lock_nocancel();
+    a.unlock();
+    b.unlock();
+    let after = lockdep::problems();
+    unsafe { EXPECTED_LOCK_PROBLEMS += after - before; }
+    if after == before + 1 {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "lockdep found {} problems taking 'b' then 'a', expected 1", after - before);
+        BAD
+    }
+}
+
+extern "C" fn no_lock_problems(_: i32, _: *mut c_void) -> *mut c_void {
+    let n = lockdep::problems() - unsafe { EXPECTED_LOCK_PROBLEMS };
+    if n == 0 {
+        GOOD
+    } else {
+        dbg!(debug::TESTFAIL, "lockdep found {} problems with how locks were used", n);
+        BAD
+    }
+}
+
 fn do_counter(num: i32, stop: i32, dat: &Mutex<i32>) -> i32 {
     let mut c = 0;
     let mut v = (*dat).force_lock();
@@ -28,12 +28,12 @@ struct Allocator {
     backup : BackupAllocator,
 }
 
-/// A type that can allocate pages. Currently is just a shim to the C page allocator.
+/// A type that can allocate pages. It gets them from the buddy allocator in `page`.
 struct PageAllocator;
 impl PageAllocator {
     pub unsafe fn alloc_n(&self, n: u32) -> *mut u8 {
         use super::page;
-        page::c_alloc_n(n) as *mut u8
+        page::alloc_n::<u8>(n as usize).unwrap_or(ptr::null_mut())
     }
     pub unsafe fn free_n(&self, ptr: *mut u8, n : u32) {
         use libc::c_void;
@@ -211,7 +211,8 @@ impl fmt::Debug for Allocator {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         try!(writeln!(f, "Weenix allocator"));
         try!(writeln!(f, "{:?}", self.slabs));
-        try!(writeln!(f, "free pages: {:?}", unsafe { page::free_count()} ));
+        try!(writeln!(f, "free pages: {:?}", page::free_count()));
+        try!(writeln!(f, "free page blocks by order: {:?}", page::free_blocks()));
         writeln!(f, "{:?}", self.backup)
     }
 }
@@ -2,7 +2,7 @@
 
 //! # The Reenix base allocation library.
 //!
-//! This is currently just a shim around C code. It might become rust later
+//! This has the page allocator and the kernel heap. The slab allocators are still C code.
 
 #![crate_name="mm"]
 #![crate_type="rlib"]
@@ -53,6 +53,7 @@ mod mm {
     pub use super::alloc;
 }
 
+pub mod page;
 pub mod pagetable;
 pub mod utils;
 pub mod alloc;
@@ -63,6 +64,7 @@ mod backup;
 pub mod poison {
     pub const ENABLED : bool = true;
     pub const ALLOC   : u8   = 0xBB;
+    pub const FREE    : u8   = 0xDD;
 }
 
 #[cfg(all(kernel, target_arch="x86"))]
@@ -124,98 +126,6 @@ pub mod tlb {
     }
 }
 
-pub mod page {
-    use core::intrinsics::transmute;
-    use libc::{uintptr_t, c_void};
-    use core::prelude::*;
-    extern "C" {
-        #[link_name = "page_add_range"]
-        pub fn c_add_range(start: uintptr_t, end: uintptr_t);
-        #[link_name = "page_alloc"]
-        pub fn c_alloc() -> *mut c_void;
-        #[link_name = "page_free"]
-        pub fn free(page: *mut c_void);
-        #[link_name = "page_alloc_n"]
-        pub fn c_alloc_n(num: u32) -> *mut c_void;
-        #[link_name = "page_free_n"]
-        pub fn free_n(pages: *mut c_void, num: u32);
-        #[link_name = "page_free_count"]
-        pub fn free_count() -> u32;
-
-        #[deny(dead_code)]
-        pub fn page_init();
-    }
-    pub fn init_stage1() { unsafe { page_init(); } }
-    pub fn init_stage2() {}
-
-    pub const SHIFT  : usize = 12;
-    pub const SIZE   : usize = 1 << SHIFT;
-    pub const MASK   : usize = (!0) << SHIFT;
-    pub const NSIZES : usize = 8;
-
-    pub unsafe fn alloc<T>() -> super::Allocation<*mut T> {
-        let res = c_alloc();
-        if res.is_null() { Err(super::AllocError) } else { Ok(res as *mut T) }
-    }
-
-    pub unsafe fn alloc_n<T>(pages: usize) -> super::Allocation<*mut T> {
-        let res = c_alloc_n(pages as u32);
-        if res.is_null() { Err(super::AllocError) } else { Ok(res as *mut T) }
-    }
-
-    #[inline]
-    pub unsafe fn const_align_down<T>(x: *const T) -> *const T {
-        transmute::<usize, *const T>(
-            transmute::<*const T, usize>(x) & MASK)
-    }
-
-    #[inline]
-    pub unsafe fn align_down<T>(x: *mut T) -> *mut T {
-        transmute::<usize, *mut T>(
-            transmute::<*mut T, usize>(x) & MASK)
-    }
-
-    #[inline]
-    pub unsafe fn align_up<T>(x: *mut T) -> *mut T {
-        transmute::<usize, *mut T>(
-            ((transmute::<*mut T, usize>(x) - 1) & MASK) + SIZE)
-    }
-
-    #[inline]
-    pub unsafe fn const_align_up<T>(x: *const T) -> *const T {
-        transmute::<usize, *const T>(
-            ((transmute::<*const T, usize>(x) - 1) & MASK) + SIZE)
-    }
-
-    #[inline]
-    pub fn offset<T>(x: *const T) -> usize {
-        unsafe { transmute::<*const T, usize>(x) & (!MASK) }
-    }
-
-    #[inline]
-    pub unsafe fn num_to_addr<T>(x: usize) -> *mut T {
-        transmute::<usize, *mut T>(x << SHIFT)
-    }
-
-    #[inline]
-    pub fn addr_to_num<T>(x: *const T) -> usize {
-        unsafe { transmute::<*const T, usize>(x) >> SHIFT }
-    }
-
-    #[inline]
-    pub fn aligned<T>(x: *const T) -> bool {
-        0 == offset(x)
-    }
-
-    #[inline]
-    pub fn same<T>(x: *const T, y: *const T) -> bool {
-        unsafe { const_align_down(x) == const_align_down(y) }
-    }
-
-    // TODO Make traits implemented by usize and *const/mut T for these so we don't need to call
-    // them directly.
-}
-
 #[doc(hidden)]
 mod std {
     pub use core::marker;
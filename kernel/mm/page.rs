@@ -0,0 +1,437 @@
+// TODO Copyright Header
+
+//! The physical page allocator.
+//!
+//! This is a buddy allocator. Memory is handed out in blocks of 2^order pages for orders below
+//! `NSIZES`, each aligned to its own size from the start of the range it came from. A block that is
+//! too big is split in half until it is the right size, and a freed block is joined back up with its
+//! buddy, the other half of the block it was split from, whenever that is free too.
+//!
+//! Each range of memory we are given keeps a free list for every order, threaded through the free
+//! blocks themselves, and a bitmap per order saying which blocks are on it. The bitmaps are put at
+//! the end of the range. They let us find out if a buddy is free without searching and let us catch
+//! a block being freed while it, or any part of it, is already free.
+//!
+//! The C code uses this through `page_alloc`, `page_alloc_n`, `page_free`, `page_free_n` and
+//! `page_free_count` as declared in `mm/page.h`.
+
+use core::prelude::*;
+use core::cmp::min;
+use core::intrinsics::{transmute, write_bytes};
+use core::ptr;
+use libc::{uintptr_t, c_void};
+use poison;
+
+pub const SHIFT  : usize = 12;
+pub const SIZE   : usize = 1 << SHIFT;
+pub const MASK   : usize = (!0) << SHIFT;
+/// The number of block sizes, the largest being 2^(NSIZES - 1) pages. This must be the same as
+/// PAGE_NSIZES in mm/page.h.
+pub const NSIZES : usize = 16;
+
+/// The most separate ranges of memory we can be given.
+const MAX_GROUPS : usize = 4;
+
+/// The start of a free block, which is how the free lists are linked together.
+struct FreeBlock {
+    next : *mut FreeBlock,
+    prev : *mut FreeBlock,
+}
+
+/// A range of memory we hand out pages from.
+struct PageGroup {
+    /// The first page we manage. Blocks are aligned to their size relative to this.
+    base : usize,
+    /// One past the last page we manage. Our bitmaps start here.
+    end  : usize,
+    free : [*mut FreeBlock; NSIZES],
+    /// One bit for each block of each order, set if that block is on its free list.
+    map  : [*mut u32; NSIZES],
+}
+
+const EMPTY_GROUP : PageGroup = PageGroup {
+    base : 0,
+    end  : 0,
+    free : [0 as *mut FreeBlock; NSIZES],
+    map  : [0 as *mut u32; NSIZES],
+};
+
+static mut GROUPS : [PageGroup; MAX_GROUPS] = [EMPTY_GROUP, EMPTY_GROUP, EMPTY_GROUP, EMPTY_GROUP];
+static mut NGROUPS : usize = 0;
+static mut NFREE : usize = 0;
+
+/// When an allocation leaves fewer free pages than this we say memory is low.
+pub const LOW_WATER : u32 = 128;
+
+/// What to call when memory is low. See `set_low_memory_hook`.
+static mut LOW_MEMORY_HOOK : Option<fn(bool)> = None;
+
+/// How many free blocks of each order there are. This is exported so gdb can find it.
+#[no_mangle]
+pub static mut PAGE_FREE_BLOCKS : [usize; NSIZES] = [0; NSIZES];
+
+/// Called with every allocation so gdb can keep track of them. See mm/memcheck.py.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __py_hook_page_alloc(_addr: *mut c_void, _npages: u32) {}
+
+/// Called with every free so gdb can keep track of them. See mm/memcheck.py.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __py_hook_page_free(_addr: *mut c_void, _npages: u32) {}
+
+#[inline]
+fn block_size(order: usize) -> usize { SIZE << order }
+
+/// The number of 32 bit words needed for a bitmap of the blocks of `order` in `npages` pages.
+#[inline]
+fn map_words(npages: usize, order: usize) -> usize { ((npages >> order) + 31) / 32 }
+
+impl PageGroup {
+    /// Take the pages in `[start, end)`, both page aligned, putting our bitmaps at the end.
+    unsafe fn init(&mut self, start: usize, end: usize) {
+        let npages = (end - start) >> SHIFT;
+        let mut mapend = end;
+        for order in 0..NSIZES {
+            let words = map_words(npages, order);
+            mapend -= words * 4;
+            self.map[order] = mapend as *mut u32;
+            write_bytes(self.map[order], 0, words);
+            self.free[order] = ptr::null_mut();
+        }
+        self.base = start;
+        // The bitmaps were sized for all of the pages, even the ones they are now taking up.
+        self.end = if mapend & MASK > start { mapend & MASK } else { start };
+    }
+
+    #[inline]
+    fn contains(&self, addr: usize) -> bool { addr >= self.base && addr < self.end }
+
+    #[inline]
+    fn index(&self, addr: usize, order: usize) -> usize { (addr - self.base) >> (SHIFT + order) }
+
+    /// The block of `order` that `addr` is in.
+    #[inline]
+    fn block_of(&self, addr: usize, order: usize) -> usize {
+        self.base + ((addr - self.base) & !(block_size(order) - 1))
+    }
+
+    /// Whether the block of `order` starting at `addr` is on the free list.
+    fn is_free(&self, addr: usize, order: usize) -> bool {
+        if addr + block_size(order) > self.end {
+            return false;
+        }
+        let i = self.index(addr, order);
+        unsafe { *self.map[order].offset((i / 32) as isize) & (1 << (i % 32)) != 0 }
+    }
+
+    fn set_free(&mut self, addr: usize, order: usize, free: bool) {
+        let i = self.index(addr, order);
+        unsafe {
+            let word = self.map[order].offset((i / 32) as isize);
+            if free { *word |= 1 << (i % 32); } else { *word &= !(1 << (i % 32)); }
+        }
+    }
+
+    /// Put the block of `order` at `addr` on its free list.
+    unsafe fn push(&mut self, addr: usize, order: usize) {
+        let blk = addr as *mut FreeBlock;
+        (*blk).prev = ptr::null_mut();
+        (*blk).next = self.free[order];
+        if let Some(n) = self.free[order].as_mut() {
+            n.prev = blk;
+        }
+        self.free[order] = blk;
+        self.set_free(addr, order, true);
+        PAGE_FREE_BLOCKS[order] += 1;
+    }
+
+    /// Take the block of `order` at `addr`, which is free, off of its free list.
+    unsafe fn remove(&mut self, addr: usize, order: usize) {
+        let blk = addr as *mut FreeBlock;
+        match (*blk).prev.as_mut() {
+            Some(p) => { p.next = (*blk).next; },
+            None => { self.free[order] = (*blk).next; },
+        }
+        if let Some(n) = (*blk).next.as_mut() {
+            n.prev = (*blk).prev;
+        }
+        self.set_free(addr, order, false);
+        PAGE_FREE_BLOCKS[order] -= 1;
+    }
+
+    /// Add all of our pages to the free lists, in the biggest blocks they fit in.
+    unsafe fn free_all(&mut self) {
+        let mut addr = self.base;
+        while addr < self.end {
+            let mut order = NSIZES - 1;
+            while (addr - self.base) & (block_size(order) - 1) != 0 || addr + block_size(order) > self.end {
+                order -= 1;
+            }
+            self.push(addr, order);
+            addr += block_size(order);
+        }
+    }
+
+    /// Get a block of `order`, splitting up a bigger one if we have to.
+    unsafe fn alloc(&mut self, order: usize) -> Option<usize> {
+        let mut o = match (order..NSIZES).find(|&o| !self.free[o].is_null()) {
+            Some(o) => o,
+            None => { return None; },
+        };
+        let addr = self.free[o] as usize;
+        self.remove(addr, o);
+        while o > order {
+            o -= 1;
+            dbg!(debug::PAGEALLOC, "split {:#x} ({}) into {:#x} and {:#x}", addr, o + 1, addr, addr + block_size(o));
+            self.push(addr + block_size(o), o);
+        }
+        Some(addr)
+    }
+
+    /// Whether any of the block of `order` at `addr` is already free.
+    fn overlaps_free(&self, addr: usize, order: usize) -> bool {
+        // It could be inside of a bigger free block, or be the free block itself.
+        if (order..NSIZES).any(|o| self.is_free(self.block_of(addr, o), o)) {
+            return true;
+        }
+        // Or there could be smaller free blocks inside of it.
+        (0..order).any(|o| {
+            let mut cur = addr;
+            while cur < addr + block_size(order) {
+                if self.is_free(cur, o) {
+                    return true;
+                }
+                cur += block_size(o);
+            }
+            false
+        })
+    }
+
+    /// Free the block of `order` at `addr`, joining it with its buddies for as long as they are
+    /// free.
+    unsafe fn free(&mut self, addr: usize, order: usize) {
+        if (addr - self.base) & (block_size(order) - 1) != 0 || addr + block_size(order) > self.end {
+            kpanic!("freeing {:#x} as a block of {} pages, which is not a block we could have given out", addr, 1usize << order);
+        }
+        if self.overlaps_free(addr, order) {
+            kpanic!("double free of {} pages at {:#x}", 1usize << order, addr);
+        }
+        if poison::ENABLED {
+            write_bytes(addr as *mut u8, poison::FREE, block_size(order));
+        }
+        let (mut addr, mut order) = (addr, order);
+        while order < NSIZES - 1 {
+            let buddy = self.base + ((addr - self.base) ^ block_size(order));
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            dbg!(debug::PAGEALLOC, "joining {:#x} and {:#x} ({}) into {:#x}", addr, buddy, order, min(addr, buddy));
+            self.remove(buddy, order);
+            addr = min(addr, buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+}
+
+unsafe fn groups() -> &'static mut [PageGroup] { &mut GROUPS[..NGROUPS] }
+
+/// The smallest order whose blocks hold `npages` pages.
+fn order_for(npages: usize) -> usize {
+    match (0..NSIZES).find(|&o| (1 << o) >= npages) {
+        Some(o) => o,
+        None => { kpanic!("Implementation does not permit allocating {} pages!", npages); },
+    }
+}
+
+unsafe fn alloc_order(order: usize) -> Option<usize> {
+    let mut retries = 2;
+    loop {
+        // Prefer not splitting anything, then splitting the smallest block we can.
+        for o in order..NSIZES {
+            for g in groups().iter_mut() {
+                if !g.free[o].is_null() {
+                    let addr = g.alloc(order).expect("there was a free block big enough");
+                    if poison::ENABLED {
+                        write_bytes(addr as *mut u8, poison::ALLOC, block_size(order));
+                    }
+                    NFREE -= 1 << order;
+                    dbg!(debug::MM, "allocating {} pages (addr {:#x})", 1usize << order, addr);
+                    return Some(addr);
+                }
+            }
+        }
+        dbg!(debug::PAGEALLOC|debug::MM, "unable to allocate {} pages, {} are free, free blocks by order are {:?}",
+             1usize << order, NFREE, PAGE_FREE_BLOCKS);
+        if retries == 0 {
+            return None;
+        }
+        retries -= 1;
+        // The slab allocators might be holding on to empty pages we can have back right now.
+        super::alloc::reclaim_memory();
+    }
+}
+
+unsafe fn free_order(addr: usize, order: usize) {
+    let g = match groups().iter_mut().find(|g| g.contains(addr)) {
+        Some(g) => g,
+        None => { kpanic!("freeing {:#x} which is not memory we hand out", addr); },
+    };
+    g.free(addr, order);
+    NFREE += 1 << order;
+    dbg!(debug::MM, "freed {} pages (addr {:#x}); {} pages currently free", 1usize << order, addr, NFREE);
+}
+
+/// Add the pages in `[start, end)` to those we hand out. Each page may only be added once.
+#[no_mangle]
+pub extern "C" fn page_add_range(start: uintptr_t, end: uintptr_t) {
+    dbg!(debug::MM, "Page System adding range: {:#x} to {:#x}", start, end);
+    let (start, end) = ((start as usize) & MASK, (end as usize) & MASK);
+    unsafe {
+        if NGROUPS == MAX_GROUPS {
+            dbg!(debug::MM|debug::DANGER, "already have {} ranges of pages, ignoring {:#x} to {:#x}", MAX_GROUPS, start, end);
+            return;
+        }
+        if end <= start {
+            return;
+        }
+        let g = &mut GROUPS[NGROUPS];
+        g.init(start, end);
+        if g.base < g.end {
+            g.free_all();
+            NFREE += (g.end - g.base) >> SHIFT;
+            NGROUPS += 1;
+        }
+    }
+}
+
+/// Allocate one page, returning null if there are none left.
+#[no_mangle]
+pub extern "C" fn page_alloc() -> *mut c_void { page_alloc_n(1) }
+
+/// Allocate a block of at least `npages` pages, returning null if there is not one free.
+#[no_mangle]
+pub extern "C" fn page_alloc_n(npages: u32) -> *mut c_void {
+    let addr = unsafe { alloc_order(order_for(npages as usize)) }.map(|a| a as *mut c_void).unwrap_or(ptr::null_mut());
+    __py_hook_page_alloc(addr, npages);
+    if addr.is_null() || page_free_count() < LOW_WATER {
+        low_memory(false);
+    }
+    addr
+}
+
+/// Free the page at `addr`, which must have come from `page_alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn page_free(addr: *mut c_void) { page_free_n(addr, 1) }
+
+/// Free the block of `npages` pages at `addr`, which must have come from `page_alloc_n` with the
+/// same number of pages.
+#[no_mangle]
+pub unsafe extern "C" fn page_free_n(addr: *mut c_void, npages: u32) {
+    __py_hook_page_free(addr, npages);
+    free_order(addr as usize, order_for(npages as usize));
+}
+
+/// The number of free pages. Allocating a block of pages can still fail when there are at least
+/// that many free if none of the free blocks are big enough.
+#[no_mangle]
+pub extern "C" fn page_free_count() -> u32 { unsafe { NFREE as u32 } }
+
+pub fn init_stage1() {}
+pub fn init_stage2() {}
+
+pub unsafe fn alloc<T>() -> super::Allocation<*mut T> {
+    let res = page_alloc();
+    if res.is_null() { Err(super::AllocError) } else { Ok(res as *mut T) }
+}
+
+pub unsafe fn alloc_n<T>(pages: usize) -> super::Allocation<*mut T> {
+    let res = page_alloc_n(pages as u32);
+    if res.is_null() { Err(super::AllocError) } else { Ok(res as *mut T) }
+}
+
+/// Like `alloc_n` but if there are no pages we wait a little while for some to be freed and try
+/// again. This may sleep so it must only be used by threads that are able to.
+pub unsafe fn alloc_n_wait<T>(pages: usize) -> super::Allocation<*mut T> {
+    alloc_n(pages).or_else(|_| {
+        low_memory(true);
+        alloc_n(pages)
+    })
+}
+
+/// Have `f` called whenever an allocation leaves fewer than `LOW_WATER` pages free or fails. It is
+/// told whether the caller can sleep while memory is freed up. We cannot free anything ourself, the
+/// page cache and swap are far above us, so this is how they find out they are needed.
+pub fn set_low_memory_hook(f: fn(bool)) {
+    unsafe { LOW_MEMORY_HOOK = Some(f); }
+}
+
+/// Say that memory is low, waiting for some to be freed if `wait` is set.
+fn low_memory(wait: bool) {
+    if let Some(f) = unsafe { LOW_MEMORY_HOOK } {
+        f(wait);
+    }
+}
+
+pub unsafe fn free(page: *mut c_void) { page_free(page) }
+
+pub unsafe fn free_n(pages: *mut c_void, num: u32) { page_free_n(pages, num) }
+
+/// The number of free pages.
+pub fn free_count() -> u32 { page_free_count() }
+
+/// How many free blocks of each order there are.
+pub fn free_blocks() -> [usize; NSIZES] { unsafe { PAGE_FREE_BLOCKS } }
+
+#[inline]
+pub unsafe fn const_align_down<T>(x: *const T) -> *const T {
+    transmute::<usize, *const T>(
+        transmute::<*const T, usize>(x) & MASK)
+}
+
+#[inline]
+pub unsafe fn align_down<T>(x: *mut T) -> *mut T {
+    transmute::<usize, *mut T>(
+        transmute::<*mut T, usize>(x) & MASK)
+}
+
+#[inline]
+pub unsafe fn align_up<T>(x: *mut T) -> *mut T {
+    transmute::<usize, *mut T>(
+        ((transmute::<*mut T, usize>(x) - 1) & MASK) + SIZE)
+}
+
+#[inline]
+pub unsafe fn const_align_up<T>(x: *const T) -> *const T {
+    transmute::<usize, *const T>(
+        ((transmute::<*const T, usize>(x) - 1) & MASK) + SIZE)
+}
+
+#[inline]
+pub fn offset<T>(x: *const T) -> usize {
+    unsafe { transmute::<*const T, usize>(x) & (!MASK) }
+}
+
+#[inline]
+pub unsafe fn num_to_addr<T>(x: usize) -> *mut T {
+    transmute::<usize, *mut T>(x << SHIFT)
+}
+
+#[inline]
+pub fn addr_to_num<T>(x: *const T) -> usize {
+    unsafe { transmute::<*const T, usize>(x) >> SHIFT }
+}
+
+#[inline]
+pub fn aligned<T>(x: *const T) -> bool {
+    0 == offset(x)
+}
+
+#[inline]
+pub fn same<T>(x: *const T, y: *const T) -> bool {
+    unsafe { const_align_down(x) == const_align_down(y) }
+}
+
+// TODO Make traits implemented by usize and *const/mut T for these so we don't need to call
+// them directly.
@@ -63,6 +63,36 @@ impl PageDir {
         // TODO Rewrite this in rust.
         unsafe { base_virt_to_phys(vaddr as u32) as usize }
     }
+
+    /// Find where `vaddr` is mapped in this page directory. Returns the physical address and the
+    /// flags that apply to it, or None if it is not mapped.
+    pub fn lookup(&self, vaddr: usize) -> Option<(usize, usize)> {
+        let pd = unsafe { &*self.0 };
+        let index = vaddr_to_pdindex(vaddr);
+        let pde = pd.pd_physical[index];
+        let pt = pd.pd_virtual[index];
+        if pde & PRESENT == 0 || pt.is_null() {
+            return None;
+        }
+        let pte = unsafe { *pt.offset(vaddr_to_ptindex(vaddr) as isize) };
+        if pte & PRESENT == 0 {
+            None
+        } else {
+            // Both levels have to allow something for it to be allowed.
+            Some(((pte & page::MASK) | (vaddr & !page::MASK), pde & pte & !page::MASK))
+        }
+    }
+}
+
+/// Mark the kernel page at `vaddr` present or not. The kernel page tables are shared by every page
+/// directory so this changes it everywhere.
+pub unsafe fn set_kernel_page_present(vaddr: usize, present: bool) {
+    let pd = &*current;
+    let pt = pd.pd_virtual[vaddr_to_pdindex(vaddr)];
+    assert!(!pt.is_null(), "kernel page {:#x} has no page table", vaddr);
+    let pte = pt.offset(vaddr_to_ptindex(vaddr) as isize);
+    if present { *pte |= PRESENT; } else { *pte &= !PRESENT; }
+    ::tlb::flush(vaddr as *mut ::libc::c_void);
 }
 
 #[inline] pub fn vaddr_to_pdindex(vaddr: usize) -> usize { ((vaddr) >> page::SHIFT) / ENTRY_COUNT }
@@ -7,7 +7,8 @@ use startup::{gdt, tsd};
 use libc::{c_void, uintptr_t};
 use interrupt;
 use std::mem::{transmute, transmute_copy};
-use std::collections::VecDeque;
+use startup::cmdline;
+use sched::{self, SchedInfo, SchedPolicy};
 use std::ptr::null_mut;
 use std::rc::*;
 use pcell::*;
@@ -30,6 +31,15 @@ pub struct Context {
 
     kstack : usize,
     kstack_size : usize,
+
+    pub sched : SchedInfo,
+
+    /// The base and limit, in pages, of this thread's user TLS segment if it has set one up.
+    pub tls : Option<(usize, u32)>,
+    /// The user's %fs, which the kernel never touches so it is kept across switches here.
+    fs : u16,
+    /// How many interrupt handlers this thread was inside of when it was switched away from.
+    intr_depth : usize,
 }
 
 static mut BOOTSTRAP_FUNC_CTX : *mut Context = 0 as *mut Context;
@@ -54,35 +64,31 @@ pub fn cleanup_bootstrap_function() {
     }
 }
 
-struct SleepingThread(*mut Context);
-struct RunQueue(VecDeque<SleepingThread>);
+struct RunQueue(Box<SchedPolicy + 'static>);
 
 impl RunQueue {
     fn push(&mut self, ctx: &mut Context) {
         assert!(interrupt::get_ipl() == interrupt::HIGH);
         let &mut RunQueue(ref mut b) = self;
-        b.push_back(SleepingThread(unsafe { transmute(ctx) }));
+        b.push(ctx as *mut Context);
         dbg!(debug::SCHED, "there are now {} threads waiting to be executed", b.len());
     }
 
     /// Needed to make sure the whole check isnt optimized away.
     ///
     /// NOTE This is the closest way I can say that a value is volatile...
-    unsafe fn get_inner(&mut self) -> &mut VecDeque<SleepingThread> {
+    unsafe fn get_inner(&mut self) -> &mut Box<SchedPolicy + 'static> {
         use std::intrinsics::volatile_load;
         let &mut RunQueue(ref mut b) = self;
-        volatile_load::<&mut VecDeque<SleepingThread>>(&b as *const &mut VecDeque<SleepingThread>)
+        volatile_load::<&mut Box<SchedPolicy + 'static>>(&b as *const &mut Box<SchedPolicy + 'static>)
     }
 
     fn pop(&mut self) -> &mut Context {
         loop {
             assert!(interrupt::get_ipl() == interrupt::HIGH);
-            if let Some(next) = unsafe { self.get_inner().pop_front() } {
-                // TODO Put this dbg back in.
-                //dbg!(debug::SCHED, "found context for thead {} in {}", next.get_current_thread(), next.get_current_proc());
+            if let Some(c) = unsafe { self.get_inner().pop() } {
                 dbg!(debug::SCHED, "found a thread and executing it");
                 assert!(interrupt::get_ipl() == interrupt::HIGH);
-                let SleepingThread(c) = next;
                 return unsafe { c.as_mut().expect("Null thread in queue?") };
             }
             interrupt::disable();
@@ -91,27 +97,16 @@ impl RunQueue {
             interrupt::wait();
             interrupt::set_ipl(interrupt::HIGH);
         }
-        /* NB The below does not seem to work. I am unsure why. I think it is trying to be smart
-         * and realizes that since we don't add anything to b it must continue to be false so it
-         * just infinite loops maybe?
-         */
-        /*
-        while b.is_empty() {
-            interrupt::disable();
-            interrupt::set_ipl(interrupt::LOW);
-            dbg!(debug::SCHED, "No threads waiting to be executed!");
-            interrupt::wait();
-            interrupt::set_ipl(interrupt::HIGH);
-        }
-        if let Some(next) = b.pop_front() {
-            // TODO Put this dbg back in.
-            //dbg!(debug::SCHED, "found context for thead {} in {}", next.get_current_thread(), next.get_current_proc());
-            let SleepingThread(c) = next;
-            unsafe { c.as_mut().expect("Null thread in queue?") }
-        } else {
-            kpanic!("No context found for next thread despite is_empty returning false!");
+    }
+
+    /// Move everything waiting over to a new policy.
+    fn replace(&mut self, new: Box<SchedPolicy + 'static>) {
+        let &mut RunQueue(ref mut b) = self;
+        let mut old = ::std::mem::replace(b, new);
+        while let Some(c) = old.pop() {
+            b.push(c);
         }
-        */
+        dbg!(debug::SCHED, "switched scheduling policy from {} to {}", old.name(), b.name());
     }
 }
 
@@ -119,7 +114,13 @@ static mut runq : *mut RunQueue = 0 as *mut RunQueue;
 
 pub fn init_stage1() {}
 pub fn init_stage2() {
-    let x = box RunQueue(VecDeque::new());
+    let name = cmdline::get_option("sched").unwrap_or(sched::DEFAULT_POLICY);
+    let policy = sched::create_policy(name).unwrap_or_else(|| {
+        dbg!(debug::SCHED, "Unknown scheduling policy {:?}, using {}", name, sched::DEFAULT_POLICY);
+        sched::create_policy(sched::DEFAULT_POLICY).expect("default scheduling policy must exist")
+    });
+    dbg!(debug::CORE, "using the {} scheduling policy", policy.name());
+    let x = box RunQueue(policy);
     unsafe {
         runq = transmute(x);
     }
@@ -156,6 +157,30 @@ fn push_runable_ctx(ctx : &mut Context) {
     }
 }
 
+/// Switch the run queue over to a new scheduling policy.
+pub fn replace_policy(p: Box<SchedPolicy + 'static>) {
+    block_interrupts!({
+        unsafe { runq.as_mut().expect("Attempted to change policy before initialization finished").replace(p) }
+    })
+}
+
+/// Tell the scheduling policy the priority of `ctx` has changed. It is fine if `ctx` is not on the
+/// run queue.
+pub fn requeue(ctx: &mut Context) {
+    block_interrupts!({
+        let &mut RunQueue(ref mut b) = unsafe { runq.as_mut().expect("Attempted to requeue before initialization finished") };
+        b.requeue(ctx as *mut Context);
+    })
+}
+
+/// The name of the scheduling policy in use.
+pub fn policy_name() -> &'static str {
+    unsafe {
+        let &RunQueue(ref b) = runq.as_ref().expect("Attempted to get policy before initialization finished");
+        b.name()
+    }
+}
+
 // Not really static but I need to make sure it isn't collected.
 fn pop_runable_ctx() -> &'static mut Context {
     unsafe {
@@ -245,13 +270,29 @@ impl Context {
             kstack_size : stack_size,
             pd          : transmute(pd),
             tsd         : box temp_tsd,
+            sched       : SchedInfo::new(),
+            tls         : None,
+            fs          : gdt::ZERO,
+            intr_depth  : 0,
         }
     }
 
+    /// Give this thread the TLS segment `tls`, taking effect right away if it is the one running.
+    pub fn set_tls(&mut self, tls: Option<(usize, u32)>) {
+        block_interrupts!({
+            self.tls = tls;
+            if gdt::get_tsd() as *const tsd::TSDInfo == &*self.tsd as *const tsd::TSDInfo {
+                gdt::set_user_tls(tls, gdt::get_fs());
+            }
+        })
+    }
+
     unsafe fn make_active(&self) -> ! {
         gdt::set_kernel_stack((self.kstack + self.kstack_size) as *mut c_void);
         self.pd.as_mut().expect("pagedir is missing").set_active();
         gdt::set_tsd(transmute_copy(&self.tsd));
+        gdt::set_user_tls(self.tls, self.fs);
+        interrupt::swap_depth(self.intr_depth);
         asm!("
             movl $0, %ebp
             movl $1, %esp
@@ -275,6 +316,11 @@ impl Context {
         }
 
         gdt::set_tsd(transmute_copy(&newc.tsd));
+        self.fs = gdt::get_fs();
+        gdt::set_user_tls(newc.tls, newc.fs);
+        self.intr_depth = interrupt::swap_depth(newc.intr_depth);
+        // We get a whole timeslice again the next time we are run.
+        self.sched.slice_used = 0;
 
         // NOTE LLVM Really doesn't seem to like the inline ASM for some reason. If it even works
         // it gets incorrect asm. This is a function compiled by GDB.
@@ -0,0 +1,150 @@
+// TODO Copyright Header
+
+//! Fast userspace locking.
+//!
+//! User code does its locking with atomic operations on a word of its own memory and only asks the
+//! kernel for help when it has to sleep or wake someone up. Sleepers wait on a queue named by the
+//! memory object the word is mapped from and where in it the word is, so processes sharing the
+//! memory share the queue no matter where each of them has it mapped, and the name stays the same
+//! while the page is swapped out. Memory that is not in any mapping is named by physical address.
+
+use std::collections::BTreeMap;
+use std::intrinsics::volatile_load;
+use mm::pagetable;
+use base::errno::{self, KResult};
+use kqueue::WQueue;
+use sync::{Wait, WakeupOne};
+use time::{self, Timespec};
+
+/// Sleep if the word still holds the value given.
+pub const FUTEX_WAIT : i32 = 0;
+/// Wake up to the given number of threads sleeping on the word.
+pub const FUTEX_WAKE : i32 = 1;
+
+struct FutexQueue {
+    queue   : WQueue,
+    /// The number of threads sleeping on `queue`. We get rid of it when this is back to 0.
+    waiters : usize,
+}
+
+/// What a futex queue is named by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Key {
+    /// The word `off` bytes into the memory object with the given id.
+    Object(u64, usize),
+    /// The word at this physical address.
+    Physical(usize),
+}
+
+/// The queues that have someone waiting on them.
+static mut FUTEXES : *mut BTreeMap<Key, Box<FutexQueue>> = 0 as *mut BTreeMap<Key, Box<FutexQueue>>;
+
+/// Names words in mapped user memory. See `set_key_hook`.
+static mut KEY_HOOK : Option<fn(usize) -> Option<KResult<Key>>> = None;
+
+pub fn init_stage1() {}
+pub fn init_stage2() {
+    unsafe { FUTEXES = box BTreeMap::new() as *mut BTreeMap<Key, Box<FutexQueue>>; }
+}
+
+fn futexes() -> &'static mut BTreeMap<Key, Box<FutexQueue>> {
+    unsafe { FUTEXES.as_mut().expect("futex table not yet initialized") }
+}
+
+/// Have `f` name the word at a user address by the object it is mapped from. It returns None if the
+/// address is not in any mapping and EFAULT if it may not be read. The maps are kept far above us so
+/// this is how we get at them.
+pub fn set_key_hook(f: fn(usize) -> Option<KResult<Key>>) {
+    unsafe { KEY_HOOK = Some(f); }
+}
+
+/// The name of the word at `addr` in the current process.
+fn key_of(addr: usize) -> KResult<Key> {
+    if addr % 4 != 0 {
+        return Err(errno::EINVAL);
+    }
+    if let Some(k) = unsafe { KEY_HOOK }.and_then(|f| f(addr)) {
+        return k;
+    }
+    match (current_proc!()).get_pagedir().lookup(addr) {
+        Some((paddr, flags)) if flags & pagetable::USER != 0 => Ok(Key::Physical(paddr)),
+        _ => Err(errno::EFAULT),
+    }
+}
+
+/// The queue for `key`, making it if nobody is waiting there yet.
+fn get_queue(key: Key) -> KResult<*mut FutexQueue> {
+    if let Some(q) = futexes().get_mut(&key) {
+        return Ok(&mut **q as *mut FutexQueue);
+    }
+    let mut q = try!(alloc!(try box FutexQueue { queue: WQueue::new(), waiters: 0 }).or(Err(errno::ENOMEM)));
+    let ptr = &mut *q as *mut FutexQueue;
+    try!(alloc!(try futexes().insert(key, q)).or(Err(errno::ENOMEM)));
+    Ok(ptr)
+}
+
+fn wait(addr: usize, val: u32, timeout: Option<&Timespec>) -> KResult<()> {
+    let key = try!(key_of(addr));
+    let deadline = match timeout {
+        Some(t) if !t.is_valid() => { return Err(errno::EINVAL); },
+        Some(t) => Some(time::ticks() + t.to_ticks()),
+        None => None,
+    };
+    // Fault the page in now, since it cannot be brought in once interrupts are off. Nothing else
+    // runs before we check it again so it will still be there.
+    unsafe { volatile_load(addr as *const u32); }
+    let res = block_interrupts!({
+        // With interrupts off nobody can change the word or wake the queue between this check and
+        // us going to sleep.
+        if unsafe { volatile_load(addr as *const u32) } != val {
+            Err(errno::EAGAIN)
+        } else {
+            match get_queue(key) {
+                Err(e) => Err(e),
+                Ok(q) => {
+                    let q = unsafe { &mut *q };
+                    q.waiters += 1;
+                    let r = match deadline {
+                        Some(d) => q.queue.wait_until(d),
+                        None => q.queue.wait().or(Err(errno::EINTR)),
+                    };
+                    q.waiters -= 1;
+                    if q.waiters == 0 {
+                        futexes().remove(&key);
+                    }
+                    r
+                }
+            }
+        }
+    });
+    match res {
+        // Being cancelled looks just like a signal to the caller.
+        Err(errno::ECANCELED) => Err(errno::EINTR),
+        r => r,
+    }
+}
+
+fn wake(addr: usize, n: u32) -> KResult<usize> {
+    let key = try!(key_of(addr));
+    Ok(block_interrupts!({
+        let mut woken = 0;
+        if let Some(q) = futexes().get(&key) {
+            while woken < n as usize && q.queue.signal_one() {
+                woken += 1;
+            }
+        }
+        woken
+    }))
+}
+
+/// Perform the futex syscall. FUTEX_WAIT sleeps until woken if the word at `addr` is still `val`,
+/// or until `timeout` has passed if one is given. It returns EAGAIN if the word had already changed,
+/// ETIMEDOUT if we ran out of time and EINTR if a signal or cancellation woke us. FUTEX_WAKE wakes up
+/// to `val` threads waiting on `addr` and returns how many it woke.
+pub fn futex(addr: usize, op: i32, val: u32, timeout: Option<&Timespec>) -> KResult<usize> {
+    match op {
+        FUTEX_WAIT => wait(addr, val, timeout).map(|_| 0),
+        FUTEX_WAKE => wake(addr, val),
+        _ => Err(errno::ENOSYS),
+    }
+}
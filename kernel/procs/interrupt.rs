@@ -5,6 +5,8 @@
 use std::intrinsics;
 use startup::gdt;
 use super::apic;
+use signal;
+use preempt;
 
 /// A struct containing the register state when a interrupt function is called. Note that modifying
 /// this structure in an ISR will change the state of the registers once the function returns to
@@ -30,6 +32,7 @@ pub const HIGH : u8 = 0xFF;
 
 pub const DIVIDE_BY_ZERO : u8 = 0x00;
 pub const INVALID_OPCODE : u8 = 0x06;
+pub const DOUBLE_FAULT   : u8 = 0x08;
 pub const GPF            : u8 = 0x0d;
 pub const PAGE_FAULT     : u8 = 0x0e;
 pub const SYSCALL        : u8 = 0x2e;
@@ -126,6 +129,7 @@ struct InterruptInfo {
 }
 
 const TRAP    : u8 = 0x01;
+const TASK    : u8 = 0x05;
 #[allow(dead_code)]
 const BIT16   : u8 = 0x06;
 const BIT32   : u8 = 0x0E;
@@ -187,6 +191,28 @@ unsafe fn set_entry(isr: u8, addr: u32, seg: u16, flags: u8) {
     };
 }
 
+/// Make the given interrupt switch to the task `tss` instead of calling a handler. The task gets a
+/// fresh stack, which is what we need when the current one might be broken.
+pub unsafe fn set_task_gate(isr: u8, tss: u16) {
+    set_entry(isr, 0, tss, PRESENT | TASK | RING0);
+}
+
+/// How many interrupt handlers the current thread is inside of. Handlers for page faults and
+/// system calls can sleep, so this is saved and restored on every context switch.
+static mut DEPTH : usize = 0;
+
+/// Returns true if the current thread is running an interrupt handler. Anything that might exit
+/// the current thread must not be done when this is true.
+pub fn in_interrupt() -> bool { unsafe { DEPTH != 0 } }
+
+/// Set how many interrupt handlers the current thread is inside of, returning the old value. This
+/// is only for switching threads.
+pub unsafe fn swap_depth(depth: usize) -> usize {
+    let old = DEPTH;
+    DEPTH = depth;
+    old
+}
+
 /// This is the function that is actually initially entered by the interrupt handler. It should
 /// never be called directly. It is public only so that the compiler will not remove this for being
 /// dead code.
@@ -197,10 +223,18 @@ unsafe fn set_entry(isr: u8, addr: u32, seg: u16, flags: u8) {
 pub unsafe extern "C" fn _rust_intr_handler(r: &mut Registers) {
     // TODO I might need to setup the %es stuff as early as here.
     let h = IDT.handlers[r.intr as usize];
+    DEPTH += 1;
     h(r);
+    DEPTH -= 1;
     if IDT.mappings[r.intr as usize].is_some() {
         apic::set_eoi();
     }
+    if (r.cs & 3) == 3 {
+        // We are about to go back to user mode. This is the only place it is safe to switch away
+        // from a thread that did not ask to, and we need to take care of any signals first.
+        preempt::preempt_if_needed();
+        signal::handle_pending(r);
+    }
 }
 
 /**
@@ -1,6 +1,11 @@
 // TODO Copyright Header
 
 //! KMutex thing
+//!
+//! Mutexes lend priority to whoever holds them. A thread that has to wait for a mutex gives the
+//! holder its nice value if that is better, and if the holder is itself waiting on a mutex the
+//! holder of that gets it too, and so on. Every thread keeps a list of the mutexes it holds so that
+//! when it unlocks one it can go back to whatever priority the rest still give it.
 
 use kqueue::KQueue;
 use std::intrinsics::{size_of, transmute};
@@ -11,6 +16,9 @@ use std::ptr::*;
 use std::sync::atomic::*;
 use sync::Wakeup;
 use kthread::KThread;
+use lockdep::{self, Site};
+use context;
+use std::cmp;
 
 pub fn init_stage1() {
     request_slab_allocator("KMutex allocator", unsafe { size_of::<KMutex>() as u32 });
@@ -18,33 +26,108 @@ pub fn init_stage1() {
 
 pub fn init_stage2() {}
 
-/// A basic re-entrant mutex
+/// A basic re-entrant mutex. Fair mutexes hand the lock straight to the thread that has waited
+/// longest when they are unlocked, so nobody can starve. Unfair ones let everyone waiting race for it
+/// again, which is faster when the lock is held briefly.
 pub struct KMutex {
     name : &'static str,
+    fair : bool,
     held : AtomicUsize,
     holder: AtomicPtr<KThread>,
     queue : UnsafeCell<KQueue>,
+    /// The next mutex held by our holder.
+    next_held : Cell<*const KMutex>,
     //no_copy : core::kinds::marker::NoCopy,
 }
 
 impl KMutex {
     /// Create a new mutex with the given name.
     pub fn new(name: &'static str) -> KMutex {
-        KMutex { name : name, held : AtomicUsize::new(0), holder: AtomicPtr::new(null_mut()), queue : UnsafeCell::new(KQueue::new()) }
+        KMutex { name : name, fair : false, held : AtomicUsize::new(0), holder: AtomicPtr::new(null_mut()),
+                 queue : UnsafeCell::new(KQueue::new()), next_held : Cell::new(null()) }
     }
 
-    /// Obtain the lock, waiting until it is freed. Note that there are no ordering/fairness
-    /// gaurentees on who gets a lock when it is contested.
+    /// Create a new mutex that hands the lock to waiters in the order they started waiting.
+    pub fn new_fair(name: &'static str) -> KMutex {
+        KMutex { fair : true, .. KMutex::new(name) }
+    }
+
+    /// Returns true if we now hold the lock, either by taking it or having it handed to us.
+    fn try_take(&self, thr: *mut KThread) -> bool {
+        self.holder.load(Ordering::SeqCst) == thr || self.holder.compare_and_swap(null_mut(), thr, Ordering::SeqCst) == null_mut()
+    }
+
+    #[inline]
+    fn queue<'a>(&'a self) -> &'a mut KQueue { unsafe { self.queue.get().as_mut().expect("Kmutex queue cannot be null") } }
+
+    /// Wait for the holder to let go of the lock, lending it our priority while we do. Returns
+    /// false if the wait was cut short.
+    fn wait_for(&self, thr: *mut KThread, cancelable: bool) -> bool {
+        let t = unsafe { &mut *thr };
+        let res = block_interrupts!({
+            t.blocked_on = self as *const KMutex;
+            lend_priority(self, t.ctx.sched.priority());
+            self.queue().wait_on(cancelable)
+        });
+        t.blocked_on = null();
+        res
+    }
+
+    /// The best priority of any thread waiting for this.
+    fn waiter_priority(&self) -> Option<i32> {
+        self.queue().fold(None, |best, &t| {
+            let p = unsafe { (*t).ctx.sched.priority() };
+            Some(best.map(|b| cmp::min(b, p)).unwrap_or(p))
+        })
+    }
+
+    /// We have just become the holder, put this on our list of held mutexes and take on the
+    /// priority of anyone still waiting for it.
+    fn acquired(&self, thr: *mut KThread, site: Site) {
+        block_interrupts!({
+            let t = unsafe { &mut *thr };
+            self.next_held.set(t.held_mutexes);
+            t.held_mutexes = self as *const KMutex;
+            lockdep::acquired(t, self as *const KMutex, self.name, site);
+        });
+        update_inherited(thr);
+    }
+
+    /// Take this off `thr`'s list of held mutexes.
+    fn released(&self, thr: *mut KThread) {
+        block_interrupts!({
+            let t = unsafe { &mut *thr };
+            let me = self as *const KMutex;
+            let mut prev : *const KMutex = null();
+            let mut cur = t.held_mutexes;
+            while cur != me {
+                assert!(!cur.is_null(), "{:?} is not on the held list of its holder", self);
+                prev = cur;
+                cur = unsafe { (*cur).next_held.get() };
+            }
+            match unsafe { prev.as_ref() } {
+                Some(p) => { p.next_held.set(self.next_held.get()); },
+                None => { t.held_mutexes = self.next_held.get(); },
+            }
+            self.next_held.set(null());
+            lockdep::released(t, me);
+        });
+    }
+
+    /// Obtain the lock, waiting until it is freed. Unless this is a fair mutex there are no
+    /// ordering/fairness gaurentees on who gets a lock when it is contested.
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn lock_nocancel(&self) {
+        let site = lockdep::here();
         dbg!(debug::SCHED, "locking {:?} for {:?} of {:?}", self, current_thread!(), current_proc!());
-        let null = null_mut();
         let thr = current_thread!() as *mut KThread;
         if self.holder.load(Ordering::SeqCst) != thr {
-            while self.holder.compare_and_swap(null, thr, Ordering::SeqCst) != null {
-                unsafe { self.queue.get().as_mut().expect("Kmutex queue cannot be null").wait_on(false) };
+            while !self.try_take(thr) {
+                self.wait_for(thr, false);
             }
             assert!(self.holder.load(Ordering::SeqCst) == thr, "We should have gotten mutex but didn't");
             assert!(self.held.load(Ordering::SeqCst) == 0, "Multiple threads with same thread pointer!");
+            self.acquired(thr, site);
         } else {
             assert!(self.held.load(Ordering::SeqCst) != 0, "Multiple threads with same thread pointer!");
         }
@@ -54,18 +137,27 @@ impl KMutex {
     }
 
     /// Returns true if we got the lock, False if we didn't because of being canceled.
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn lock(&self) -> bool {
+        let site = lockdep::here();
         dbg!(debug::SCHED, "cancelable locking {:?} for {:?} of {:?}", self, current_thread!(), current_proc!());
-        let null = null_mut();
         let thr = current_thread!() as *mut KThread;
         if self.holder.load(Ordering::SeqCst) != thr {
-            while self.holder.compare_and_swap(null, thr, Ordering::SeqCst) != null {
-                if unsafe { !self.queue.get().as_mut().expect("Kmutex queue cannot be null").wait_on(true) } {
-                    return false;
+            while !self.try_take(thr) {
+                if !self.wait_for(thr, true) {
+                    // If the lock was handed to us before we noticed we were cancelled we have to
+                    // take it, nobody else will be woken up for it.
+                    let holder = self.holder.load(Ordering::SeqCst);
+                    if holder != thr {
+                        // We are not waiting anymore so the holder might not need our priority.
+                        update_inherited(holder);
+                        return false;
+                    }
                 }
             }
             assert!(self.holder.load(Ordering::SeqCst) == thr, "We should have gotten mutex but didn't");
             assert!(self.held.load(Ordering::SeqCst) == 0, "Multiple threads with same thread pointer!");
+            self.acquired(thr, site);
         } else {
             assert!(self.held.load(Ordering::SeqCst) != 0, "Multiple threads with same thread pointer!");
         }
@@ -75,8 +167,12 @@ impl KMutex {
     }
 
     /// Returns true if we get the lock. False, without sleeping, if we did not.
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn try_lock(&self) -> bool {
-        if self.holder.compare_and_swap(null_mut(), current_thread!() as *mut KThread, Ordering::SeqCst) == null_mut() {
+        let site = lockdep::here();
+        let thr = current_thread!() as *mut KThread;
+        if self.holder.compare_and_swap(null_mut(), thr, Ordering::SeqCst) == null_mut() {
+            self.acquired(thr, site);
             self.held.fetch_add(1, Ordering::SeqCst);
             dbg!(debug::SCHED, "locking {:?} for {:?} of {:?}", self, current_thread!(), current_proc!());
             true
@@ -87,16 +183,36 @@ impl KMutex {
     }
 
     /// Unlocks the lock. This should only be called by the thread that originally locked it.
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn unlock(&self) {
+        let site = lockdep::here();
         dbg!(debug::SCHED, "unlocking {:?} for {:?} of {:?}", self, current_thread!(), current_proc!());
         let thr = current_thread!() as *mut KThread;
+        let holder = self.holder.load(Ordering::SeqCst);
+        if holder != thr {
+            lockdep::bad_unlock(self as *const KMutex, self.name, holder, site);
+        }
         match self.held.fetch_sub(1, Ordering::SeqCst) {
             0 => { panic!("Unlocked a mutex thats not locked!"); },
             1 => {
-                if self.holder.compare_and_swap(thr, null_mut(), Ordering::SeqCst) != thr {
-                    panic!("Unlocked a mutex locked by another thread!");
+                self.released(thr);
+                let queue = self.queue();
+                if self.fair {
+                    block_interrupts!({
+                        // Pass the lock on to the longest waiter without ever letting it go.
+                        let next = queue.wake_first().unwrap_or(null_mut());
+                        if self.holder.compare_and_swap(thr, next, Ordering::SeqCst) != thr {
+                            panic!("Unlocked a mutex locked by another thread!");
+                        }
+                    });
+                } else {
+                    if self.holder.compare_and_swap(thr, null_mut(), Ordering::SeqCst) != thr {
+                        panic!("Unlocked a mutex locked by another thread!");
+                    }
+                    queue.signal();
                 }
-                unsafe { self.queue.get().as_mut().expect("Kmutex queue cannot be null")}.signal();
+                // Give back whatever priority the waiters on this lent us.
+                update_inherited(thr);
             },
             _ => { assert!(self.holder.load(Ordering::SeqCst) == thr, "Unlocked a mutex held by another thread."); }
         }
@@ -104,12 +220,14 @@ impl KMutex {
 
     /// Used for sleeping on a mutex, Unlocks the mutex as many times as it has been locked and
     /// returns the number of times it has been locked.
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn unlock_all(&self) -> usize {
         let held = self.held.swap(1, Ordering::SeqCst);
         self.unlock();
         return held;
     }
 
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn relock_all(&self, t: usize) -> bool {
         self.lock_nocancel();
         if self.held.compare_and_swap(1, t, Ordering::SeqCst) != 1 {
@@ -124,6 +242,70 @@ impl Debug for KMutex {
         write!(f, "KMutex '{}' {{ holder: {:?}, held: {:?}, waiters: {} }}", self.name,
                 unsafe { transmute::<*const KThread, &KThread>(self.holder.load(Ordering::SeqCst)) },
                 self.held.load(Ordering::SeqCst),
-                unsafe { self.queue.get().as_mut().expect("Kmutex queue cannot be null")}.len())
+                self.queue().len())
+    }
+}
+
+/// How long a chain of threads waiting on each others mutexes we follow when passing on priority.
+/// Only a deadlock should make one this long.
+const MAX_INHERIT_DEPTH : usize = 16;
+
+/// Set the priority `t` has inherited, moving it on the run queue if it is waiting there.
+fn set_inherited(t: &mut KThread, inherited: Option<i32>) {
+    dbg!(debug::SCHED, "{:?} now inherits priority {:?} (was {:?})", t, inherited, t.ctx.sched.inherited);
+    t.ctx.sched.inherited = inherited;
+    if !t.is_current_thread() {
+        context::requeue(&mut t.ctx);
     }
 }
+
+/// A thread with priority `prio` is about to wait for `mtx`. Make sure its holder, and whoever is
+/// holding up the holder, runs with at least that priority.
+fn lend_priority(mtx: &KMutex, prio: i32) {
+    block_interrupts!({
+        let mut mtx = mtx as *const KMutex;
+        for _ in 0..MAX_INHERIT_DEPTH {
+            let holder = match unsafe { mtx.as_ref().and_then(|m| m.holder.load(Ordering::SeqCst).as_mut()) } {
+                Some(h) => h,
+                None => break,
+            };
+            if holder.ctx.sched.priority() <= prio {
+                break;
+            }
+            set_inherited(holder, Some(prio));
+            mtx = holder.blocked_on;
+        }
+    })
+}
+
+/// Work out again what priority `thr` should inherit from the waiters on the mutexes it holds. If
+/// that changed and `thr` is itself waiting on a mutex the change is passed on to its holder.
+fn update_inherited(thr: *mut KThread) {
+    block_interrupts!({
+        let mut thr = thr;
+        for _ in 0..MAX_INHERIT_DEPTH {
+            let t = match unsafe { thr.as_mut() } {
+                Some(t) => t,
+                None => break,
+            };
+            let mut best = None;
+            let mut m = t.held_mutexes;
+            while let Some(mtx) = unsafe { m.as_ref() } {
+                if let Some(p) = mtx.waiter_priority() {
+                    best = Some(best.map(|b| cmp::min(b, p)).unwrap_or(p));
+                }
+                m = mtx.next_held.get();
+            }
+            // Only keep it if it is actually better than what we have on our own.
+            let inherited = match best { Some(p) if p < t.ctx.sched.nice => Some(p), _ => None };
+            if inherited == t.ctx.sched.inherited {
+                break;
+            }
+            set_inherited(t, inherited);
+            thr = match unsafe { t.blocked_on.as_ref() } {
+                Some(mtx) => mtx.holder.load(Ordering::SeqCst),
+                None => null_mut(),
+            };
+        }
+    })
+}
@@ -1,6 +1,7 @@
 // TODO Copyright Header
 
 use std::{hash, fmt};
+use std::any::Any;
 use std::rc::{self, Rc, Weak};
 use base::errno;
 use std::collections::HashMap;
@@ -8,8 +9,10 @@ use context::ContextFunc;
 use std::mem::{transmute, transmute_copy};
 use std::ptr::null_mut;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use libc::c_void;
 use kthread;
+use interrupt;
 use kthread::{KThread, CUR_THREAD_SLOT};
 use pcell::*;
 use sync::Wakeup;
@@ -19,6 +22,10 @@ use mm::pagetable::PageDir;
 use mm::AllocError;
 use util::uid::*;
 use mm::Allocation;
+use time::{self, Timer};
+use session;
+use resource::{self, RLimit, Usage};
+use signal::{self, Signal, SigSet, SigAction, DefaultAction, SIGCHLD, SIGCONT, SIGKILL};
 
 pub use self::WaitProcId::*;
 pub use base::pid::*;
@@ -36,35 +43,92 @@ static IDLE_PID : ProcId = ProcId(0);
 static mut PID_GEN : *mut UIDSource<ProcId> = 0 as *mut UIDSource<ProcId>;
 /// Get a PID from our generator.
 fn get_pid() -> Option<ProcId> {
-    unsafe { PID_GEN.as_mut().expect("PID_GEN not initialized") }.get()
+    match unsafe { PID_GEN.as_mut() } {
+        Some(g) => g.get(),
+        None => { dbg!(debug::PROC, "there is no PID source, it could not be made"); None },
+    }
 }
 /// Notify that we are done with a pid.
 fn drop_pid(i: &ProcId) { unsafe { &mut *PID_GEN }.destroy(i); }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum ProcState { RUNNING, DEAD }
-pub type ProcStatus = isize;
+
+/// The status of a process as reported by waitpid. This is encoded the same way as in userland so
+/// that the usual `WIFEXITED`, `WEXITSTATUS`, etc. work on `raw()`. Unlike `WEXITSTATUS` the exit
+/// status is not truncated to 8 bits when read back through `exit_status`.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct ProcStatus(isize);
+
+impl ProcStatus {
+    /// The process exited with the given status.
+    pub fn exited(status: isize) -> ProcStatus { ProcStatus(status << 8) }
+    /// The process was terminated by the given signal.
+    pub fn signaled(sig: Signal) -> ProcStatus { ProcStatus((sig & 0x7f) as isize) }
+    /// The process was stopped by the given signal.
+    pub fn stopped(sig: Signal) -> ProcStatus { ProcStatus((((sig & 0xff) as isize) << 8) | 0x7f) }
+    /// The process was continued by SIGCONT.
+    pub fn continued() -> ProcStatus { ProcStatus(0xffff) }
+
+    pub fn raw(&self) -> isize { let &ProcStatus(s) = self; s }
+
+    pub fn if_exited(&self) -> bool { self.raw() & 0x7f == 0 }
+    pub fn exit_status(&self) -> isize { self.raw() >> 8 }
+    pub fn if_signaled(&self) -> bool { let s = self.raw() & 0x7f; s != 0 && s != 0x7f }
+    pub fn term_sig(&self) -> Signal { (self.raw() & 0x7f) as Signal }
+    pub fn if_stopped(&self) -> bool { self.raw() & 0xff == 0x7f }
+    pub fn stop_sig(&self) -> Signal { ((self.raw() >> 8) & 0xff) as Signal }
+    pub fn if_continued(&self) -> bool { self.raw() == 0xffff }
+}
+
+impl fmt::Debug for ProcStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.if_continued() {
+            write!(f, "Continued")
+        } else if self.if_stopped() {
+            write!(f, "Stopped({})", self.stop_sig())
+        } else if self.if_signaled() {
+            write!(f, "Signaled({})", self.term_sig())
+        } else {
+            write!(f, "Exited({} 0x{:x})", self.exit_status(), self.exit_status())
+        }
+    }
+}
 
 pub struct KProc {
     pid      : ProcId,                      /* Our pid */
+    pgid     : ProcId,                      /* Our process group */
+    sid      : ProcId,                      /* Our session */
     command  : String,                      /* Process Name */
     threads  : HashMap<u64, Box<KThread>>, /* Our threads */
     children : HashMap<ProcId, Rc<ProcRefCell<KProc>>>, /* Our children */
     status   : ProcStatus,                  /* Our exit status */
+    kill_status : Option<ProcStatus>,       /* The status we were killed with, if we were */
+    wait_report : Option<ProcStatus>,       /* A stop or continue our parent has not seen yet */
     state    : ProcState,                   /* running/sleeping/etc. */
     parent   : Option<Weak<ProcRefCell<KProc>>>,/* Our parent */
     pagedir  : PageDir,
 
     wait : WQueue,
 
+    sigactions : [SigAction; signal::NSIG], /* What to do for each signal */
+    sigpending : SigSet,                    /* Signals no thread is currently able to take */
+    deferred   : AtomicUsize,               /* Signals sent while we were busy, see signal_or_defer */
+    stopped    : bool,                      /* True if we have been stopped by a signal */
+    stopq      : WQueue,                    /* Where our threads sleep while we are stopped */
+    alarm      : Box<Timer>,                /* Sends us SIGALRM, see time::alarm */
+
+    rlimits     : [RLimit; resource::RLIM_NLIMITS], /* Our resource limits */
+    usage       : Usage,                    /* What we have used */
+    child_usage : Usage,                    /* What our reaped children have used */
+
     // TODO For VFS
     // files : [Option<KFile>, ..NFILES],
     // cwd   : RC<VNode>,
 
-    // TODO For VM
-    // brk : usize,
-    // start_brk : usize,
-    // vmmap : Vec<VMArea>,
+    brk       : usize,                      /* The end of our heap */
+    start_brk : usize,                      /* Where our heap starts, just past the data segment */
+    vmmap : Option<Box<Any>>,               /* Our umem::vmmap::VMMap, if we have user memory */
 }
 
 pub fn init_stage1() {
@@ -78,8 +142,12 @@ pub fn init_stage2() {
     unsafe {
         let y : Box<HashMap<ProcId, Rc<ProcRefCell<KProc>>>> = box HashMap::new();
         PROC_LIST = transmute(y);
-        let z : Box<UIDSource<ProcId>> = box UIDSource::new(ProcId(0)).unwrap_or_else(|_| { panic!("unable to create pid source"); });
-        PID_GEN = transmute(z);
+        DEFER_TIMER = transmute(box Timer::new(post_deferred, 0));
+        // Without this no process can be made, which start_idle_proc reports.
+        match UIDSource::new(ProcId(0)).and_then(|z| alloc!(try_box z)) {
+            Ok(z) => { PID_GEN = transmute(z); },
+            Err(_) => { dbg!(debug::PROC|debug::MM, "unable to make the PID source"); },
+        }
     }
 }
 
@@ -92,14 +160,50 @@ macro_rules! proc_list{
 
 static mut IDLE_STARTED : bool = false;
 
-/// Function that is called once to start the idle process from a non-thread context.
-pub fn start_idle_proc(init_main : ContextFunc, arg1: i32, arg2: *mut c_void) -> ! {
+/// Posts the signals that `KProc::signal_or_defer` had to put aside. It is only set while some are
+/// waiting.
+static mut DEFER_TIMER : *mut Timer = 0 as *mut Timer;
+
+/// The callback for `DEFER_TIMER`. Every process with deferred signals that is not busy any more
+/// gets them now, if any are still busy we try again next tick.
+fn post_deferred(_: usize) -> bool {
+    let mut busy = false;
+    KProc::each_proc(|_, p| {
+        if unsafe { (*p.as_unsafe_cell().get()).deferred.load(Ordering::SeqCst) } == 0 {
+            return;
+        }
+        match p.try_borrow_mut() {
+            Some(mut p) => {
+                let mut sigs = SigSet::from_bits(p.deferred.swap(0, Ordering::SeqCst) as u32);
+                while let Some(sig) = sigs.first() {
+                    sigs.remove(sig);
+                    p.post_signal(sig);
+                }
+            },
+            None => { busy = true; },
+        }
+    });
+    if busy {
+        unsafe { (*DEFER_TIMER).set(time::ticks() + 1); }
+    }
+    true
+}
+
+/// Function that is called once to start the idle process from a non-thread context. This only
+/// returns if there was not enough memory to make the idle process, there is nobody to kill yet.
+pub fn start_idle_proc(init_main : ContextFunc, arg1: i32, arg2: *mut c_void) -> AllocError {
     use context;
 
     assert!(unsafe { IDLE_STARTED } == false, "IDLE THREAD ALREADY STARTED");
     unsafe { IDLE_STARTED = true; }
 
-    let pid = KProc::new("IDLE PROCESS".to_string(), init_main, arg1, arg2).ok().expect("Unable to allocate idle proc!");
+    let pid = match KProc::new("IDLE PROCESS".to_string(), init_main, arg1, arg2) {
+        Ok(pid) => pid,
+        Err(e) => {
+            dbg!(debug::CORE|debug::MM, "Unable to allocate idle proc!");
+            return e;
+        },
+    };
 
     dbg!(debug::CORE, "made idel proc");
     assert!(pid == IDLE_PID);
@@ -112,14 +216,32 @@ pub fn start_idle_proc(init_main : ContextFunc, arg1: i32, arg2: *mut c_void) ->
 pub enum WaitProcId { Any, Pid(ProcId) }
 pub type WaitOps = u32;
 
+/// Return immediately if no child has anything to report.
+pub const WNOHANG    : WaitOps = 0x1;
+/// Also report children which have stopped.
+pub const WUNTRACED  : WaitOps = 0x2;
+/// Also report stopped children which have been continued.
+pub const WCONTINUED : WaitOps = 0x8;
+
 impl KProc {
     pub fn get_pagedir<'a>(&'a self) -> &'a PageDir {
         &self.pagedir
     }
+    pub fn get_pagedir_mut<'a>(&'a mut self) -> &'a mut PageDir {
+        &mut self.pagedir
+    }
     /// Perform the waitpid syscall. This simply passes the call along to the current process. It
-    /// returns Ok((killed_PID,status)) on success and Err(errno) on failure.
+    /// returns Ok((killed_PID,status)) on success and Err(errno) on failure. This always blocks
+    /// until some child has something to report, use `try_waitpid` for WNOHANG.
     pub fn waitpid(pid: WaitProcId, options : WaitOps) -> Result<(ProcId, ProcStatus),errno::Errno> {
-        (current_proc_mut!()).do_waitpid(pid, options)
+        (current_proc_mut!()).do_waitpid(pid, options & !WNOHANG)
+                             .map(|r| { r.expect("waitpid without WNOHANG returned nothing") })
+    }
+
+    /// Perform the waitpid syscall with WNOHANG. This returns Ok(None) if there are children we
+    /// could wait on but none of them has anything to report yet.
+    pub fn try_waitpid(pid: WaitProcId, options : WaitOps) -> Result<Option<(ProcId, ProcStatus)>,errno::Errno> {
+        (current_proc_mut!()).do_waitpid(pid, options | WNOHANG)
     }
 
     /// Checks if this process is the one we are currently running in.
@@ -137,31 +259,85 @@ impl KProc {
     /// If we have no children or the given pid is not one of our children, we should exit with
     /// Err(ECHILD).
     ///
-    /// Options other than 0 are unsupported.
+    /// With WUNTRACED or WCONTINUED we also return children that have stopped or continued since
+    /// the last time we looked, without reaping them. With WNOHANG we return Ok(None) instead of
+    /// waiting.
     ///
-    /// This operation cannot be canceled.
-    fn do_waitpid(&mut self, pid: WaitProcId, options : WaitOps) -> Result<(ProcId, ProcStatus), errno::Errno> {
-        if options != 0 {
-            dbg!(debug::PROC, "waitpid with options 0b{:b} is not supported.", options);
-            return Err(errno::ECHILD);
+    /// If we are cancelled or a signal arrives while waiting we return Err(ECANCELED) or Err(EINTR).
+    fn do_waitpid(&mut self, pid: WaitProcId, options : WaitOps) -> Result<Option<(ProcId, ProcStatus)>, errno::Errno> {
+        if options & !(WNOHANG | WUNTRACED | WCONTINUED) != 0 {
+            dbger!(debug::PROC, errno::EINVAL, "waitpid with options 0b{:b} is not supported.", options);
+            return Err(errno::EINVAL);
         }
 
         // This should only be called while running in our own context.
         assert!(self.is_current_process());
-        // Get the process we will actually wait on.
-        let to_kill = try!(match pid {
-                                Any => self.wait_any_process(options),
-                                Pid(p) => self.wait_specific_process(p, options),
-                            });
-        let final_pid = (*to_kill).borrow().pid.clone();
-        if let Pid(p) = pid { assert!(final_pid == p); }
-        let result = (*to_kill).borrow().status;
+        loop {
+            if let Some((cpid, status, dead)) = try!(self.find_waitable(pid, options)) {
+                if let Pid(p) = pid { assert!(cpid == p); }
+                if dead {
+                    self.reap(cpid);
+                    dbg!(debug::PROC, "{:?} Successfully waited on process {:?} which exited with {:?}", self, cpid, status);
+                } else {
+                    dbg!(debug::PROC, "{:?} Found child {:?} with status {:?}", self, cpid, status);
+                }
+                return Ok(Some((cpid, status)));
+            }
+            if options & WNOHANG != 0 {
+                return Ok(None);
+            }
+            dbg!(debug::PROC, "Begining wait for {:?}", pid);
+            if self.wait.wait().is_err() {
+                dbg!(debug::PROC, "Process {:?} interrupted while waiting for {:?}", self, pid);
+                return Err((current_thread!()).interrupted_errno());
+            }
+        }
+    }
+
+    /// Look for a child matching `pid` that has something to report. Returns its pid, status and
+    /// whether it is dead and should be reaped.
+    fn find_waitable(&self, pid: WaitProcId, options: WaitOps) -> Result<Option<(ProcId, ProcStatus, bool)>, errno::Errno> {
+        let mut found = false;
+        for (cpid, child) in self.children.iter() {
+            if let Pid(p) = pid { if p != *cpid { continue; } }
+            found = true;
+            let c = (*child).borrow();
+            if c.state == ProcState::DEAD {
+                dbg!(debug::PROC, "found already dead process {:?}", *c);
+                return Ok(Some((*cpid, c.status, true)));
+            }
+            match c.wait_report {
+                Some(st) if (st.if_stopped() && options & WUNTRACED != 0) ||
+                            (st.if_continued() && options & WCONTINUED != 0) => {
+                    drop(c);
+                    (*child).borrow_mut().wait_report = None;
+                    return Ok(Some((*cpid, st, false)));
+                },
+                _ => {},
+            }
+        }
+        if !found {
+            dbger!(debug::PROC, errno::ECHILD, "Attempt by {:?} to wait on {:?} failed because there is no such child.", self, pid);
+            return Err(errno::ECHILD);
+        }
+        Ok(None)
+    }
+
+    /// Destroy one of our dead children.
+    fn reap(&mut self, final_pid: ProcId) {
         // Remove it from our child map.
-        self.children.remove(&final_pid);
+        let to_kill = self.children.remove(&final_pid).expect("reaped process must be our child");
 
         // Remove all child threads.
         (*to_kill).borrow_mut().threads.clear();
 
+        // Everything it and its children used now counts against our children.
+        {
+            let c = (*to_kill).borrow();
+            self.child_usage.add(&c.usage);
+            self.child_usage.add(&c.child_usage);
+        }
+
         // Remove it from the global map.
         KProc::remove_proc(&final_pid);
 
@@ -184,61 +360,6 @@ impl KProc {
 
         // Actually destroy the process.
         drop(to_kill);
-
-        dbg!(debug::PROC, "{:?} Successfully waited on process {:?} which exited with {:?} (0x{:X})", self, final_pid, result, result);
-        return Ok((final_pid, result));
-    }
-
-    /// Wait for any child process to die
-    fn wait_any_process(&mut self, options: WaitOps) -> Result<Rc<ProcRefCell<KProc>>, errno::Errno> {
-        assert!(options == 0);
-        loop {
-            if self.children.is_empty() {
-                dbger!(debug::PROC, errno::ECHILD, "Process {:?} attempted to wait on any child when no children were availible.",
-                       self);
-                return Err(errno::ECHILD);
-            }
-            if let Some(ref kproc) =
-                    self.children.values().find(|a: &&Rc<ProcRefCell<KProc>>| -> bool {
-                                                    (**a).borrow().state == ProcState::DEAD
-                                                }) {
-                dbg!(debug::PROC, "found already dead thread {:?}", *(***kproc).borrow());
-                return Ok((*kproc).clone());
-            }
-            if self.wait.wait().is_err() {
-                dbg!(debug::PROC, "Process {:?} interrupted while waiting for any children to exit", self);//describe!(self));
-                return Err(errno::ECANCELED);
-            }
-        }
-    }
-    /// Wait for a specific PID to exit.
-    fn wait_specific_process(&mut self, pid: ProcId, options: WaitOps) -> Result<Rc<ProcRefCell<KProc>>, errno::Errno> {
-        assert!(options == 0);
-        match self.children.get(&pid) {
-            Some(v) => {
-                let pr = v.clone();
-                loop {
-                    let b = (*pr).borrow();
-                    if b.state == ProcState::DEAD {
-                        break;
-                    } else {
-                        // We need to make sure the borrow isn't held during the sleep. Something
-                        // else might want to look at it.
-                        drop(b);
-                        dbg!(debug::PROC, "Begining wait for {:?}", pid);
-                        if self.wait.wait().is_err() {
-                            dbg!(debug::PROC, "Process {:?} interrupted while waiting for child {:?} to exit",self, pid); //describe!(self), pid);
-                            return Err(errno::ECANCELED);
-                        }
-                    }
-                }
-                Ok(pr)
-            },
-            None => {
-                dbger!(debug::PROC, errno::ECHILD, "Attempt by {:?} to wait on pid {:?} failed because it is not a child.", self, pid);
-                Err(errno::ECHILD)
-            },
-        }
     }
 
     /// Returns true if all threads (other then the current one) are EXITED.
@@ -257,16 +378,26 @@ impl KProc {
                 Some(pr) => {
                     let mut canidate = pr.deref().borrow_mut();
                     if !canidate.is_current_process() && canidate.pid != IDLE_PID && canidate.pid != INIT_PID {
-                        canidate.kill(errno::ECANCELED as ProcStatus);
+                        canidate.kill(ProcStatus::exited(errno::ECANCELED as isize));
                     }
                 }
                 _ => (),
             }
         }
-        (current_proc_mut!()).kill(errno::ECANCELED as ProcStatus);
+        (current_proc_mut!()).kill(ProcStatus::exited(errno::ECANCELED as isize));
         kpanic!("Should not return from killing yourself");
     }
 
+    /// Call `f` on every process that exists. Nothing is allocated so this may be used from
+    /// interrupt handlers.
+    pub fn each_proc<F>(mut f: F) where F: FnMut(ProcId, &Rc<ProcRefCell<KProc>>) {
+        for (pid, p) in (proc_list!()).iter() {
+            if let Some(p) = p.clone().upgrade() {
+                f(*pid, &p);
+            }
+        }
+    }
+
     pub fn get_proc(pid: &ProcId) -> Option<Rc<ProcRefCell<KProc>>> {
         let r = proc_list!().get(pid);
         match r {
@@ -291,17 +422,34 @@ impl KProc {
 
     /// The base creation function for a process. This should not generally be used.
     pub fn create(name: String) -> Allocation<KProc> {
+        let pid = try!(get_pid().ok_or_else(|| { dbg!(debug::PROC, "Unable to allocate PID!"); AllocError }));
         Ok(KProc {
-            pid : try!(get_pid().ok_or_else(|| { dbg!(debug::PROC, "Unable to allocate PID!"); AllocError })),
+            pid : pid,
+            pgid : pid,
+            sid : pid,
             command : name,
             // TODO Maybe I should just have this be a box for now.
             threads : try!(alloc!(try HashMap::new())),
             children : try!(alloc!(try HashMap::new())),
-            status : 0,
+            status : ProcStatus::exited(0),
+            kill_status : None,
+            wait_report : None,
             state : ProcState::RUNNING,
             parent : None,
             pagedir : PageDir::new(),
             wait : try!(alloc!(try WQueue::new())),
+            sigactions : [SigAction::new(); signal::NSIG],
+            sigpending : SigSet::empty(),
+            deferred : AtomicUsize::new(0),
+            stopped : false,
+            stopq : try!(alloc!(try WQueue::new())),
+            alarm : try!(alloc!(try box time::alarm_timer(pid))),
+            rlimits : resource::default_limits(),
+            usage : Usage::new(),
+            child_usage : Usage::new(),
+            brk : 0,
+            start_brk : 0,
+            vmmap : None,
         })
     }
 
@@ -310,6 +458,15 @@ impl KProc {
         let is_idle = unsafe { IDLE_PROC == null_mut() };
         let is_init = unsafe { !is_idle && INIT_PROC == null_mut() };
 
+        if !is_idle {
+            let nproc = (current_proc!()).rlimits[resource::RLIMIT_NPROC as usize].cur;
+            if (current_proc!()).live_children() >= nproc {
+                // There is no other way to fail here so it is reported like running out of memory.
+                dbg!(debug::PROC, "{:?} already has {} running children, not creating {}", current_proc!(), nproc, name);
+                return Err(AllocError);
+            }
+        }
+
         let rcp = match alloc!(try Rc::new(ProcRefCell::new(try!(KProc::create(name))))) {
             Ok(e) => e,
             Err(e) => { dbg!(debug::PROC, "Unable to allocate a Process."); return Err(e); }
@@ -319,6 +476,9 @@ impl KProc {
             Ok(t) => t,
             Err(s) => { dbg!(debug::PROC|debug::THR, "Unable to allocate kthread."); return Err(s); }
         };
+        if !is_idle {
+            init_thread.ctx.sched = (current_thread!()).ctx.sched.inherit();
+        }
 
         let hash = hash::hash::<KThread, hash::SipHasher>(&*init_thread);
         let pid = (*rcp).borrow_mut().pid.clone();
@@ -334,6 +494,11 @@ impl KProc {
             let mut p = (*rcp).borrow_mut();
             if !is_idle {
                 p.parent = Some(KProc::get_proc(&current_proc!().pid).clone().expect("Only the idle thread should have no parent").downgrade());
+                if !is_init {
+                    p.pgid = (current_proc!()).pgid;
+                    p.sid = (current_proc!()).sid;
+                }
+                p.rlimits = (current_proc!()).rlimits;
             } else {
                 dbg!(debug::CORE, "IDLE PROCESS BEING CREATED");
                 assert!(pid == ProcId(0));
@@ -366,20 +531,318 @@ impl KProc {
         self.pid
     }
 
-    /// This has nothing to do with signals and kill(1).
+    /// Our parent, if it is still around.
+    pub fn get_parent(&self) -> Option<Rc<ProcRefCell<KProc>>> { self.parent.clone().and_then(|p| p.upgrade()) }
+
+    /// The process group we are in.
+    pub fn get_pgid(&self) -> ProcId { self.pgid }
+
+    /// The session we are in.
+    pub fn get_sid(&self) -> ProcId { self.sid }
+
+    /// Move us to another process group. `session::setpgid` checks that this is allowed.
+    pub fn set_pgid(&mut self, pgid: ProcId) { self.pgid = pgid; }
+
+    /// Make us the leader of a new session and process group. `session::setsid` checks that this
+    /// is allowed.
+    pub fn make_session_leader(&mut self) {
+        self.pgid = self.pid;
+        self.sid = self.pid;
+    }
+
+    /// Returns true if `pid` is one of our children.
+    pub fn has_child(&self, pid: ProcId) -> bool { self.children.contains_key(&pid) }
+
+    /// How many of our children have not exited yet. Ones only waiting to be reaped do not count.
+    pub fn live_children(&self) -> usize {
+        self.children.values().filter(|c| !(*c).borrow().is_dead()).count()
+    }
+
+    /// Call `f` on each of our children.
+    pub fn each_child<F>(&self, mut f: F) where F: FnMut(ProcId, &Rc<ProcRefCell<KProc>>) {
+        for (pid, c) in self.children.iter() {
+            f(*pid, c);
+        }
+    }
+
+    /// Returns true if we have exited and are only waiting to be reaped.
+    pub fn is_dead(&self) -> bool { self.state == ProcState::DEAD }
+
+    /// Returns true if we have been killed, even if we have not finished exiting yet.
+    pub fn is_killed(&self) -> bool { self.kill_status.is_some() }
+
+    /// Returns true if we are the idle or init process, which the system cannot go on without.
+    pub fn is_system(&self) -> bool { self.pid == IDLE_PID || self.pid == INIT_PID }
+
+    /// This is not kill(2), see `signal::kill` for that.
     ///
-    /// This is called to have a process cancel all of its threads.
+    /// This is called to have a process cancel all of its threads. Signals whose action is to
+    /// terminate the process end up here.
     pub fn kill(&mut self, status: ProcStatus) {
-        dbg!(debug::PROC, "proc::kill(status = {:?} {:?}) called on {:?}. Called by {:?}",
-             status, errno::Errno::from(status as usize), self, current_proc!());
+        self.do_kill(status, interrupt::in_interrupt());
+    }
+
+    /// Like `kill` but if we are the current process we keep running until we are on our way back
+    /// out to user space, so that whatever we are in the middle of can clean up after itself.
+    pub fn kill_deferred(&mut self, status: ProcStatus) {
+        self.do_kill(status, true);
+    }
+
+    fn do_kill(&mut self, status: ProcStatus, defer: bool) {
+        dbg!(debug::PROC, "proc::kill(status = {:?}) called on {:?}. Called by {:?}",
+             status, self, current_proc!());
+        self.kill_status = Some(status);
+        let retval = status.exit_status() as *mut c_void;
         for (_, thr) in self.threads.iter_mut() {
-            if !thr.is_current_thread() { thr.exit(status as *mut c_void); }
+            if !thr.is_current_thread() { thr.exit(retval); }
             if cfg!(MTP) {
                 not_yet_implemented!("MTP: proc::kill");
             }
         }
         if self.is_current_process() {
-            (current_thread!()).exit(status as *mut c_void);
+            if defer {
+                // We cannot exit out from under the interrupt handler, or whoever asked us to wait.
+                // We will notice we have been cancelled on the way back out.
+                (current_thread!()).cancel(retval);
+            } else {
+                (current_thread!()).exit(retval);
+            }
+        }
+    }
+
+    /// Send a signal to this process. If some thread is able to take the signal it is handed to
+    /// that thread, otherwise it is left pending on the process until a thread unblocks it.
+    /// Signals using their default action have it carried out immediately.
+    pub fn post_signal(&mut self, sig: Signal) {
+        assert!(signal::is_valid(sig));
+        dbg!(debug::SIGNAL, "Posting signal {} to {:?}", sig, self);
+        if self.state == ProcState::DEAD {
+            return;
+        }
+        if sig == SIGKILL {
+            self.do_default_action(sig);
+            return;
+        }
+        if sig == SIGCONT {
+            self.resume();
+        } else if signal::is_stop(sig) {
+            self.discard_signal(SIGCONT);
+        }
+        if self.is_ignored(sig) {
+            dbg!(debug::SIGNAL, "{:?} is ignoring signal {}", self, sig);
+            return;
+        }
+        let handled = self.sigactions[sig as usize].handler != signal::SIG_DFL;
+        match self.threads.values_mut().find(|t| { t.state != kthread::State::EXITED && !t.sigmask.contains(sig) }) {
+            Some(t) => { if handled { t.add_signal(sig); return; } },
+            None => { self.sigpending.add(sig); return; },
+        }
+        self.do_default_action(sig);
+    }
+
+    /// Send `sig` to `p`, even if someone else has it borrowed. A busy process has the signal put
+    /// aside and posted to it as soon as it is free, so nothing is lost. This may be called from
+    /// interrupt handlers.
+    pub fn signal_or_defer(p: &ProcRefCell<KProc>, sig: Signal) {
+        assert!(signal::is_valid(sig));
+        if let Some(mut p) = p.try_borrow_mut() {
+            p.post_signal(sig);
+            return;
+        }
+        // Nobody holding the borrow touches this, so we do not need one.
+        let deferred = unsafe { &(*p.as_unsafe_cell().get()).deferred };
+        dbg!(debug::SIGNAL, "process is busy, deferring signal {}", sig);
+        deferred.fetch_or(1 << sig, Ordering::SeqCst);
+        block_interrupts!({
+            let t = unsafe { &mut *DEFER_TIMER };
+            if !t.is_pending() {
+                t.set(time::ticks() + 1);
+            }
+        });
+    }
+
+    /// Try to hand out any signals that are pending on the process as a whole. This is done
+    /// whenever a thread might have unblocked some of them.
+    pub fn redeliver_pending(&mut self) {
+        let mut left = self.sigpending;
+        self.sigpending = SigSet::empty();
+        while let Some(sig) = left.first() {
+            left.remove(sig);
+            self.post_signal(sig);
+        }
+    }
+
+    /// Returns true if a signal would be thrown away on arrival.
+    pub fn is_ignored(&self, sig: Signal) -> bool {
+        match self.sigactions[sig as usize].handler {
+            signal::SIG_IGN => true,
+            signal::SIG_DFL => match signal::default_action(sig) {
+                DefaultAction::Ignore | DefaultAction::Continue => true,
+                DefaultAction::Terminate | DefaultAction::Stop => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Remove a signal from the process and all of its threads.
+    fn discard_signal(&mut self, sig: Signal) {
+        self.sigpending.remove(sig);
+        for (_, thr) in self.threads.iter_mut() {
+            thr.sigpending.remove(sig);
+        }
+    }
+
+    /// Carry out what a signal does when its handler is SIG_DFL.
+    pub fn do_default_action(&mut self, sig: Signal) {
+        dbg!(debug::SIGNAL, "Default action {:?} for signal {} on {:?}", signal::default_action(sig), sig, self);
+        match signal::default_action(sig) {
+            DefaultAction::Terminate => {
+                // Our threads need to be running to notice they have been cancelled.
+                self.resume();
+                self.kill(ProcStatus::signaled(sig));
+            },
+            DefaultAction::Stop => self.stop(sig),
+            DefaultAction::Ignore | DefaultAction::Continue => {},
+        }
+    }
+
+    /// Perform the sigaction syscall.
+    pub fn do_sigaction(&mut self, sig: Signal, act: Option<SigAction>) -> errno::KResult<SigAction> {
+        if !signal::is_valid(sig) {
+            return Err(errno::EINVAL);
+        }
+        let old = self.sigactions[sig as usize];
+        if let Some(a) = act {
+            if signal::UNBLOCKABLE.contains(sig) {
+                dbger!(debug::SIGNAL, errno::EINVAL, "{:?} attempted to change the action of signal {}", self, sig);
+                return Err(errno::EINVAL);
+            }
+            self.sigactions[sig as usize] = SigAction { mask: a.mask - signal::UNBLOCKABLE, ..a };
+            if self.is_ignored(sig) {
+                self.discard_signal(sig);
+            }
+        }
+        Ok(old)
+    }
+
+    pub fn get_sigaction(&self, sig: Signal) -> SigAction {
+        self.sigactions[sig as usize]
+    }
+
+    /// Set the action for a signal back to SIG_DFL.
+    pub fn reset_sigaction(&mut self, sig: Signal) {
+        self.sigactions[sig as usize] = SigAction::new();
+    }
+
+    pub fn is_stopped(&self) -> bool { self.stopped }
+
+    /// Get one of our resource limits.
+    pub fn get_rlimit(&self, res: resource::Resource) -> RLimit { self.rlimits[res as usize] }
+
+    /// Set one of our resource limits. `resource::setrlimit` checks that this is allowed.
+    pub fn set_rlimit(&mut self, res: resource::Resource, lim: RLimit) { self.rlimits[res as usize] = lim; }
+
+    /// What we have used so far.
+    pub fn get_usage(&self) -> &Usage { &self.usage }
+    pub fn get_usage_mut(&mut self) -> &mut Usage { &mut self.usage }
+
+    /// The current end of our heap.
+    pub fn get_brk(&self) -> usize { self.brk }
+    pub fn set_brk(&mut self, brk: usize) { self.brk = brk; }
+
+    /// Where our heap starts.
+    pub fn get_start_brk(&self) -> usize { self.start_brk }
+
+    /// Start our heap, empty, at `addr`. The program loader does this once it knows where the data
+    /// segment ends.
+    pub fn set_start_brk(&mut self, addr: usize) {
+        self.start_brk = addr;
+        self.brk = addr;
+    }
+
+    /// Our memory map. It is kept as an `Any` since the type lives in umem, see `umem::vmmap::current`.
+    pub fn get_vmmap(&self) -> Option<&Any> { self.vmmap.as_ref().map(|m| &**m) }
+
+    pub fn get_vmmap_mut(&mut self) -> Option<&mut Any> { self.vmmap.as_mut().map(|m| &mut **m) }
+
+    /// Replace our memory map, returning the old one.
+    pub fn set_vmmap(&mut self, m: Option<Box<Any>>) -> Option<Box<Any>> { ::std::mem::replace(&mut self.vmmap, m) }
+
+    /// What our children that have been waited for used.
+    pub fn get_child_usage(&self) -> &Usage { &self.child_usage }
+
+    /// The timer used to send us SIGALRM. It lives as long as we do.
+    pub fn alarm_timer(&self) -> *mut Timer { &*self.alarm as *const Timer as *mut Timer }
+
+    /// Get the nice value of this process. All of our threads share the same one.
+    pub fn get_nice(&self) -> i32 {
+        self.threads.values().next().map(|t| { t.ctx.sched.nice }).unwrap_or(0)
+    }
+
+    /// Set the nice value of all of our threads. This takes effect the next time they are put on
+    /// the run queue.
+    pub fn set_nice(&mut self, nice: i32) {
+        dbg!(debug::SCHED, "setting nice of {:?} to {}", self, nice);
+        for (_, thr) in self.threads.iter_mut() {
+            thr.ctx.sched.nice = nice;
+        }
+    }
+
+    /// Sleep until we are no longer stopped or the current thread has been cancelled.
+    pub fn wait_while_stopped(&self) {
+        while self.stopped && !(current_thread!()).cancelled {
+            if self.stopq.force_wait().is_err() {
+                break;
+            }
+        }
+    }
+
+    fn stop(&mut self, sig: Signal) {
+        if self.stopped {
+            return;
+        }
+        dbg!(debug::SIGNAL, "{:?} stopped", self);
+        self.stopped = true;
+        self.wait_report = Some(ProcStatus::stopped(sig));
+        self.notify_parent_of_stop();
+    }
+
+    fn resume(&mut self) {
+        if !self.stopped {
+            return;
+        }
+        dbg!(debug::SIGNAL, "{:?} continued", self);
+        self.stopped = false;
+        self.wait_report = Some(ProcStatus::continued());
+        self.stopq.signal();
+        self.notify_parent_of_stop();
+    }
+
+    /// Tell our parent we have stopped or continued, unless it asked not to be told.
+    fn notify_parent_of_stop(&self) {
+        let parent = match self.parent.clone().and_then(|p| { p.upgrade() }) {
+            Some(p) => p,
+            None => { return; },
+        };
+        // We can be stopped from an interrupt handler or by our parent, so it might be in the
+        // middle of something. Like signal::killpg we do not wait for it, if it is busy SIGCHLD
+        // is deferred.
+        if (current_proc!()).has_child(self.pid) {
+            (current_proc!()).wait.signal();
+            if (current_proc!()).sigactions[SIGCHLD as usize].flags & signal::SA_NOCLDSTOP == 0 {
+                (current_proc_mut!()).post_signal(SIGCHLD);
+            }
+            return;
+        }
+        let tell = {
+            // Whoever has it borrowed will not move its wait queue or signal actions out from under
+            // us, so we can look at them without a borrow of our own.
+            let p = unsafe { &*parent.as_unsafe_cell().get() };
+            p.wait.signal();
+            p.sigactions[SIGCHLD as usize].flags & signal::SA_NOCLDSTOP == 0
+        };
+        if tell {
+            KProc::signal_or_defer(&parent, SIGCHLD);
         }
     }
 
@@ -388,7 +851,8 @@ impl KProc {
     pub fn thread_exited(&mut self, exit: *mut c_void) {
         assert!(self.threads.contains_key(&hash::hash::<KThread, hash::SipHasher>(current_thread!())));
         if self.all_threads_dead() {
-            self.cleanup(exit as ProcStatus);
+            let status = self.kill_status.take().unwrap_or(ProcStatus::exited(exit as isize));
+            self.cleanup(status);
         } else {
             not_yet_implemented!("MTP: thread_exited for multithreaded programming");
         }
@@ -399,16 +863,15 @@ impl KProc {
         assert!(self.is_current_process());
         assert!(self.pid != IDLE_PID);
         let parent = self.parent.clone().expect("PARENT PROCESS UNSET").upgrade().expect("Parent process should not have been destroyed!");
-        dbg!(debug::PROC, "{:?} cleaning up. Sending wakeup to parent {:?}, exit status was 0x{:x}", self, parent.borrow(), status);
+        dbg!(debug::PROC, "{:?} cleaning up. Sending wakeup to parent {:?}, exit status was {:?}", self, parent.borrow(), status);
         self.status = status;
         self.state = ProcState::DEAD;
         // TODO This is actually pretty bad WRT borrowing. If parent-proc is INIT we might try to
         // TODO double borrow, depending on drop-placement. This is not dangerous but it is annoying.
         // TODO Therefore I should try to rearrange this so it is not dependent on the ordering of
         // TODO Drops, possibly by doing some sort of callback routine.
-        let pref = parent.borrow();
-        if pref.get_pid() != IDLE_PID {
-            drop(pref);
+        let parent_pid = parent.borrow().get_pid();
+        if parent_pid != IDLE_PID {
             let init = init_proc!();
             for (pid, child) in self.children.drain() {
                 dbg!(debug::PROC, "moving {:?} to init proc", pid);
@@ -420,11 +883,17 @@ impl KProc {
         // get rid of our ref's to the children.
         //self.children.clear();
 
+        self.alarm.cancel();
+        if self.pid == self.sid {
+            session::leader_exited(self.sid);
+        }
+
         // TODO VFS CLOSE ALL FILES
         // TODO VFS CLOSE CWD
-        // TODO VM  DELETE VMMAP
+        self.vmmap = None;
 
         parent.borrow().wait.signal();
+        parent.borrow_mut().post_signal(SIGCHLD);
 
         dbg!(debug::PROC, "process is dead");
     }
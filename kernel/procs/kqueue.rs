@@ -5,48 +5,36 @@
 use std::collections::*;
 use std::mem::{transmute, transmute_copy};
 use std::cell::*;
-use std::cmp::Ordering::{self, Equal};
 use std::{fmt, ptr};
 use kthread::KThread;
 use kthread;
 use sync;
+use resource;
+use lockdep;
+use time::{self, Timer};
+use base::errno::{self, KResult};
 use base::cell::*;
 
-#[allow(raw_pointer_derive)]
-#[derive(Hash)]
-pub struct QueuedThread(*mut KThread);
-pub struct KQueue(SafeCell<HashSet<QueuedThread>>);
+/// A queue of sleeping threads. Threads are woken in the order they started waiting.
+pub struct KQueue(SafeCell<VecDeque<*mut KThread>>);
 
 pub fn init_stage1() {}
 pub fn init_stage2() {}
 
-impl Ord for QueuedThread {
-    fn cmp(&self, other: &QueuedThread) -> Ordering {
-        let &QueuedThread(me) = self;
-        let &QueuedThread(o) = other;
-        (me as usize).cmp(&(o as usize))
-    }
-}
-
-impl PartialOrd for QueuedThread {
-    fn partial_cmp(&self, other: &QueuedThread) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PartialEq for QueuedThread {
-    fn eq(&self, other: &QueuedThread) -> bool {
-        self.cmp(other) == Equal
-    }
-}
-
-impl Eq for QueuedThread {}
 impl KQueue {
     pub fn len(&self) -> usize {
         let &KQueue(ref s) = self;
         (*s.get_ref()).len()
     }
 
+    /// Fold `f` over the threads waiting on this queue, in the order they started waiting.
+    pub fn fold<T, F>(&self, init: T, f: F) -> T where F: FnMut(T, &*mut KThread) -> T {
+        block_interrupts!({
+            let &KQueue(ref s) = self;
+            s.get_ref().iter().fold(init, f)
+        })
+    }
+
     /// Remove a thread from this queue without waking it.
     pub fn remove(&mut self, t: &mut KThread) {
         block_interrupts!({
@@ -54,23 +42,37 @@ impl KQueue {
             t.queue = ptr::null_mut();
             let k : *mut KThread = t as *mut KThread;
             let &mut KQueue(ref s) = self;
-            assert!((*s.get_mut()).remove(&QueuedThread(k)));
+            let mut lst = s.get_mut();
+            let pos = lst.iter().position(|&x| { x == k }).expect("thread not in the queue it is waiting on");
+            assert!(lst.remove(pos).is_some());
         });
     }
 
     fn add(&mut self, t: &mut KThread) {
         block_interrupts!({
             let &mut KQueue(ref s) = self;
-            assert!((*s.get_mut()).insert(QueuedThread(unsafe { transmute(t) })));
+            (*s.get_mut()).push_back(unsafe { transmute(t) });
         });
     }
 
+    /// Wake up the thread that has been waiting longest, returning it.
+    pub fn wake_first(&self) -> Option<*mut KThread> {
+        block_interrupts!({
+            let &KQueue(ref q) = self;
+            let first = q.get_mut().pop_front();
+            if let Some(x) = first {
+                self.wakeup_one(x);
+            }
+            first
+        })
+    }
+
     /// Add a thread into this queue. This returns after some call to signal. false if we were
-    /// canceled, true otherwise.
+    /// canceled or a signal arrived during a cancelable wait, true otherwise.
     pub fn wait_on(&mut self, cancelable: bool) -> bool {
         let t = current_thread!();
-        if cancelable && t.cancelled {
-            dbg!(debug::SCHED, "Not waiting for cancelation because thread {:?} is already canceled", t);
+        if cancelable && t.is_interrupted() {
+            dbg!(debug::SCHED, "Not waiting because thread {:?} is already canceled or signaled", t);
             return false;
         }
         block_interrupts!({
@@ -81,9 +83,48 @@ impl KQueue {
             }
             t.state = if cancelable { kthread::State::SLEEPCANCELLABLE } else { kthread::State::SLEEP };
             self.add(t);
+            resource::count_voluntary_switch();
+            lockdep::might_sleep(t);
             t.ctx.switch();
         });
-        return !t.cancelled;
+        return if cancelable { !t.is_interrupted() } else { !t.cancelled };
+    }
+
+    /// Like a cancelable `wait_on` but gives up once the tick count reaches `deadline`. Returns
+    /// ETIMEDOUT if we ran out of time and EINTR or ECANCELED if a signal or cancellation woke us.
+    pub fn wait_until(&mut self, deadline: u64) -> KResult<()> {
+        let t = current_thread!();
+        if t.is_interrupted() {
+            dbg!(debug::SCHED, "Not waiting because thread {:?} is already canceled or signaled", t);
+            return Err(t.interrupted_errno());
+        }
+        let mut timer = Timer::new(time::wake_thread, t as *mut KThread as usize);
+        let waited = block_interrupts!({
+            if time::ticks() >= deadline {
+                false
+            } else {
+                dbg!(debug::SCHED, "{:?} begining wait until tick {}", t, deadline);
+                bassert!(t.queue == ptr::null_mut());
+                unsafe {
+                    t.queue = transmute_copy(&self);
+                }
+                t.state = kthread::State::SLEEPCANCELLABLE;
+                self.add(t);
+                timer.set(deadline);
+                resource::count_voluntary_switch();
+                lockdep::might_sleep(t);
+                t.ctx.switch();
+                timer.cancel();
+                true
+            }
+        });
+        if !waited || timer.triggered() {
+            Err(errno::ETIMEDOUT)
+        } else if t.is_interrupted() {
+            Err(t.interrupted_errno())
+        } else {
+            Ok(())
+        }
     }
 
     fn wakeup_one(&self, t: *mut KThread) {
@@ -96,7 +137,7 @@ impl KQueue {
     }
 
     pub fn new() -> KQueue {
-        KQueue ( SafeCell::new(HashSet::new()) )
+        KQueue ( SafeCell::new(VecDeque::new()) )
     }
 }
 
@@ -107,13 +148,18 @@ impl sync::Wakeup for KQueue {
             let &KQueue(ref q) = self;
             dbg!(debug::SCHED, "Waking up {} threads", q.get_ref().len());
             let mut lst = q.get_mut();
-            for QueuedThread(x) in lst.drain() {
+            while let Some(x) = lst.pop_front() {
                 self.wakeup_one(x);
             }
         });
     }
 }
 
+impl sync::WakeupOne for KQueue {
+    /// Wake up the thread that has been waiting longest, if there are any.
+    fn signal_one(&self) -> bool { self.wake_first().is_some() }
+}
+
 pub struct WQueue(UnsafeCell<KQueue>);
 
 impl WQueue {
@@ -122,6 +168,8 @@ impl WQueue {
     fn get_inner<'a>(&'a self) -> &'a mut KQueue { let &WQueue(ref kq) = self; unsafe { transmute(kq.get()) } }
     pub fn len(&self) -> usize { self.get_inner().len() }
     pub fn force_wait(&self) -> Result<(),()> { if self.get_inner().wait_on(false) { Ok(()) } else { Err(()) } }
+    /// Wait until signaled or the tick count reaches `deadline`. See `KQueue::wait_until`.
+    pub fn wait_until(&self, deadline: u64) -> KResult<()> { self.get_inner().wait_until(deadline) }
 }
 
 impl sync::Wait<(),()> for WQueue {
@@ -134,6 +182,11 @@ impl sync::Wakeup for WQueue {
     /// Wake up all waiting threads in this queue.
     fn signal(&self) { self.get_inner().signal(); }
 }
+impl sync::WakeupOne for WQueue {
+    /// Wake up a single waiting thread, if there are any.
+    fn signal_one(&self) -> bool { self.get_inner().signal_one() }
+}
+
 impl fmt::Debug for WQueue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         block_interrupts!( write!(f, "WQueue {{ waiters: {} }}", self.get_inner().len()) )
@@ -1,27 +1,66 @@
 // TODO Copyright Header
 
 //use base::describe;
-use mm::{alloc, page};
+use mm::{alloc, page, pagetable};
 use libc::c_void;
+use startup::gdt;
+use interrupt;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use base::errno;
 use std::{cmp, hash, fmt};
 use context;
 use std::mem::size_of;
 use std::ptr::{self, copy_nonoverlapping};
 use kqueue::KQueue;
+use kmutex::KMutex;
+use lockdep;
 use context::{Context, ContextFunc};
 use mm::pagetable::PageDir;
 use mm::{AllocError, Allocation};
+use signal::SigSet;
 
 pub static CUR_THREAD_SLOT : usize = 0;
+/// How many pages a kernel stack takes up, counting its guard pages. This should be a power of two
+/// since that is what the page allocator hands out.
 pub static DEFAULT_STACK_PAGES : usize = 16;
 
+/// How many pages at the bottom of each kernel stack are left unmapped, so that running off the
+/// end of one faults instead of scribbling over whatever is next.
+pub const GUARD_PAGES : usize = 1;
+
+/// What a new stack is filled with, so we can tell how much of it has been used.
+const STACK_POISON : u8 = 0x5a;
+
+/// The most stack any thread that has gone away used, in bytes.
+static MAX_STACK_USE : AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The most stack any thread that has gone away used, in bytes. This is what to look at when
+/// tuning `DEFAULT_STACK_PAGES`.
+pub fn max_stack_use() -> usize { MAX_STACK_USE.load(Ordering::SeqCst) }
+
+/// A kernel stack. The pages are preceded by `GUARD_PAGES` unmapped ones, which come out of the
+/// same allocation.
 #[allow(raw_pointer_derive)] #[derive(Hash, Eq, PartialEq)]
 pub struct KStack(usize, *mut u8);
 
 impl KStack {
+    /// Make a stack out of `pages` pages, the lowest `GUARD_PAGES` of which are the guard.
     pub fn with_size(pages : usize) -> Allocation<KStack> {
-        Ok(KStack(pages, try!(unsafe { page::alloc_n::<u8>(pages) })))
+        assert!(pages > GUARD_PAGES, "a stack of {} pages would have no room after its guard", pages);
+        let size = pages - GUARD_PAGES;
+        unsafe {
+            let base = try!(page::alloc_n_wait::<u8>(pages).map_err(|e| {
+                dbg!(debug::THR|debug::MM, "unable to get {} pages for a stack, {} pages are free, free blocks by order are {:?}",
+                     pages, page::free_count(), page::free_blocks());
+                e
+            }));
+            let stack = base.offset((GUARD_PAGES * page::SIZE) as isize);
+            ptr::write_bytes(stack, STACK_POISON, size * page::SIZE);
+            for i in 0..GUARD_PAGES {
+                pagetable::set_kernel_page_present(base as usize + i * page::SIZE, false);
+            }
+            Ok(KStack(size, stack))
+        }
     }
 
     pub fn new() -> Allocation<KStack> {
@@ -30,7 +69,7 @@ impl KStack {
 
     pub fn copy(&mut self) -> Result<KStack, AllocError> {
         let &mut KStack(size, _) = self;
-        let mut new = try!(KStack::with_size(size));
+        let mut new = try!(KStack::with_size(size + GUARD_PAGES));
         new.copy_from(self);
         Ok(new)
     }
@@ -42,6 +81,7 @@ impl KStack {
         unsafe { copy_nonoverlapping(mptr, optr, size); }
     }
 
+    /// The number of usable pages in this stack, not counting the guard.
     pub fn num_pages(&self) -> usize {
         let &KStack(size, _) = self;
         size
@@ -51,18 +91,69 @@ impl KStack {
         let &KStack(_, p) = self;
         p as *mut c_void
     }
+
+    /// The lowest and one past the highest address of the guard pages below this stack.
+    pub fn guard(&self) -> (usize, usize) {
+        let &KStack(_, p) = self;
+        (p as usize - GUARD_PAGES * page::SIZE, p as usize)
+    }
+
+    /// The most of this stack that has ever been in use, in bytes.
+    pub fn high_water(&self) -> usize {
+        let &KStack(size, p) = self;
+        let len = size * page::SIZE;
+        let untouched = (0..len).take_while(|&i| unsafe { *p.offset(i as isize) } == STACK_POISON).count();
+        len - untouched
+    }
 }
 
 impl Drop for KStack {
     fn drop(&mut self) {
         let &mut KStack(size, s) = self;
         if size != 0 {
-            unsafe { page::free_n(s as *mut c_void, size as u32); }
+            let used = self.high_water();
+            let mut max = MAX_STACK_USE.load(Ordering::SeqCst);
+            while used > max {
+                let old = MAX_STACK_USE.compare_and_swap(max, used, Ordering::SeqCst);
+                if old == max { break; }
+                max = old;
+            }
+            let (low, _) = self.guard();
+            unsafe {
+                for i in 0..GUARD_PAGES {
+                    pagetable::set_kernel_page_present(low + i * page::SIZE, true);
+                }
+                page::free_n(low as *mut c_void, (size + GUARD_PAGES) as u32);
+            }
         }
         //*self = KStack(0, 0 as *mut u8);
     }
 }
 
+/// How big the stack the double fault handler runs on is.
+const EMERGENCY_STACK_PAGES : usize = 2;
+
+/// How far above the bottom of a stack the stack pointer can be and still have run into the guard
+/// page. Only a push or a call can fault without moving the stack pointer below the bottom first.
+const OVERFLOW_SLOP : usize = 16;
+
+/// The double fault task. A fault while the CPU is trying to push the frame for another one, which
+/// is what happens when a kernel stack runs into its guard page, ends up here on a stack of its own.
+#[no_stack_check]
+extern "C" fn double_fault() {
+    let (mut eip, mut esp) = (0u32, 0u32);
+    unsafe { gdt::get_interrupted_task(&mut eip, &mut esp); }
+    let (eip, esp) = (eip as usize, esp as usize);
+    let thr = current_thread!();
+    let (low, high) = thr.kstack.guard();
+    if low <= esp && esp <= high + OVERFLOW_SLOP {
+        kpanic!("Kernel stack overflow in {:?} of {:?}. eip was {:#x} and esp was {:#x}, the guard page is {:#x}-{:#x}",
+                thr, current_proc!(), eip, esp, low, high);
+    } else {
+        kpanic!("Double fault in {:?} of {:?}. eip was {:#x} and esp was {:#x}", thr, current_proc!(), eip, esp);
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Mode { USER, KERNEL }
 
@@ -78,10 +169,24 @@ pub struct KThread {
     pub state : State, // Our state.
     pub mode  : Mode, // Whether we are in user or kernel mode
     pub queue : *mut KQueue, // The queue we are currently blocking on.
+    pub blocked_on : *const KMutex, // The mutex we are waiting to get, if any.
+    pub held_mutexes : *const KMutex, // The first mutex we hold, the rest are linked from it.
+    pub lockdep : lockdep::Held, // The locks we hold and where we took them, if checking lock order.
+    pub sigpending : SigSet, // Signals handed to this thread which have not yet been delivered.
+    pub sigmask : SigSet, // Signals this thread has blocked.
 }
 
 pub fn init_stage1() { alloc::request_slab_allocator("kthread", size_of::<KThread>() as u32) }
-pub fn init_stage2() {}
+pub fn init_stage2() {
+    let stack = unsafe { page::alloc_n::<u8>(EMERGENCY_STACK_PAGES) }.unwrap_or_else(|_| {
+        kpanic!("Unable to allocate a stack for the double fault handler");
+    });
+    unsafe {
+        let top = stack.offset((EMERGENCY_STACK_PAGES * page::SIZE) as isize);
+        gdt::set_double_fault_task(double_fault as *const c_void, top as *mut c_void);
+        interrupt::set_task_gate(interrupt::DOUBLE_FAULT, gdt::DOUBLE_FAULT_TSS);
+    }
+}
 
 pub fn kyield() {
     let ct = current_thread!();
@@ -107,10 +212,44 @@ impl KThread {
             cancelled : false,
             state     : State::NOSTATE,
             mode      : Mode::KERNEL,
-            queue     : 0 as *mut KQueue
+            queue     : 0 as *mut KQueue,
+            blocked_on : ptr::null(),
+            held_mutexes : ptr::null(),
+            lockdep : lockdep::Held::new(),
+            sigpending : SigSet::empty(),
+            sigmask    : SigSet::empty(),
         })
     }
 
+    /// Returns true if a cancellable sleep by this thread should end, either because we have been
+    /// cancelled or because there is a signal we can take.
+    pub fn is_interrupted(&self) -> bool {
+        self.cancelled || !(self.sigpending - self.sigmask).is_empty()
+    }
+
+    /// The error a cancellable operation should return once `is_interrupted` is true.
+    pub fn interrupted_errno(&self) -> errno::Errno {
+        if self.cancelled { errno::ECANCELED } else { errno::EINTR }
+    }
+
+    /// Give a signal to this thread, waking it if it is in a cancellable sleep. The signal must
+    /// not be blocked by this thread.
+    pub fn add_signal(&mut self, sig: ::signal::Signal) {
+        assert!(!self.sigmask.contains(sig));
+        self.sigpending.add(sig);
+        self.interrupt_sleep();
+    }
+
+    /// If we are in a cancellable sleep pull us out of it.
+    fn interrupt_sleep(&mut self) {
+        if self.state == State::SLEEPCANCELLABLE {
+            if let Some(queue) = unsafe { self.queue.as_mut() } {
+                queue.remove(self);
+            }
+            self.make_runable();
+        }
+    }
+
     /// returns true if this is the current thread, false otherwise.
     pub fn is_current_thread(&self) -> bool { self.kstack == current_thread!().kstack }
 
@@ -142,12 +281,7 @@ impl KThread {
         }
         assert!(self.state != State::NOSTATE, "Illegal state for a process");
         self.retval = v;
-        if self.state == State::SLEEPCANCELLABLE {
-            if let Some(queue) = unsafe { self.queue.as_mut() } {
-                queue.remove(self);
-            }
-            self.make_runable();
-        }
+        self.interrupt_sleep();
     }
     fn exit_self(&mut self, v: *mut c_void) -> ! {
         self.retval = v;
@@ -164,7 +298,7 @@ impl KThread {
 
 impl fmt::Debug for KThread {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "KThread {{ cancelled: {}, state: {:?}, errno: {:?} }}",
-               self.cancelled, self.state, self.errno)
+        write!(f, "KThread {{ cancelled: {}, state: {:?}, errno: {:?}, pending: {:?} }}",
+               self.cancelled, self.state, self.errno, self.sigpending)
     }
 }
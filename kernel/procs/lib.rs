@@ -32,21 +32,31 @@ pub use context::ContextFunc;
 pub fn init_stage1() {
     apic::init_stage1();
     interrupt::init_stage1();
+    time::init_stage1();
     kqueue::init_stage1();
     kmutex::init_stage1();
+    lockdep::init_stage1();
     context::init_stage1();
     kthread::init_stage1();
     kproc::init_stage1();
+    session::init_stage1();
+    futex::init_stage1();
+    workqueue::init_stage1();
 }
 
 pub fn init_stage2() {
     apic::init_stage2();
     interrupt::init_stage2();
+    time::init_stage2();
     kqueue::init_stage2();
     kmutex::init_stage2();
+    lockdep::init_stage2();
     context::init_stage2();
     kthread::init_stage2();
     kproc::init_stage2();
+    session::init_stage2();
+    futex::init_stage2();
+    workqueue::init_stage2();
 }
 pub fn init_stage3() {
     // TODO Put ones here for everything else.
@@ -72,6 +82,16 @@ pub mod kmutex;
 pub mod kproc;
 pub mod interrupt;
 pub mod args;
+pub mod signal;
+pub mod preempt;
+pub mod time;
+pub mod session;
+pub mod resource;
+pub mod workqueue;
+pub mod sched;
+pub mod lockdep;
+pub mod futex;
+pub mod tls;
 
 
 // TODO Rewrite this in rust.
@@ -97,11 +117,11 @@ mod apic {
         pub fn get_ipl() -> u8;
     }
 
-    #[allow(dead_code)]
     extern "C" {
         #[link_name = "apic_enable_periodic_timer"]
         pub fn enable_periodic_timer(freq: u32);
 
+        #[allow(dead_code)]
         #[link_name = "apic_disable_periodic_timer"]
         pub fn disable_periodic_timer();
 
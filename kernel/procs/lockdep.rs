@@ -0,0 +1,268 @@
+// TODO Copyright Header
+
+//! A checker for the order kernel locks are taken in. It only does anything with the LOCKDEP cfg.
+//!
+//! Every `KMutex` belongs to a lock class, which is just its name. Whenever a thread takes a mutex
+//! while holding others we remember that the held classes come before the new one, along with where
+//! each was taken. If a new ordering closes a cycle then two threads taking the locks in those
+//! orders could deadlock, even if they never have yet, so we report it. We also report sleeping
+//! while holding a `SpinLock` and unlocking a `KMutex` held by someone else.
+//!
+//! A site is the program counter a lock was taken at. Look them up with `info symbol` in gdb. Each
+//! problem is reported with the LOCKDEP debug mode and counted so the tests can check for them.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::ptr;
+use mm::Allocation;
+use startup::gdt;
+use kmutex::KMutex;
+use kthread::{KThread, CUR_THREAD_SLOT};
+
+/// Where a lock was taken or released.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Site(pub usize);
+
+impl fmt::Debug for Site {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{:#x}", self.0) }
+}
+
+/// The program counter of wherever this ends up inlined. The locking functions are always inlined
+/// when LOCKDEP is on so that this is in their caller.
+#[inline(always)]
+pub fn here() -> Site {
+    if !cfg!(LOCKDEP) {
+        return Site(0);
+    }
+    let pc : usize;
+    unsafe { asm!("call 1f; 1: pop $0" : "=r"(pc) : : : "volatile"); }
+    Site(pc)
+}
+
+/// How many spin locks we keep track of a thread holding at once.
+const MAX_SPINS : usize = 8;
+
+struct HeldMutex {
+    lock : *const KMutex,
+    name : &'static str,
+    site : Site,
+}
+
+/// The locks a thread holds, as far as lockdep knows. This lives in the `KThread`.
+pub struct Held {
+    mutexes : Vec<HeldMutex>,
+    /// Spin locks might be taken in interrupt handlers so we cannot allocate for them.
+    spins : [(usize, Site); MAX_SPINS],
+    nspins : usize,
+}
+
+impl Held {
+    pub fn new() -> Held { Held { mutexes: Vec::new(), spins: [(0, Site(0)); MAX_SPINS], nspins: 0 } }
+
+    fn site_of(&self, lock: *const KMutex) -> Option<Site> {
+        self.mutexes.iter().rev().find(|h| h.lock == lock).map(|h| h.site)
+    }
+}
+
+/// `to` was taken at `taken_at` while `from` was held, having been taken at `held_at`.
+#[derive(Clone, Copy)]
+struct Edge {
+    held_at  : Site,
+    taken_at : Site,
+}
+
+struct State {
+    /// Every ordering we have seen, keyed by (from, to).
+    edges    : BTreeMap<(&'static str, &'static str), Edge>,
+    problems : usize,
+}
+
+static mut STATE : *mut State = 0 as *mut State;
+
+pub fn init_stage1() {}
+pub fn init_stage2() {
+    if cfg!(LOCKDEP) {
+        dbg!(debug::LOCKDEP, "checking the order locks are taken in");
+        unsafe { STATE = box State { edges: BTreeMap::new(), problems: 0 } as *mut State; }
+    }
+}
+
+/// The checker's state if we are checking right now.
+fn state() -> Option<&'static mut State> {
+    if cfg!(LOCKDEP) { unsafe { STATE.as_mut() } } else { None }
+}
+
+impl State {
+    /// Stop checking. We only do this if we run out of memory since our picture of what is held
+    /// is no good after that.
+    fn give_up(&mut self) {
+        dbg!(debug::LOCKDEP | debug::MM, "out of memory, no longer checking lock order");
+        unsafe { STATE = ptr::null_mut(); }
+    }
+
+    /// Returns true if some chain of orderings goes from `from` to `to`. If `report` is set each
+    /// ordering on the chain is printed, the last first. This is a breadth first search so each
+    /// lock class is only looked at once.
+    fn find_chain(&self, from: &'static str, to: &'static str, report: bool) -> Allocation<bool> {
+        // The lock before each one we have reached, which is also the set of those we have reached.
+        let mut via : BTreeMap<&'static str, &'static str> = BTreeMap::new();
+        let mut queue = VecDeque::new();
+        try!(alloc!(try queue.push_back(from)));
+        while let Some(cur) = queue.pop_front() {
+            for (&(a, b), _) in self.edges.iter() {
+                if a != cur || b == from || via.contains_key(b) {
+                    continue;
+                }
+                try!(alloc!(try via.insert(b, a)));
+                if b == to {
+                    if report {
+                        self.report_chain(&via, from, to);
+                    }
+                    return Ok(true);
+                }
+                try!(alloc!(try queue.push_back(b)));
+            }
+        }
+        Ok(false)
+    }
+
+    /// Print the chain `find_chain` found from `from` to `to`, the last ordering first.
+    fn report_chain(&self, via: &BTreeMap<&'static str, &'static str>, from: &'static str, to: &'static str) {
+        let mut b = to;
+        while b != from {
+            let a = *via.get(b).expect("lockdep chain is missing a lock");
+            let e = self.edges.get(&(a, b)).expect("lockdep chain has an ordering we never saw");
+            dbg!(debug::LOCKDEP, "    earlier '{}' was taken at {:?} while holding '{}' taken at {:?}",
+                 b, e.taken_at, a, e.held_at);
+            b = a;
+        }
+    }
+
+    /// Note that `name` was taken at `site` while `held` was held, complaining if that can deadlock.
+    fn add_edge(&mut self, thr: &KThread, held: &HeldMutex, name: &'static str, site: Site) {
+        let key = (held.name, name);
+        if self.edges.contains_key(&key) {
+            return;
+        }
+        match self.find_chain(name, held.name, false) {
+            Ok(true) => {
+                self.problems += 1;
+                dbg!(debug::LOCKDEP, "possible deadlock: {:?} took '{}' at {:?} while holding '{}' taken at {:?}",
+                     thr, name, site, held.name, held.site);
+                if self.find_chain(name, held.name, true).is_err() {
+                    self.give_up();
+                    return;
+                }
+            },
+            Ok(false) => {},
+            Err(_) => {
+                self.give_up();
+                return;
+            },
+        }
+        if alloc!(try self.edges.insert(key, Edge { held_at: held.site, taken_at: site })).is_err() {
+            self.give_up();
+        }
+    }
+}
+
+/// `thr` has just become the holder of the mutex `lock` named `name`, taking it at `site`.
+pub fn acquired(thr: &mut KThread, lock: *const KMutex, name: &'static str, site: Site) {
+    block_interrupts!({
+        if let Some(st) = state() {
+            for h in thr.lockdep.mutexes.iter() {
+                // We cannot tell mutexes of the same class apart so there is no order to check.
+                if h.name != name {
+                    st.add_edge(thr, h, name, site);
+                }
+            }
+            if alloc!(try thr.lockdep.mutexes.push(HeldMutex { lock: lock, name: name, site: site })).is_err() {
+                st.give_up();
+            }
+        }
+    })
+}
+
+/// `thr` no longer holds `lock`.
+pub fn released(thr: &mut KThread, lock: *const KMutex) {
+    if state().is_some() {
+        block_interrupts!({
+            if let Some(pos) = thr.lockdep.mutexes.iter().rposition(|h| h.lock == lock) {
+                thr.lockdep.mutexes.remove(pos);
+            }
+        })
+    }
+}
+
+/// The current thread is unlocking the mutex `lock` at `site` but `holder` is the one holding it.
+pub fn bad_unlock(lock: *const KMutex, name: &'static str, holder: *mut KThread, site: Site) {
+    if let Some(st) = state() {
+        st.problems += 1;
+        match unsafe { holder.as_ref() } {
+            Some(h) => {
+                dbg!(debug::LOCKDEP, "{:?} unlocked '{}' at {:?} but {:?} holds it, having taken it at {:?}",
+                     current_thread!(), name, site, h, h.lockdep.site_of(lock));
+            },
+            None => {
+                dbg!(debug::LOCKDEP, "{:?} unlocked '{}' at {:?} but nobody holds it", current_thread!(), name, site);
+            },
+        }
+    }
+}
+
+/// What the current thread holds. Unlike `current_thread!` this does not panic if there is no
+/// thread yet, spin locks get used before then.
+fn current_held() -> Option<&'static mut Held> {
+    gdt::get_tsd().get_slot(CUR_THREAD_SLOT)
+                  .and_then(|s| { s.downcast_ref::<*mut KThread>() })
+                  .and_then(|&t| unsafe { t.as_mut() })
+                  .map(|t| { &mut t.lockdep })
+}
+
+/// The current thread took the spin lock at `lock` at `site`.
+pub fn spin_acquired(lock: usize, site: Site) {
+    if state().is_some() {
+        block_interrupts!({
+            if let Some(held) = current_held() {
+                if held.nspins < MAX_SPINS {
+                    held.spins[held.nspins] = (lock, site);
+                    held.nspins += 1;
+                }
+            }
+        })
+    }
+}
+
+/// The current thread let go of the spin lock at `lock`.
+pub fn spin_released(lock: usize) {
+    if state().is_some() {
+        block_interrupts!({
+            if let Some(held) = current_held() {
+                let n = held.nspins;
+                if let Some(pos) = held.spins[..n].iter().rposition(|&(l, _)| l == lock) {
+                    for i in pos..(n - 1) {
+                        held.spins[i] = held.spins[i + 1];
+                    }
+                    held.nspins -= 1;
+                }
+            }
+        })
+    }
+}
+
+/// `thr` is about to go to sleep. Complain if it holds any spin locks.
+pub fn might_sleep(thr: &KThread) {
+    if let Some(st) = state() {
+        let held = &thr.lockdep;
+        if held.nspins != 0 {
+            st.problems += 1;
+            dbg!(debug::LOCKDEP, "{:?} is going to sleep holding {} spin locks", thr, held.nspins);
+            for &(l, site) in held.spins[..held.nspins].iter() {
+                dbg!(debug::LOCKDEP, "    the spin lock at {:#x} was taken at {:?}", l, site);
+            }
+        }
+    }
+}
+
+/// How many problems we have found so far.
+pub fn problems() -> usize { state().map(|s| s.problems).unwrap_or(0) }
@@ -52,6 +52,11 @@ impl<T> ProcRefCell<T> {
         }
     }
 
+    /// Get at the cell holding the value without borrowing it. This is for the rare caller, such as
+    /// an interrupt handler, that cannot wait for a borrow to end and only touches parts of the value
+    /// that nobody holding a borrow will move or change.
+    pub unsafe fn as_unsafe_cell<'a>(&'a self) -> &'a UnsafeCell<T> { &self.value }
+
     /*
     /// Used to relinquish control of this before going to sleep. Must be paired with a restore
     /// state later. This lets us say we own it during our run but we can go to sleep and release
@@ -0,0 +1,55 @@
+// TODO Copyright Header
+
+//! Time slicing for threads running in user mode.
+//!
+//! When UPREEMPT is enabled every tick of the timer in `time` is counted against the running thread.
+//! Once a thread has used up its `TIMESLICE` it needs to be rescheduled. The switch itself is only
+//! done in `_rust_intr_handler` when the interrupted code was running in user mode, so kernel code is
+//! never preempted and everything that relies on that (`SpinOnce`, IPL based critical sections,
+//! silent process borrows) stays correct. The count is kept in each thread's `SchedInfo` and starts
+//! over whenever it is switched away from, however that happens.
+
+use startup::gdt;
+use interrupt;
+use kthread::{self, KThread, CUR_THREAD_SLOT};
+use resource;
+
+/// How many ticks a thread may run in user mode before it is made to yield.
+pub const TIMESLICE : usize = 5;
+
+/// The running thread, if we have gotten far enough in boot to have one. The timer starts ticking
+/// before that.
+fn current() -> Option<&'static mut KThread> {
+    gdt::get_tsd().get_slot(CUR_THREAD_SLOT)
+                  .and_then(|s| { s.downcast_ref::<*mut KThread>() })
+                  .and_then(|&t| unsafe { t.as_mut() })
+}
+
+/// Account for one timer tick against the running thread.
+pub fn tick() {
+    if let Some(t) = current() {
+        t.ctx.sched.slice_used += 1;
+    }
+}
+
+/// Returns true if the current thread has used up its timeslice.
+pub fn need_resched() -> bool {
+    current().map(|t| t.ctx.sched.slice_used >= TIMESLICE).unwrap_or(false)
+}
+
+/// Called on the way out of an interrupt that came from user mode. If the current thread has used
+/// up its timeslice we put it at the back of the run queue and let something else go.
+pub fn preempt_if_needed() {
+    if !need_resched() {
+        return;
+    }
+    dbg!(debug::SCHED, "preempting {:?}", current_thread!());
+    // Let the scheduling policy know this thread is CPU bound.
+    (current_thread!()).ctx.sched.expired = true;
+    resource::count_involuntary_switch();
+    // Whatever we switch to expects to be running with interrupts on. We are still on the
+    // interrupt's stack frame so we turn them back off before returning into it.
+    interrupt::enable();
+    kthread::kyield();
+    interrupt::disable();
+}
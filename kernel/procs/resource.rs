@@ -0,0 +1,224 @@
+// TODO Copyright Header
+
+//! Per-process resource limits and usage accounting.
+//!
+//! Limits are inherited from the creating process and checked by whatever allocates the resource:
+//! process creation checks RLIMIT_NPROC, the timer checks RLIMIT_CPU and mmap checks RLIMIT_AS.
+//! RLIMIT_STACK and RLIMIT_NOFILE are kept and inherited but not enforced until we have exec to set
+//! up user stacks and a VFS to open files with. Usage is counted as things happen to the current
+//! process and is added into the parent's totals for its children when it is reaped.
+
+use std::ops::DerefMut;
+use std::rc::Weak;
+use startup::gdt;
+use base::errno::{self, KResult};
+use kproc::{KProc, CUR_PROC_SLOT};
+use pcell::ProcRefCell;
+use signal::{SIGXCPU, SIGKILL};
+use time::{self, Timeval};
+
+pub type Resource = u32;
+/// CPU time in seconds. SIGXCPU is sent every second past the soft limit, SIGKILL at the hard one.
+pub const RLIMIT_CPU    : Resource = 0;
+/// The size of the user stack in bytes.
+pub const RLIMIT_STACK  : Resource = 3;
+/// The number of children a process may have at once.
+pub const RLIMIT_NPROC  : Resource = 6;
+/// The number of files a process may have open.
+pub const RLIMIT_NOFILE : Resource = 7;
+/// The size of the address space in bytes.
+pub const RLIMIT_AS     : Resource = 9;
+/// One more than the largest resource number.
+pub const RLIM_NLIMITS  : usize = 10;
+
+/// The value of a limit that is not limited at all.
+pub const RLIM_INFINITY : usize = !0;
+
+/// A soft (`cur`) and hard (`max`) limit on a resource. Going over the soft limit is an error, a
+/// process may raise its soft limit up to the hard one and lower the hard one but never raise it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RLimit {
+    pub cur : usize,
+    pub max : usize,
+}
+
+impl RLimit {
+    pub fn unlimited() -> RLimit { RLimit { cur: RLIM_INFINITY, max: RLIM_INFINITY } }
+    pub fn new(cur: usize, max: usize) -> RLimit { RLimit { cur: cur, max: max } }
+}
+
+fn is_supported(res: Resource) -> bool {
+    match res {
+        RLIMIT_CPU | RLIMIT_STACK | RLIMIT_NPROC | RLIMIT_NOFILE | RLIMIT_AS => true,
+        _ => false,
+    }
+}
+
+/// The limits the first processes start with.
+pub fn default_limits() -> [RLimit; RLIM_NLIMITS] {
+    let mut l = [RLimit::unlimited(); RLIM_NLIMITS];
+    l[RLIMIT_STACK as usize] = RLimit::new(8 * 1024 * 1024, RLIM_INFINITY);
+    l[RLIMIT_NPROC as usize] = RLimit::new(256, RLIM_INFINITY);
+    l[RLIMIT_NOFILE as usize] = RLimit::new(32, 1024);
+    l
+}
+
+/// Perform the getrlimit syscall.
+pub fn getrlimit(res: Resource) -> KResult<RLimit> {
+    if !is_supported(res) {
+        return Err(errno::EINVAL);
+    }
+    Ok((current_proc!()).get_rlimit(res))
+}
+
+/// Perform the setrlimit syscall.
+pub fn setrlimit(res: Resource, new: RLimit) -> KResult<()> {
+    if !is_supported(res) || new.cur > new.max {
+        return Err(errno::EINVAL);
+    }
+    let old = (current_proc!()).get_rlimit(res);
+    if new.max > old.max {
+        dbg!(debug::PROC, "{:?} tried to raise its hard limit on {} from {} to {}",
+             current_pid!(), res, old.max, new.max);
+        return Err(errno::EPERM);
+    }
+    (current_proc_mut!()).set_rlimit(res, new);
+    Ok(())
+}
+
+/// What a process has used.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    /// Timer ticks spent running in user mode.
+    pub user_ticks   : u64,
+    /// Timer ticks spent running in the kernel.
+    pub system_ticks : u64,
+    /// Page faults handled without doing any IO.
+    pub minflt       : u64,
+    /// Page faults that needed IO.
+    pub majflt       : u64,
+    /// Times we blocked.
+    pub nvcsw        : u64,
+    /// Times we were preempted.
+    pub nivcsw       : u64,
+    /// Blocks read from disk.
+    pub inblock      : u64,
+    /// Blocks written to disk.
+    pub oublock      : u64,
+}
+
+impl Usage {
+    pub fn new() -> Usage { Default::default() }
+
+    /// Add everything in `other` to this.
+    pub fn add(&mut self, other: &Usage) {
+        self.user_ticks   += other.user_ticks;
+        self.system_ticks += other.system_ticks;
+        self.minflt       += other.minflt;
+        self.majflt       += other.majflt;
+        self.nvcsw        += other.nvcsw;
+        self.nivcsw       += other.nivcsw;
+        self.inblock      += other.inblock;
+        self.oublock      += other.oublock;
+    }
+
+    fn to_rusage(&self) -> Rusage {
+        fn tv(ticks: u64) -> Timeval {
+            let t = time::Timespec::from_ticks(ticks);
+            Timeval { tv_sec: t.tv_sec, tv_usec: t.tv_nsec / 1000 }
+        }
+        Rusage {
+            ru_utime: tv(self.user_ticks),
+            ru_stime: tv(self.system_ticks),
+            ru_minflt: self.minflt,
+            ru_majflt: self.majflt,
+            ru_nvcsw: self.nvcsw,
+            ru_nivcsw: self.nivcsw,
+            ru_inblock: self.inblock,
+            ru_oublock: self.oublock,
+        }
+    }
+}
+
+/// Usage as reported to user space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rusage {
+    pub ru_utime   : Timeval,
+    pub ru_stime   : Timeval,
+    pub ru_minflt  : u64,
+    pub ru_majflt  : u64,
+    pub ru_nvcsw   : u64,
+    pub ru_nivcsw  : u64,
+    pub ru_inblock : u64,
+    pub ru_oublock : u64,
+}
+
+pub const RUSAGE_SELF     : i32 = 0;
+pub const RUSAGE_CHILDREN : i32 = -1;
+
+/// Perform the getrusage syscall. RUSAGE_CHILDREN covers all of our descendants that have been
+/// waited for.
+pub fn getrusage(who: i32) -> KResult<Rusage> {
+    match who {
+        RUSAGE_SELF => Ok((current_proc!()).get_usage().to_rusage()),
+        RUSAGE_CHILDREN => Ok((current_proc!()).get_child_usage().to_rusage()),
+        _ => Err(errno::EINVAL),
+    }
+}
+
+/// Run `f` on the current process's usage, if there is a current process we can get at. Unlike
+/// `current_proc_mut!` this never panics, so it is fine to use from interrupt handlers and early
+/// in boot.
+fn with_current<F>(f: F) where F: FnOnce(&mut KProc) {
+    let r = match gdt::get_tsd().get_slot(CUR_PROC_SLOT)
+                                .and_then(|s| { s.downcast_ref::<Weak<ProcRefCell<KProc>>>() })
+                                .and_then(|w| { w.clone().upgrade() }) {
+        Some(r) => r,
+        None => { return; },
+    };
+    if let Some(mut p) = r.try_silent_borrow_mut() {
+        f(p.deref_mut());
+    };
+}
+
+/// Charge a timer tick to the current process. Called from the timer interrupt.
+pub fn charge_tick(user: bool) {
+    with_current(|p| {
+        let total = {
+            let u = p.get_usage_mut();
+            if user { u.user_ticks += 1; } else { u.system_ticks += 1; }
+            u.user_ticks + u.system_ticks
+        };
+        let hz = time::HZ as u64;
+        if total % hz != 0 {
+            return;
+        }
+        let secs = (total / hz) as usize;
+        let lim = p.get_rlimit(RLIMIT_CPU);
+        if secs >= lim.max {
+            dbg!(debug::PROC, "{:?} hit its hard CPU limit of {} seconds", p, lim.max);
+            p.post_signal(SIGKILL);
+        } else if secs >= lim.cur {
+            p.post_signal(SIGXCPU);
+        }
+    });
+}
+
+/// Count a page fault against the current process.
+pub fn count_fault(major: bool) {
+    with_current(|p| { let u = p.get_usage_mut(); if major { u.majflt += 1; } else { u.minflt += 1; } });
+}
+
+/// Count the current thread blocking.
+pub fn count_voluntary_switch() { with_current(|p| { p.get_usage_mut().nvcsw += 1; }); }
+
+/// Count the current thread being preempted.
+pub fn count_involuntary_switch() { with_current(|p| { p.get_usage_mut().nivcsw += 1; }); }
+
+/// Count blocks read from disk for the current process.
+pub fn count_blocks_in(n: usize) { with_current(|p| { p.get_usage_mut().inblock += n as u64; }); }
+
+/// Count blocks written to disk for the current process.
+pub fn count_blocks_out(n: usize) { with_current(|p| { p.get_usage_mut().oublock += n as u64; }); }
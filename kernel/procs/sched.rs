@@ -0,0 +1,209 @@
+// TODO Copyright Header
+
+//! Scheduling policies.
+//!
+//! The run queue in `context` hands every runnable thread to a `SchedPolicy` and asks it which one
+//! to run next. The policy is chosen at boot with the `sched=<name>` kernel command line option and
+//! may be switched later with `set_policy`.
+
+use std::collections::VecDeque;
+use std::cmp;
+use base::errno::{self, KResult};
+use context::{self, Context};
+use kproc::{KProc, ProcId};
+
+/// The lowest (most favorable) nice value.
+pub const NICE_MIN : i32 = -20;
+/// The highest (least favorable) nice value.
+pub const NICE_MAX : i32 = 19;
+
+/// The scheduling state of a thread. This lives in the thread's `Context`.
+#[derive(Debug, Clone, Copy)]
+pub struct SchedInfo {
+    /// The nice value of the thread, between NICE_MIN and NICE_MAX.
+    pub nice : i32,
+    /// The priority level the policy currently has the thread at, lower runs first.
+    pub level : usize,
+    /// Set when the thread is being put back on the run queue because it used up its timeslice.
+    pub expired : bool,
+    /// A better nice value lent to us by threads waiting on a `KMutex` we hold.
+    pub inherited : Option<i32>,
+    /// How many timer ticks we have run for since we were last switched to.
+    pub slice_used : usize,
+}
+
+impl SchedInfo {
+    pub fn new() -> SchedInfo { SchedInfo { nice: 0, level: 0, expired: false, inherited: None, slice_used: 0 } }
+
+    /// The scheduling state a newly created thread should get from its creator.
+    pub fn inherit(&self) -> SchedInfo { SchedInfo { nice: self.nice, level: 0, expired: false, inherited: None, slice_used: 0 } }
+
+    /// The nice value we should be scheduled with, counting anything we have inherited.
+    pub fn priority(&self) -> i32 { self.inherited.map(|i| cmp::min(i, self.nice)).unwrap_or(self.nice) }
+}
+
+/// A policy for choosing which runnable thread goes next. Nothing here needs to worry about
+/// interrupts, the run queue always calls in at high IPL.
+pub trait SchedPolicy {
+    /// The name used to pick this policy.
+    fn name(&self) -> &'static str;
+    /// Add a runnable context.
+    fn push(&mut self, ctx: *mut Context);
+    /// Remove and return the context that should run next, if any are runnable.
+    fn pop(&mut self) -> Option<*mut Context>;
+    /// How many contexts are waiting to run.
+    fn len(&self) -> usize;
+    /// The priority of `ctx` has changed, move it to wherever it now belongs if it is waiting to
+    /// run. Policies that ignore priorities need not do anything.
+    fn requeue(&mut self, _ctx: *mut Context) {}
+}
+
+/// Make a policy given its name.
+pub fn create_policy(name: &str) -> Option<Box<SchedPolicy + 'static>> {
+    match name {
+        "fifo" => Some(box Fifo::new() as Box<SchedPolicy + 'static>),
+        "mlfq" => Some(box Mlfq::new() as Box<SchedPolicy + 'static>),
+        _ => None,
+    }
+}
+
+/// The names of all the policies we have.
+pub const POLICIES : &'static [&'static str] = &["fifo", "mlfq"];
+/// The policy used if none is given on the command line.
+pub const DEFAULT_POLICY : &'static str = "mlfq";
+
+/// Switch to the named policy, moving everything waiting to run over to it.
+pub fn set_policy(name: &str) -> KResult<()> {
+    match create_policy(name) {
+        Some(p) => { context::replace_policy(p); Ok(()) },
+        None => { dbg!(debug::SCHED, "Unknown scheduling policy {:?}", name); Err(errno::EINVAL) },
+    }
+}
+
+/// The name of the policy in use.
+pub fn get_policy() -> &'static str { context::policy_name() }
+
+/// Perform the nice syscall, adding `inc` to the nice value of the current process. Returns the
+/// new nice value.
+pub fn nice(inc: i32) -> KResult<i32> {
+    let new = clamp_nice((current_thread!()).ctx.sched.nice.saturating_add(inc));
+    (current_proc_mut!()).set_nice(new);
+    Ok(new)
+}
+
+/// Perform the getpriority syscall for PRIO_PROCESS.
+pub fn getpriority(pid: ProcId) -> KResult<i32> {
+    if pid == current_pid!() {
+        return Ok((current_proc!()).get_nice());
+    }
+    let p = try!(KProc::get_proc(&pid).ok_or(errno::ESRCH));
+    let n = p.borrow().get_nice();
+    Ok(n)
+}
+
+/// Perform the setpriority syscall for PRIO_PROCESS. Out of range values are clamped.
+pub fn setpriority(pid: ProcId, prio: i32) -> KResult<()> {
+    let prio = clamp_nice(prio);
+    if pid == current_pid!() {
+        (current_proc_mut!()).set_nice(prio);
+        return Ok(());
+    }
+    let p = try!(KProc::get_proc(&pid).ok_or(errno::ESRCH));
+    p.borrow_mut().set_nice(prio);
+    Ok(())
+}
+
+fn clamp_nice(n: i32) -> i32 { cmp::max(NICE_MIN, cmp::min(NICE_MAX, n)) }
+
+/// The plain first-in first-out policy. Priorities are ignored entirely.
+pub struct Fifo(VecDeque<*mut Context>);
+
+impl Fifo {
+    pub fn new() -> Fifo { Fifo(VecDeque::new()) }
+}
+
+impl SchedPolicy for Fifo {
+    fn name(&self) -> &'static str { "fifo" }
+    fn push(&mut self, ctx: *mut Context) { self.0.push_back(ctx); }
+    fn pop(&mut self) -> Option<*mut Context> { self.0.pop_front() }
+    fn len(&self) -> usize { self.0.len() }
+}
+
+/// The number of priority levels the MLFQ has.
+pub const MLFQ_LEVELS : usize = 8;
+/// How many times we pick a thread between moving everything back up to the best level it may be at.
+pub const MLFQ_BOOST_INTERVAL : usize = 100;
+
+/// A multi-level feedback queue. Threads always run from the lowest non-empty level. A thread that
+/// uses up its whole timeslice drops a level, while one that blocks or yields stays where it is, so
+/// interactive threads stay ahead of CPU bound ones. A thread's nice value decides the best level
+/// it can be at. Every so often everything is moved back up to that level so nothing starves.
+pub struct Mlfq {
+    queues : Vec<VecDeque<*mut Context>>,
+    picks  : usize,
+}
+
+impl Mlfq {
+    pub fn new() -> Mlfq {
+        Mlfq { queues: (0..MLFQ_LEVELS).map(|_| VecDeque::new()).collect(), picks: 0 }
+    }
+
+    /// The best level a thread with the given nice value may be at.
+    fn base_level(nice: i32) -> usize {
+        ((clamp_nice(nice) - NICE_MIN) as usize * MLFQ_LEVELS) / ((NICE_MAX - NICE_MIN + 1) as usize)
+    }
+
+    fn boost(&mut self) {
+        dbg!(debug::SCHED, "Boosting all {} waiting threads to their best level", self.len());
+        for lvl in 1..MLFQ_LEVELS {
+            // Anything already at its best level goes back on the end of this queue, so only look
+            // at the ones that were there to start with.
+            for _ in 0..self.queues[lvl].len() {
+                let c = self.queues[lvl].pop_front().expect("queue had this many threads");
+                let info = unsafe { &mut (*c).sched };
+                info.level = Mlfq::base_level(info.priority());
+                self.queues[info.level].push_back(c);
+            }
+        }
+    }
+}
+
+impl SchedPolicy for Mlfq {
+    fn name(&self) -> &'static str { "mlfq" }
+
+    fn push(&mut self, ctx: *mut Context) {
+        let info = unsafe { &mut (*ctx).sched };
+        if info.expired {
+            info.level = cmp::min(info.level + 1, MLFQ_LEVELS - 1);
+            info.expired = false;
+        }
+        let best = Mlfq::base_level(info.priority());
+        // A thread running on borrowed priority goes right to the level it was lent, it is holding
+        // up someone more important.
+        info.level = if info.inherited.is_some() { best } else { cmp::max(info.level, best) };
+        self.queues[info.level].push_back(ctx);
+    }
+
+    fn pop(&mut self) -> Option<*mut Context> {
+        self.picks += 1;
+        if self.picks % MLFQ_BOOST_INTERVAL == 0 {
+            self.boost();
+        }
+        for q in self.queues.iter_mut() {
+            if let Some(c) = q.pop_front() {
+                return Some(c);
+            }
+        }
+        None
+    }
+
+    fn len(&self) -> usize { self.queues.iter().fold(0, |a, q| a + q.len()) }
+
+    fn requeue(&mut self, ctx: *mut Context) {
+        let lvl = unsafe { (*ctx).sched.level };
+        if let Some(pos) = self.queues[lvl].iter().position(|&c| c == ctx) {
+            self.queues[lvl].remove(pos);
+            self.push(ctx);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// TODO Copyright Header
+
+//! Process groups, sessions and controlling terminals.
+//!
+//! Every process is in a process group and every group is in a session, both named by the pid of
+//! their leader. A session may have a controlling terminal, and one of its groups is the foreground
+//! group of that terminal. The terminal only remembers which session it belongs to, everything else
+//! is kept here.
+
+use std::collections::BTreeMap;
+use base::devices::DeviceId;
+use base::errno::{self, KResult};
+use kproc::{KProc, ProcId};
+use signal::{self, SIGHUP, SIGCONT};
+
+/// What we know about a session.
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    /// The controlling terminal, if we have one.
+    ctty : Option<DeviceId>,
+    /// The group in the foreground of the controlling terminal.
+    foreground : ProcId,
+}
+
+/// Sessions with a controlling terminal. Sessions without one need no entry.
+static mut SESSIONS : *mut BTreeMap<ProcId, Session> = 0 as *mut BTreeMap<ProcId, Session>;
+
+pub fn init_stage1() {}
+pub fn init_stage2() {
+    unsafe { SESSIONS = box BTreeMap::new() as *mut BTreeMap<ProcId, Session>; }
+}
+
+fn sessions() -> &'static mut BTreeMap<ProcId, Session> {
+    unsafe { SESSIONS.as_mut().expect("session table not yet initialized") }
+}
+
+/// Look up a process by pid, with 0 meaning the current one, and get `f` of it.
+fn with_proc<T, F>(pid: ProcId, f: F) -> KResult<T> where F: FnOnce(&KProc) -> T {
+    if pid == ProcId(0) || pid == current_pid!() {
+        return Ok(f(&*current_proc!()));
+    }
+    let p = try!(KProc::get_proc(&pid).ok_or(errno::ESRCH));
+    let r = f(&*p.borrow());
+    Ok(r)
+}
+
+/// Returns true if some living process in session `sid` is in the process group `pgid`.
+fn group_in_session(pgid: ProcId, sid: ProcId) -> bool {
+    let mut found = false;
+    KProc::each_proc(|_, p| {
+        if let Some(p) = p.try_borrow() {
+            found = found || (p.get_pgid() == pgid && p.get_sid() == sid && !p.is_dead());
+        }
+    });
+    found
+}
+
+/// Returns true if the process group `pgid` is orphaned, meaning none of its members has a parent
+/// in another group of the same session that could stop and continue it. Processes that are busy
+/// are taken to have such a parent.
+pub fn is_orphaned(pgid: ProcId) -> bool {
+    let me = current_pid!();
+    let mut orphaned = true;
+    KProc::each_proc(|pid, p| {
+        if !orphaned {
+            return;
+        }
+        let (sid, parent) = if pid == me {
+            let p = current_proc!();
+            if p.get_pgid() != pgid || p.is_dead() { return; }
+            (p.get_sid(), p.get_parent())
+        } else {
+            match p.try_borrow() {
+                Some(p) => {
+                    if p.get_pgid() != pgid || p.is_dead() { return; }
+                    (p.get_sid(), p.get_parent())
+                },
+                None => { orphaned = false; return; },
+            }
+        };
+        let parent = match parent {
+            Some(parent) => parent,
+            None => { return; },
+        };
+        let outside = |pp: &KProc| { pp.get_sid() == sid && pp.get_pgid() != pgid };
+        if (current_proc!()).has_child(pid) {
+            orphaned = !outside(&*current_proc!());
+        } else {
+            orphaned = match parent.try_borrow() {
+                Some(pp) => !outside(&*pp),
+                None => false,
+            };
+        }
+    });
+    orphaned
+}
+
+/// Perform the getpgid syscall.
+pub fn getpgid(pid: ProcId) -> KResult<ProcId> { with_proc(pid, |p| { p.get_pgid() }) }
+
+/// Perform the getsid syscall.
+pub fn getsid(pid: ProcId) -> KResult<ProcId> { with_proc(pid, |p| { p.get_sid() }) }
+
+/// Perform the setpgid syscall, moving `pid` (which must be us or one of our children) into the
+/// group `pgid`. A `pid` of 0 means us and a `pgid` of 0 means the group named after `pid`.
+pub fn setpgid(pid: ProcId, pgid: ProcId) -> KResult<()> {
+    let me = current_pid!();
+    let pid = if pid == ProcId(0) { me } else { pid };
+    let pgid = if pgid == ProcId(0) { pid } else { pgid };
+    if pid != me && !(current_proc!()).has_child(pid) {
+        dbg!(debug::PROC, "{:?} cannot setpgid on {:?} which is not its child", me, pid);
+        return Err(errno::ESRCH);
+    }
+    let sid = (current_proc!()).get_sid();
+    let (tsid, tpid) = try!(with_proc(pid, |p| { (p.get_sid(), p.get_pid()) }));
+    if tsid != sid || tsid == tpid {
+        // It is in another session or leads its own.
+        return Err(errno::EPERM);
+    }
+    if pgid != pid && !group_in_session(pgid, sid) {
+        return Err(errno::EPERM);
+    }
+    if pid == me {
+        (current_proc_mut!()).set_pgid(pgid);
+    } else {
+        try!(KProc::get_proc(&pid).ok_or(errno::ESRCH)).borrow_mut().set_pgid(pgid);
+    }
+    dbg!(debug::PROC, "moved {:?} to process group {:?}", pid, pgid);
+    Ok(())
+}
+
+/// Perform the setsid syscall, making the current process the leader of a new session with no
+/// controlling terminal. Returns the new session id.
+pub fn setsid() -> KResult<ProcId> {
+    let me = current_pid!();
+    if (current_proc!()).get_pgid() == me || group_in_session(me, (current_proc!()).get_sid()) {
+        // We already lead a process group.
+        return Err(errno::EPERM);
+    }
+    (current_proc_mut!()).make_session_leader();
+    dbg!(debug::PROC, "{:?} is now the leader of a new session", me);
+    Ok(me)
+}
+
+/// The controlling terminal of the session `sid`, if it has one.
+pub fn get_ctty(sid: ProcId) -> Option<DeviceId> { sessions().get(&sid).and_then(|s| { s.ctty }) }
+
+/// The foreground process group of the session `sid`, if it has a controlling terminal.
+pub fn get_foreground(sid: ProcId) -> Option<ProcId> {
+    sessions().get(&sid).and_then(|s| { s.ctty.map(|_| { s.foreground }) })
+}
+
+/// Make `dev` the controlling terminal of the current process's session, which we must lead. The
+/// caller is responsible for making sure no other session has `dev`.
+pub fn set_ctty(dev: DeviceId) -> KResult<()> {
+    let (pid, pgid, sid) = { let p = current_proc!(); (p.get_pid(), p.get_pgid(), p.get_sid()) };
+    if pid != sid || get_ctty(sid).is_some() {
+        return Err(errno::EPERM);
+    }
+    let s = Session { ctty: Some(dev), foreground: pgid };
+    let res = block_interrupts!(alloc!(try sessions().insert(sid, s)));
+    try!(res.or(Err(errno::ENOMEM)));
+    dbg!(debug::PROC, "{:?} is the controlling terminal of session {:?}", dev, sid);
+    Ok(())
+}
+
+/// Perform tcsetpgrp, making `pgid` the foreground group of our controlling terminal.
+pub fn set_foreground(pgid: ProcId) -> KResult<()> {
+    let sid = (current_proc!()).get_sid();
+    if get_ctty(sid).is_none() {
+        return Err(errno::ENOTTY);
+    }
+    if !group_in_session(pgid, sid) {
+        return Err(errno::EPERM);
+    }
+    block_interrupts!({
+        if let Some(s) = sessions().get_mut(&sid) { s.foreground = pgid; }
+    });
+    Ok(())
+}
+
+/// Returns true if the current process is in the foreground of its controlling terminal, or has
+/// none at all.
+pub fn is_foreground() -> bool {
+    let (pgid, sid) = { let p = current_proc!(); (p.get_pgid(), p.get_sid()) };
+    get_foreground(sid).map(|fg| { fg == pgid }).unwrap_or(true)
+}
+
+/// Called when the leader of `sid` exits. The session loses its controlling terminal and the
+/// foreground group is hung up on.
+pub fn leader_exited(sid: ProcId) {
+    let old = block_interrupts!(sessions().remove(&sid));
+    if let Some(Session { ctty: Some(dev), foreground }) = old {
+        dbg!(debug::PROC, "session {:?} lost {:?}, hanging up on {:?}", sid, dev, foreground);
+        let _ = signal::killpg(foreground, SIGHUP);
+        let _ = signal::killpg(foreground, SIGCONT);
+    }
+}
@@ -0,0 +1,350 @@
+// TODO Copyright Header
+
+//! POSIX-style signals.
+//!
+//! Signals are posted to a process with `KProc::post_signal`. A signal that has a user handler is
+//! handed to one of the process' threads which does not have it blocked, or left pending on the
+//! process until some thread unblocks it. Default actions are carried out as soon as a thread is
+//! able to take the signal. Handlers are only ever run when a thread returns to user mode, at which
+//! point a `SigFrame` is pushed onto the user stack and execution resumes in the handler. The
+//! handler returns into its `restorer`, which must call `sigreturn` to undo this.
+
+use std::{fmt, mem};
+use std::ops::{BitOr, BitAnd, Sub, Not};
+use base::errno::{self, KResult};
+use mm::user;
+use interrupt::Registers;
+use kproc::{KProc, ProcId};
+
+pub type Signal = u32;
+
+pub const SIGHUP    : Signal = 1;
+pub const SIGINT    : Signal = 2;
+pub const SIGQUIT   : Signal = 3;
+pub const SIGILL    : Signal = 4;
+pub const SIGTRAP   : Signal = 5;
+pub const SIGABRT   : Signal = 6;
+pub const SIGBUS    : Signal = 7;
+pub const SIGFPE    : Signal = 8;
+pub const SIGKILL   : Signal = 9;
+pub const SIGUSR1   : Signal = 10;
+pub const SIGSEGV   : Signal = 11;
+pub const SIGUSR2   : Signal = 12;
+pub const SIGPIPE   : Signal = 13;
+pub const SIGALRM   : Signal = 14;
+pub const SIGTERM   : Signal = 15;
+pub const SIGCHLD   : Signal = 17;
+pub const SIGCONT   : Signal = 18;
+pub const SIGSTOP   : Signal = 19;
+pub const SIGTSTP   : Signal = 20;
+pub const SIGTTIN   : Signal = 21;
+pub const SIGTTOU   : Signal = 22;
+pub const SIGURG    : Signal = 23;
+pub const SIGXCPU   : Signal = 24;
+pub const SIGXFSZ   : Signal = 25;
+pub const SIGVTALRM : Signal = 26;
+pub const SIGPROF   : Signal = 27;
+pub const SIGWINCH  : Signal = 28;
+pub const SIGSYS    : Signal = 31;
+
+/// One more than the largest valid signal number.
+pub const NSIG : usize = 32;
+
+/// Handler value requesting the default action.
+pub const SIG_DFL : usize = 0;
+/// Handler value requesting the signal be ignored.
+pub const SIG_IGN : usize = 1;
+
+/// Do not send SIGCHLD to the parent when a child stops or continues.
+pub const SA_NOCLDSTOP : u32 = 0x00000001;
+/// Do not block the signal while its handler is running.
+pub const SA_NODEFER   : u32 = 0x40000000;
+/// Reset the action to SIG_DFL once the handler has been invoked.
+pub const SA_RESETHAND : u32 = 0x80000000;
+
+/// The `how` argument of sigprocmask.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SigHow { Block, Unblock, SetMask }
+
+/// A set of signals, with bit `n` representing signal `n`.
+#[repr(C)]
+#[derive(Default, Clone, Copy, Eq, PartialEq)]
+pub struct SigSet(u32);
+
+/// Signals which can never be blocked, caught or ignored.
+pub const UNBLOCKABLE : SigSet = SigSet((1 << SIGKILL) | (1 << SIGSTOP));
+
+impl SigSet {
+    pub fn empty() -> SigSet { SigSet(0) }
+    pub fn single(sig: Signal) -> SigSet { SigSet(1 << sig) }
+    pub fn from_bits(bits: u32) -> SigSet { SigSet(bits & !1) }
+    pub fn bits(&self) -> u32 { let &SigSet(b) = self; b }
+    pub fn is_empty(&self) -> bool { self.bits() == 0 }
+    pub fn contains(&self, sig: Signal) -> bool { (self.bits() & (1 << sig)) != 0 }
+    pub fn add(&mut self, sig: Signal) { *self = *self | SigSet::single(sig); }
+    pub fn remove(&mut self, sig: Signal) { *self = *self - SigSet::single(sig); }
+
+    /// Returns the lowest numbered signal in this set, if there is one.
+    pub fn first(&self) -> Option<Signal> {
+        if self.is_empty() { None } else { Some(self.bits().trailing_zeros() as Signal) }
+    }
+}
+
+impl BitOr for SigSet {
+    type Output = SigSet;
+    fn bitor(self, r: SigSet) -> SigSet { SigSet(self.bits() | r.bits()) }
+}
+
+impl BitAnd for SigSet {
+    type Output = SigSet;
+    fn bitand(self, r: SigSet) -> SigSet { SigSet(self.bits() & r.bits()) }
+}
+
+impl Sub for SigSet {
+    type Output = SigSet;
+    fn sub(self, r: SigSet) -> SigSet { SigSet(self.bits() & !r.bits()) }
+}
+
+impl Not for SigSet {
+    type Output = SigSet;
+    fn not(self) -> SigSet { SigSet(!self.bits() & !1) }
+}
+
+impl fmt::Debug for SigSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "SigSet["));
+        let mut started = false;
+        let mut left = *self;
+        while let Some(s) = left.first() {
+            if started { try!(write!(f, "|")); } else { started = true; }
+            try!(write!(f, "{}", s));
+            left.remove(s);
+        }
+        write!(f, "]")
+    }
+}
+
+/// The action taken when a signal arrives. This is laid out the way userland passes it to us.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SigAction {
+    /// The address of the handler, or one of SIG_DFL or SIG_IGN.
+    pub handler  : usize,
+    /// Additional signals to block while the handler is running.
+    pub mask     : SigSet,
+    /// SA_* flags.
+    pub flags    : u32,
+    /// Where the handler returns to. This must end up calling sigreturn.
+    pub restorer : usize,
+}
+
+impl SigAction {
+    pub fn new() -> SigAction {
+        SigAction { handler: SIG_DFL, mask: SigSet::empty(), flags: 0, restorer: 0 }
+    }
+}
+
+/// What happens to a process that gets a signal whose handler is SIG_DFL.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DefaultAction { Terminate, Ignore, Stop, Continue }
+
+pub fn default_action(sig: Signal) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// Returns true if this is a signal number we know about. Zero is not a valid signal.
+pub fn is_valid(sig: Signal) -> bool { sig != 0 && (sig as usize) < NSIG }
+
+/// Returns true if this signal stops a process when its action is SIG_DFL.
+pub fn is_stop(sig: Signal) -> bool { default_action(sig) == DefaultAction::Stop }
+
+/// The frame pushed onto the user stack when a handler is invoked. When the handler is entered the
+/// stack pointer points at `ret_addr` so it sees `signo` as its only argument. When it returns the
+/// restorer runs with the stack pointer just past `ret_addr`.
+#[repr(C)]
+pub struct SigFrame {
+    pub ret_addr : u32,
+    pub signo    : u32,
+    pub oldmask  : SigSet,
+    pub regs     : Registers,
+}
+
+/// The eflags bits that userland is allowed to change through sigreturn (CF, PF, AF, ZF, SF, TF,
+/// DF, OF).
+const USER_EFLAGS : u32 = 0x00000dd5;
+
+/// Perform the sigaction syscall. This simply passes the call along to the current process. It
+/// returns the previous action for the signal.
+pub fn sigaction(sig: Signal, act: Option<SigAction>) -> KResult<SigAction> {
+    (current_proc_mut!()).do_sigaction(sig, act)
+}
+
+/// Perform the sigprocmask syscall on the current thread. It returns the previous mask.
+pub fn sigprocmask(how: SigHow, set: Option<SigSet>) -> KResult<SigSet> {
+    let thr = current_thread!();
+    let old = thr.sigmask;
+    if let Some(s) = set {
+        let s = s - UNBLOCKABLE;
+        thr.sigmask = match how {
+            SigHow::Block   => old | s,
+            SigHow::Unblock => old - s,
+            SigHow::SetMask => s,
+        };
+        dbg!(debug::SIGNAL, "mask of {:?} changed from {:?} to {:?}", thr, old, thr.sigmask);
+        // Anything we just unblocked that was waiting on the process can be taken now.
+        (current_proc_mut!()).redeliver_pending();
+    }
+    Ok(old)
+}
+
+/// Perform the sigreturn syscall. `r` are the registers the restorer trapped in with, these are
+/// replaced by the ones saved in the signal frame.
+pub fn sigreturn(r: &mut Registers) -> KResult<()> {
+    let addr = (r.useresp as usize).wrapping_sub(mem::size_of::<u32>());
+    if !user_range_ok(addr, mem::size_of::<SigFrame>()) {
+        dbg!(debug::SIGNAL, "sigreturn with bad stack pointer 0x{:x}", r.useresp);
+        return Err(errno::EFAULT);
+    }
+    let frame = unsafe { &*(addr as *const SigFrame) };
+    let thr = current_thread!();
+    thr.sigmask = frame.oldmask - UNBLOCKABLE;
+    // Only take the general purpose registers. The segments and privileged flags stay as they are.
+    r.edi = frame.regs.edi; r.esi = frame.regs.esi; r.ebp = frame.regs.ebp;
+    r.ebx = frame.regs.ebx; r.edx = frame.regs.edx; r.ecx = frame.regs.ecx; r.eax = frame.regs.eax;
+    r.eip = frame.regs.eip; r.useresp = frame.regs.useresp;
+    r.eflags = (r.eflags & !USER_EFLAGS) | (frame.regs.eflags & USER_EFLAGS);
+    dbg!(debug::SIGNAL, "{:?} returned from signal handler to 0x{:x}", thr, r.eip);
+    (current_proc_mut!()).redeliver_pending();
+    Ok(())
+}
+
+fn user_range_ok(addr: usize, len: usize) -> bool {
+    addr >= user::MEM_LOW && addr < user::MEM_HIGH && user::MEM_HIGH - addr >= len
+}
+
+/// Called whenever we are about to return to user mode. This takes care of exiting if we were
+/// cancelled, sleeping while the process is stopped and running any pending signal handlers.
+pub fn handle_pending(r: &mut Registers) {
+    let thr = current_thread!();
+    loop {
+        if thr.cancelled {
+            thr.exit(thr.retval);
+        }
+        if !(current_proc!()).is_stopped() {
+            break;
+        }
+        (current_proc!()).wait_while_stopped();
+    }
+    (current_proc_mut!()).redeliver_pending();
+    let sig = match (thr.sigpending - thr.sigmask).first() {
+        Some(s) => s,
+        None => { return; },
+    };
+    thr.sigpending.remove(sig);
+    let act = (current_proc!()).get_sigaction(sig);
+    match act.handler {
+        SIG_IGN => { return; },
+        SIG_DFL => { (current_proc_mut!()).do_default_action(sig); return; },
+        _ => {},
+    }
+
+    let size = mem::size_of::<SigFrame>();
+    let addr = ((r.useresp as usize).wrapping_sub(size)) & !0xf;
+    if !user_range_ok(addr, size) {
+        dbg!(debug::SIGNAL, "Unable to push signal frame for {} at 0x{:x}, killing {:?}", sig, addr, current_proc!());
+        (current_proc_mut!()).do_default_action(SIGSEGV);
+        return;
+    }
+    unsafe {
+        *(addr as *mut SigFrame) = SigFrame {
+            ret_addr : act.restorer as u32,
+            signo    : sig,
+            oldmask  : thr.sigmask,
+            regs     : r.clone(),
+        };
+    }
+    let mut blocked = thr.sigmask | act.mask;
+    if act.flags & SA_NODEFER == 0 {
+        blocked.add(sig);
+    }
+    thr.sigmask = blocked - UNBLOCKABLE;
+    if act.flags & SA_RESETHAND != 0 {
+        (current_proc_mut!()).reset_sigaction(sig);
+    }
+    dbg!(debug::SIGNAL, "Delivering signal {} to {:?} with handler 0x{:x}", sig, thr, act.handler);
+    r.useresp = addr as u32;
+    r.eip = act.handler as u32;
+}
+
+/// Send the current thread a signal caused by what it just did, such as SIGSEGV for a bad memory
+/// access. Going back to user mode without handling it would only do the same thing again, so if
+/// the thread has it blocked or would not run a handler for it the process is killed with it.
+pub fn force_signal(sig: Signal) {
+    let thr = current_thread!();
+    let handler = (current_proc!()).get_sigaction(sig).handler;
+    if thr.sigmask.contains(sig) || handler == SIG_IGN || handler == SIG_DFL {
+        dbg!(debug::SIGNAL, "{:?} cannot handle signal {}, killing {:?}", thr, sig, current_proc!());
+        (current_proc_mut!()).do_default_action(sig);
+    } else {
+        thr.add_signal(sig);
+    }
+}
+
+/// Perform the kill syscall, sending `sig` to the process `pid`. A `sig` of 0 only checks that the
+/// process exists.
+pub fn kill(pid: ProcId, sig: Signal) -> KResult<()> {
+    if sig != 0 && !is_valid(sig) {
+        return Err(errno::EINVAL);
+    }
+    if pid == current_pid!() {
+        if sig != 0 { (current_proc_mut!()).post_signal(sig); }
+        return Ok(());
+    }
+    let p = try!(KProc::get_proc(&pid).ok_or_else(|| {
+        dbg!(debug::SIGNAL, "Attempt to signal nonexistent process {:?}", pid);
+        errno::ESRCH
+    }));
+    if sig != 0 {
+        p.borrow_mut().post_signal(sig);
+    }
+    Ok(())
+}
+
+/// Perform the kill syscall on a process group, sending `sig` to every process in `pgid`. This is
+/// also used for signals generated by terminals so it does not wait for a process that is busy being
+/// looked at, the signal is deferred until it is free instead.
+pub fn killpg(pgid: ProcId, sig: Signal) -> KResult<()> {
+    if sig != 0 && !is_valid(sig) {
+        return Err(errno::EINVAL);
+    }
+    let me = current_pid!();
+    let mut found = false;
+    let mut hit_self = false;
+    KProc::each_proc(|pid, p| {
+        if pid == me {
+            let cur = current_proc!();
+            hit_self = cur.get_pgid() == pgid && !cur.is_dead();
+            return;
+        }
+        // Whoever has it borrowed will not change its group or finish exiting while we look.
+        let member = {
+            let q = unsafe { &*p.as_unsafe_cell().get() };
+            q.get_pgid() == pgid && !q.is_dead()
+        };
+        if member {
+            found = true;
+            if sig != 0 { KProc::signal_or_defer(p, sig); }
+        }
+    });
+    // We go last since we might not come back.
+    if hit_self {
+        if sig != 0 { (current_proc_mut!()).post_signal(sig); }
+        return Ok(());
+    }
+    if found { Ok(()) } else { Err(errno::ESRCH) }
+}
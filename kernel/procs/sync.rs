@@ -2,10 +2,12 @@ use std::sync::atomic::*;
 use std::sync::atomic::Ordering::SeqCst;
 use std::cell::UnsafeCell;
 use std::ops::{DerefMut, Deref};
+use std::fmt;
 
 pub use kmutex::KMutex;
 pub use kqueue::WQueue;
 use kthread;
+use lockdep;
 
 /// A type where you can send a signal on. This is usually paired with Wait.
 pub trait Wakeup {
@@ -44,22 +46,34 @@ impl SMutex {
             wqueue: WQueue::new(),
         }
     }
+    /// A mutex that is handed to whoever has been waiting for it longest when unlocked.
+    pub fn new_fair(name: &'static str) -> SMutex {
+        SMutex {
+            inner: KMutex::new_fair(name),
+            wqueue: WQueue::new(),
+        }
+    }
+    #[cfg_attr(LOCKDEP, inline(always))]
     fn unlock(&self) { self.inner.unlock(); }
-    fn wait(&self) -> Result<(),()> {
+    fn wait(&self) -> Result<(),()> { self.wait_on(&self.wqueue) }
+    /// Release the lock while sleeping on `queue`, getting it back before returning.
+    fn wait_on(&self, queue: &WQueue) -> Result<(),()> {
         block_interrupts!({
-            dbg!(debug::SCHED, "{:?} going to sleep on mutex {:?} with queue {:?}", current_proc!(), self.inner, self.wqueue);
+            dbg!(debug::SCHED, "{:?} going to sleep on mutex {:?} with queue {:?}", current_proc!(), self.inner, queue);
             let x = self.inner.unlock_all();
-            let res = self.wqueue.wait();
+            let res = queue.wait();
             // Even if we failed this lock still needs to be valid.
             self.inner.relock_all(x);
             res
         })
     }
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn force_lock<'a>(&'a self) -> SGuard<'a> {
         self.inner.lock_nocancel();
         SGuard { lock: self }
     }
 
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn lock<'a>(&'a self) -> Result<SGuard<'a>, ()> {
         if self.inner.lock() {
             Ok(SGuard { lock: self })
@@ -68,6 +82,7 @@ impl SMutex {
         }
     }
 
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn try_lock<'a>(&'a self) -> Option<SGuard<'a>> {
         if self.inner.try_lock() {
             Some(SGuard { lock: self })
@@ -81,8 +96,13 @@ impl Wakeup for SMutex {
     fn signal(&self) { dbg!(debug::SCHED, "sending wakeup on {:?} with {:?}", self.inner, self.wqueue); self.wqueue.signal(); }
 }
 
+impl WakeupOne for SMutex {
+    fn signal_one(&self) -> bool { self.wqueue.signal_one() }
+}
+
 #[unsafe_destructor]
 impl<'a> Drop for SGuard<'a> {
+    #[cfg_attr(LOCKDEP, inline(always))]
     fn drop(&mut self) {
         self.lock.unlock();
     }
@@ -110,6 +130,14 @@ impl<T> Mutex<T> {
             _data: UnsafeCell::new(data),
         }
     }
+    /// A mutex that is handed to whoever has been waiting for it longest when unlocked.
+    pub fn new_fair(name: &'static str, data: T) -> Mutex<T> {
+        Mutex {
+            _lock: SMutex::new_fair(name),
+            _data: UnsafeCell::new(data),
+        }
+    }
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn lock<'a>(&'a self) -> Result<MGuard<'a, T>, ()> {
         let g = try!(self._lock.lock());
         Ok(MGuard {
@@ -118,6 +146,7 @@ impl<T> Mutex<T> {
         })
     }
 
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn force_lock<'a>(&'a self) -> MGuard<'a, T> {
         let l = self._lock.force_lock();
         MGuard {
@@ -126,6 +155,7 @@ impl<T> Mutex<T> {
         }
     }
 
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn try_lock<'a>(&'a self) -> Option<MGuard<'a, T>> {
         self._lock.try_lock()
                   .map(|t| { MGuard {
@@ -140,6 +170,10 @@ impl<T> Wakeup for Mutex<T> {
     fn signal(&self) { self._lock.signal(); }
 }
 
+impl<T> WakeupOne for Mutex<T> {
+    fn signal_one(&self) -> bool { self._lock.signal_one() }
+}
+
 impl<'a, T> Deref for MGuard<'a, T> {
     type Target = T;
     fn deref<'b>(&'b self) -> &'b T { &*self._data }
@@ -241,6 +275,303 @@ impl<T> Wakeup for CondMutex<T> {
     fn signal(&self) { self.mtx.signal(); }
 }
 
+/// A guard for a held `SMutex`, which a `Condvar` can release while waiting.
+pub trait HeldLock {
+    fn held_mutex<'a>(&'a self) -> &'a SMutex;
+}
+
+impl<'a> HeldLock for SGuard<'a> {
+    fn held_mutex<'b>(&'b self) -> &'b SMutex { self.lock }
+}
+
+impl<'a, T: 'a> HeldLock for MGuard<'a, T> {
+    fn held_mutex<'b>(&'b self) -> &'b SMutex { self._lock.lock }
+}
+
+/// A condition variable that can be used with any held mutex. Unlike `CondMutex` the condition is
+/// up to the caller.
+pub struct Condvar { queue: WQueue, }
+
+impl Condvar {
+    pub fn new() -> Condvar { Condvar { queue: WQueue::new() } }
+
+    /// Release the lock held by `guard` and sleep until notified, getting the lock back before
+    /// returning. Fails if we were cancelled or signaled, the lock is still held either way.
+    pub fn wait<G: HeldLock>(&self, guard: &G) -> Result<(),()> {
+        guard.held_mutex().wait_on(&self.queue)
+    }
+
+    /// Wait until `cond` is true of the guarded data.
+    pub fn wait_while<'a, T, F>(&self, guard: &MGuard<'a, T>, cond: F) -> Result<(),()> where F: Fn(&T) -> bool {
+        while cond(guard.deref()) {
+            try!(self.wait(guard));
+        }
+        Ok(())
+    }
+
+    /// Wake up one waiter. Returns true if there was one.
+    pub fn notify_one(&self) -> bool { self.queue.signal_one() }
+
+    /// Wake up everyone waiting.
+    pub fn notify_all(&self) { self.queue.signal() }
+}
+
+/// A counting semaphore.
+pub struct Semaphore {
+    count : UnsafeCell<usize>,
+    queue : WQueue,
+}
+
+impl Semaphore {
+    pub fn new(count: usize) -> Semaphore { Semaphore { count: UnsafeCell::new(count), queue: WQueue::new() } }
+
+    #[inline]
+    fn count<'a>(&'a self) -> &'a mut usize { unsafe { &mut *self.count.get() } }
+
+    /// The current count.
+    pub fn value(&self) -> usize { *self.count() }
+
+    /// Take one from the count, sleeping until it is not zero. Fails if we are cancelled or
+    /// signaled first.
+    pub fn down(&self) -> Result<(),()> {
+        block_interrupts!({
+            let mut res = Ok(());
+            while *self.count() == 0 {
+                if self.queue.wait().is_err() {
+                    res = Err(());
+                    break;
+                }
+            }
+            if res.is_ok() {
+                *self.count() -= 1;
+            } else if *self.count() > 0 {
+                // We might have been woken by up() just as we were interrupted, so pass it on.
+                self.queue.signal_one();
+            }
+            res
+        })
+    }
+
+    /// Take one from the count, ignoring cancellation.
+    pub fn force_down(&self) {
+        block_interrupts!({
+            while *self.count() == 0 {
+                let _ = self.queue.force_wait();
+            }
+            *self.count() -= 1;
+        })
+    }
+
+    /// Take one from the count if it is not zero. Never sleeps.
+    pub fn try_down(&self) -> bool {
+        block_interrupts!({
+            if *self.count() == 0 { false } else { *self.count() -= 1; true }
+        })
+    }
+
+    /// Add one to the count, waking up a waiter. This may be called from interrupt handlers.
+    pub fn up(&self) {
+        block_interrupts!({
+            *self.count() += 1;
+            self.queue.signal_one();
+        })
+    }
+}
+
+impl Wait<(),()> for Semaphore {
+    fn wait(&self) -> Result<(),()> { self.down() }
+}
+
+impl Wakeup for Semaphore {
+    fn signal(&self) { self.up() }
+}
+
+struct RwState {
+    readers : usize,
+    writer  : bool,
+    waiting_writers : usize,
+}
+
+/// A reader-writer lock holding some data. Any number of readers or a single writer may hold it at
+/// once. Waiting writers go before new readers so that a stream of readers cannot starve them.
+/// Unlike `Mutex` this is not re-entrant, a thread taking a lock it already holds deadlocks.
+pub struct RwLock<T> {
+    name    : &'static str,
+    state   : UnsafeCell<RwState>,
+    readq   : WQueue,
+    writeq  : WQueue,
+    _data   : UnsafeCell<T>,
+}
+
+/// A RAII guard giving shared access to the data in an `RwLock`.
+pub struct RGuard<'a, T: 'a> { lock: &'a RwLock<T>, }
+
+/// A RAII guard giving exclusive access to the data in an `RwLock`.
+pub struct WGuard<'a, T: 'a> { lock: &'a RwLock<T>, }
+
+impl<T> RwLock<T> {
+    pub fn new(name: &'static str, data: T) -> RwLock<T> {
+        RwLock {
+            name: name,
+            state: UnsafeCell::new(RwState { readers: 0, writer: false, waiting_writers: 0 }),
+            readq: WQueue::new(),
+            writeq: WQueue::new(),
+            _data: UnsafeCell::new(data),
+        }
+    }
+
+    #[inline]
+    fn state<'a>(&'a self) -> &'a mut RwState { unsafe { &mut *self.state.get() } }
+
+    fn read_inner(&self, cancelable: bool) -> Result<(),()> {
+        block_interrupts!({
+            let mut res = Ok(());
+            while self.state().writer || self.state().waiting_writers != 0 {
+                let r = if cancelable { self.readq.wait() } else { self.readq.force_wait() };
+                if r.is_err() && cancelable {
+                    res = Err(());
+                    break;
+                }
+            }
+            if res.is_ok() { self.state().readers += 1; }
+            res
+        })
+    }
+
+    fn write_inner(&self, cancelable: bool) -> Result<(),()> {
+        block_interrupts!({
+            let mut res = Ok(());
+            self.state().waiting_writers += 1;
+            while self.state().writer || self.state().readers != 0 {
+                let r = if cancelable { self.writeq.wait() } else { self.writeq.force_wait() };
+                if r.is_err() && cancelable {
+                    res = Err(());
+                    break;
+                }
+            }
+            self.state().waiting_writers -= 1;
+            if res.is_ok() {
+                self.state().writer = true;
+            } else {
+                // We might have been the one woken up, or the writer readers were waiting on.
+                self.wake_next();
+            }
+            res
+        })
+    }
+
+    /// Let whoever should go next know the lock might be free.
+    fn wake_next(&self) {
+        let st = self.state();
+        if st.writer {
+            return;
+        }
+        if st.waiting_writers != 0 {
+            if st.readers == 0 { self.writeq.signal_one(); }
+        } else {
+            self.readq.signal();
+        }
+    }
+
+    fn read_unlock(&self) {
+        block_interrupts!({
+            assert!(self.state().readers != 0, "Read unlock of {} which is not read locked", self.name);
+            self.state().readers -= 1;
+            self.wake_next();
+        })
+    }
+
+    fn write_unlock(&self) {
+        block_interrupts!({
+            assert!(self.state().writer, "Write unlock of {} which is not write locked", self.name);
+            self.state().writer = false;
+            self.wake_next();
+        })
+    }
+
+    /// Get shared access, failing if we are cancelled or signaled first.
+    pub fn read<'a>(&'a self) -> Result<RGuard<'a, T>, ()> {
+        try!(self.read_inner(true));
+        Ok(RGuard { lock: self })
+    }
+
+    /// Get shared access, ignoring cancellation.
+    pub fn force_read<'a>(&'a self) -> RGuard<'a, T> {
+        let _ = self.read_inner(false);
+        RGuard { lock: self }
+    }
+
+    /// Get shared access if we can do so without sleeping.
+    pub fn try_read<'a>(&'a self) -> Option<RGuard<'a, T>> {
+        block_interrupts!({
+            let st = self.state();
+            if st.writer || st.waiting_writers != 0 {
+                None
+            } else {
+                st.readers += 1;
+                Some(RGuard { lock: self })
+            }
+        })
+    }
+
+    /// Get exclusive access, failing if we are cancelled or signaled first.
+    pub fn write<'a>(&'a self) -> Result<WGuard<'a, T>, ()> {
+        try!(self.write_inner(true));
+        Ok(WGuard { lock: self })
+    }
+
+    /// Get exclusive access, ignoring cancellation.
+    pub fn force_write<'a>(&'a self) -> WGuard<'a, T> {
+        let _ = self.write_inner(false);
+        WGuard { lock: self }
+    }
+
+    /// Get exclusive access if we can do so without sleeping.
+    pub fn try_write<'a>(&'a self) -> Option<WGuard<'a, T>> {
+        block_interrupts!({
+            let st = self.state();
+            if st.writer || st.readers != 0 {
+                None
+            } else {
+                st.writer = true;
+                Some(WGuard { lock: self })
+            }
+        })
+    }
+}
+
+impl<T> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let st = self.state();
+        write!(f, "RwLock '{}' {{ readers: {}, writer: {}, waiting_writers: {} }}",
+               self.name, st.readers, st.writer, st.waiting_writers)
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T: 'a> Drop for RGuard<'a, T> {
+    fn drop(&mut self) { self.lock.read_unlock(); }
+}
+
+#[unsafe_destructor]
+impl<'a, T: 'a> Drop for WGuard<'a, T> {
+    fn drop(&mut self) { self.lock.write_unlock(); }
+}
+
+impl<'a, T> Deref for RGuard<'a, T> {
+    type Target = T;
+    fn deref<'b>(&'b self) -> &'b T { unsafe { &*self.lock._data.get() } }
+}
+
+impl<'a, T> Deref for WGuard<'a, T> {
+    type Target = T;
+    fn deref<'b>(&'b self) -> &'b T { unsafe { &*self.lock._data.get() } }
+}
+
+impl<'a, T> DerefMut for WGuard<'a, T> {
+    fn deref_mut<'b>(&'b mut self) -> &'b mut T { unsafe { &mut *self.lock._data.get() } }
+}
+
 /// An RAII Based mutex with auto-unlocking.
 /// This uses spin-lock semantics for locking in interrupt handlers.
 pub struct SpinLock { inner: AtomicBool, }
@@ -255,17 +586,25 @@ impl SpinLock {
     }
 
     /// Do the real unlock operation.
-    fn unlock(&self) { assert!(self.inner.swap(false, SeqCst)); }
+    fn unlock(&self) {
+        assert!(self.inner.swap(false, SeqCst));
+        lockdep::spin_released(self as *const SpinLock as usize);
+    }
 
     /// Lock the spin lock.
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn force_lock<'a>(&'a self) -> SpinGuard<'a> {
+        let site = lockdep::here();
         while self.inner.compare_and_swap(false, true, SeqCst) { kthread::kyield(); }
+        lockdep::spin_acquired(self as *const SpinLock as usize, site);
         SpinGuard { lock: self }
     }
 
     /// Try to lock the spin lock, will fail if we are canceled before we get the lock.
-    #[inline]
+    #[cfg_attr(not(LOCKDEP), inline)]
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn lock<'a>(&'a self) -> Result<SpinGuard<'a>, ()> {
+        let site = lockdep::here();
         while self.inner.compare_and_swap(false, true, SeqCst) {
             if (current_thread!()).cancelled {
                 return Err(());
@@ -273,12 +612,16 @@ impl SpinLock {
                 kthread::kyield();
             }
         }
+        lockdep::spin_acquired(self as *const SpinLock as usize, site);
         Ok(SpinGuard { lock: self })
     }
 
     /// Try to lock the spin lock, will return None if the lock is already held.
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn try_lock<'a>(&'a self) -> Option<SpinGuard<'a>> {
+        let site = lockdep::here();
         if !self.inner.compare_and_swap(false, true, SeqCst) {
+            lockdep::spin_acquired(self as *const SpinLock as usize, site);
             Some(SpinGuard { lock: self })
         } else {
             None
@@ -312,6 +655,7 @@ impl<T> SpinMutex<T> {
             _data: UnsafeCell::new(data),
         }
     }
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn lock<'a>(&'a self) -> Result<SMGuard<'a, T>, ()> {
         let g = try!(self._lock.lock());
         Ok(SMGuard {
@@ -320,6 +664,7 @@ impl<T> SpinMutex<T> {
         })
     }
 
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn force_lock<'a>(&'a self) -> SMGuard<'a, T> {
         let l = self._lock.force_lock();
         SMGuard {
@@ -328,6 +673,7 @@ impl<T> SpinMutex<T> {
         }
     }
 
+    #[cfg_attr(LOCKDEP, inline(always))]
     pub fn try_lock<'a>(&'a self) -> Option<SMGuard<'a, T>> {
         self._lock.try_lock()
                   .map(|t| { SMGuard {
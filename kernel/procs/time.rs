@@ -0,0 +1,295 @@
+// TODO Copyright Header
+
+//! Keeping time.
+//!
+//! The local APIC timer interrupts us `HZ` times a second. Every interrupt adds one to a monotonic
+//! tick count and runs any timers whose deadline has come. Wall-clock time is the time the CMOS
+//! clock said it was at boot plus however long we have been up.
+//!
+//! Timers are owned by whoever set them, we only keep pointers to them, so that nothing is ever
+//! allocated or freed from inside the interrupt handler. A timer must be cancelled before it goes
+//! away. Their callbacks run in interrupt context so they must be short and must not block.
+
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
+use std::sync::atomic::Ordering::SeqCst;
+use std::fmt;
+use base::errno::{self, KResult};
+use startup::rtc;
+use interrupt::{self, Registers};
+use kthread::KThread;
+use kproc::{KProc, ProcId, PidInner};
+use signal;
+use preempt;
+use resource;
+use kqueue::WQueue;
+use super::apic;
+
+/// How many timer interrupts we get a second.
+pub const HZ : u32 = 100;
+const NSEC_PER_SEC  : u64 = 1000000000;
+const USEC_PER_SEC  : u64 = 1000000;
+const NSEC_PER_TICK : u64 = NSEC_PER_SEC / (HZ as u64);
+
+/// The number of ticks since boot. This is only a usize since we have no 64 bit atomics, at 100 Hz
+/// it will last for well over a year.
+static TICKS : AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Pending timers sorted with the soonest deadline at the end. It is only touched with interrupts
+/// blocked. There is always room for one more so a callback can re-arm its timer without
+/// allocating.
+static mut TIMERS : *mut Vec<*mut Timer> = 0 as *mut Vec<*mut Timer>;
+
+pub fn init_stage1() {
+    assert!(interrupt::register(interrupt::APICTIMER, timer_intr_handler).is_none());
+}
+
+pub fn init_stage2() {
+    unsafe { TIMERS = box Vec::with_capacity(16) as *mut Vec<*mut Timer>; }
+    dbg!(debug::SCHED, "Starting the APIC timer at {} Hz", HZ);
+    unsafe { apic::enable_periodic_timer(HZ); }
+}
+
+extern "Rust" fn timer_intr_handler(r: &mut Registers) {
+    // This is not an ioapic redirected interrupt so _rust_intr_handler will not do this for us. It
+    // must happen before we might switch away on the way out.
+    unsafe { apic::set_eoi(); }
+    let now = (TICKS.fetch_add(1, SeqCst) + 1) as u64;
+    run_timers(now);
+    resource::charge_tick((r.cs & 3) == 3);
+    if cfg!(UPREEMPT) {
+        preempt::tick();
+    }
+}
+
+fn timers() -> &'static mut Vec<*mut Timer> { unsafe { &mut *TIMERS } }
+
+fn run_timers(now: u64) {
+    loop {
+        let t = match timers().last() {
+            Some(&t) if unsafe { (*t).deadline } <= now => t,
+            _ => return,
+        };
+        timers().pop();
+        let t = unsafe { &mut *t };
+        t.pending = false;
+        t.triggered = (t.func)(t.data);
+    }
+}
+
+/// The number of timer ticks since boot.
+pub fn ticks() -> u64 { TICKS.load(SeqCst) as u64 }
+
+/// A callback to run at some tick. `func` is given `data` and returns whether it actually did
+/// anything, which is what `triggered` reports afterwards.
+pub struct Timer {
+    deadline  : u64,
+    func      : fn(usize) -> bool,
+    data      : usize,
+    pending   : bool,
+    triggered : bool,
+}
+
+impl Timer {
+    pub fn new(func: fn(usize) -> bool, data: usize) -> Timer {
+        Timer { deadline: 0, func: func, data: data, pending: false, triggered: false }
+    }
+
+    /// Have the callback run once the tick count reaches `deadline`, replacing any earlier time
+    /// this was set for.
+    pub fn set(&mut self, deadline: u64) {
+        block_interrupts!({
+            self.cancel();
+            self.deadline = deadline;
+            self.triggered = false;
+            self.pending = true;
+            let me = self as *mut Timer;
+            let lst = timers();
+            let pos = lst.iter().position(|&t| unsafe { (*t).deadline } <= deadline).unwrap_or(lst.len());
+            lst.insert(pos, me);
+            lst.reserve(1);
+        })
+    }
+
+    /// Make sure the callback does not run. Returns true if it had not run yet.
+    pub fn cancel(&mut self) -> bool {
+        block_interrupts!({
+            if !self.pending {
+                false
+            } else {
+                let me = self as *mut Timer;
+                let lst = timers();
+                let pos = lst.iter().position(|&t| t == me).expect("pending timer not in the list");
+                lst.remove(pos);
+                self.pending = false;
+                true
+            }
+        })
+    }
+
+    /// Whether the callback is still waiting to run.
+    pub fn is_pending(&self) -> bool { self.pending }
+
+    /// Whether the callback ran and did something.
+    pub fn triggered(&self) -> bool { self.triggered }
+
+    /// The tick this is, or was last, set to go off at.
+    pub fn deadline(&self) -> u64 { self.deadline }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) { self.cancel(); }
+}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timer {{ deadline: {}, pending: {}, triggered: {} }}", self.deadline, self.pending, self.triggered)
+    }
+}
+
+/// A timer callback that wakes up the given thread if it is still asleep on a queue.
+pub fn wake_thread(t: usize) -> bool {
+    let t = unsafe { &mut *(t as *mut KThread) };
+    match unsafe { t.queue.as_mut() } {
+        Some(q) => {
+            dbg!(debug::SCHED, "Timed out waiting, waking {:?}", t);
+            q.remove(t);
+            t.make_runable();
+            true
+        },
+        None => false,
+    }
+}
+
+/// A point in time, or an amount of it, as used by nanosleep and clock_gettime.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timespec {
+    pub tv_sec  : u64,
+    pub tv_nsec : u64,
+}
+
+/// A point in time as used by gettimeofday.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeval {
+    pub tv_sec  : u64,
+    pub tv_usec : u64,
+}
+
+impl Timespec {
+    pub fn from_ticks(t: u64) -> Timespec {
+        Timespec { tv_sec: t / (HZ as u64), tv_nsec: (t % (HZ as u64)) * NSEC_PER_TICK }
+    }
+
+    /// The number of whole ticks needed to cover this much time, rounding up.
+    pub fn to_ticks(&self) -> u64 {
+        self.tv_sec * (HZ as u64) + (self.tv_nsec + NSEC_PER_TICK - 1) / NSEC_PER_TICK
+    }
+
+    /// Whether the nanoseconds are less than a second, as they must be.
+    pub fn is_valid(&self) -> bool { self.tv_nsec < NSEC_PER_SEC }
+}
+
+pub type ClockId = u32;
+pub const CLOCK_REALTIME  : ClockId = 0;
+pub const CLOCK_MONOTONIC : ClockId = 1;
+
+/// The time since boot.
+pub fn monotonic() -> Timespec { Timespec::from_ticks(ticks()) }
+
+/// The wall-clock time.
+pub fn realtime() -> Timespec {
+    let mut t = monotonic();
+    t.tv_sec += rtc::boot_time();
+    t
+}
+
+/// Perform the clock_gettime syscall.
+pub fn clock_gettime(clock: ClockId) -> KResult<Timespec> {
+    match clock {
+        CLOCK_REALTIME => Ok(realtime()),
+        CLOCK_MONOTONIC => Ok(monotonic()),
+        _ => Err(errno::EINVAL),
+    }
+}
+
+/// Perform the gettimeofday syscall. We have no idea about time zones so there is no argument for
+/// one.
+pub fn gettimeofday() -> Timeval {
+    let t = realtime();
+    Timeval { tv_sec: t.tv_sec, tv_usec: t.tv_nsec / (NSEC_PER_SEC / USEC_PER_SEC) }
+}
+
+/// Sleep until the tick count reaches `deadline`. Returns EINTR or ECANCELED if woken early by a
+/// signal or cancellation.
+pub fn sleep_until(deadline: u64) -> KResult<()> {
+    let q = WQueue::new();
+    match q.wait_until(deadline) {
+        Err(errno::ETIMEDOUT) => Ok(()),
+        Ok(_) => kpanic!("Nothing should signal a private sleep queue"),
+        Err(e) => Err(e),
+    }
+}
+
+/// Perform the nanosleep syscall. If interrupted the time left is put in `rem`, if given.
+pub fn nanosleep(req: &Timespec, rem: Option<&mut Timespec>) -> KResult<()> {
+    if !req.is_valid() {
+        return Err(errno::EINVAL);
+    }
+    let deadline = ticks() + req.to_ticks();
+    let res = sleep_until(deadline);
+    if let (Err(_), Some(r)) = (res, rem) {
+        let now = ticks();
+        *r = Timespec::from_ticks(if deadline > now { deadline - now } else { 0 });
+    }
+    res
+}
+
+/// The callback for alarm timers. The data is the pid to send SIGALRM to.
+fn send_alarm(pid: usize) -> bool {
+    let pid = ProcId(pid as PidInner);
+    match KProc::get_proc(&pid) {
+        Some(p) => {
+            match p.try_borrow_mut() {
+                Some(mut p) => { p.post_signal(signal::SIGALRM); true },
+                None => {
+                    // Someone is in the middle of using the process so try again next tick. We
+                    // cannot borrow it to find the timer, but the timer is boxed so it stays put.
+                    let t = unsafe { (*p.as_unsafe_cell().get()).alarm_timer() };
+                    unsafe { (*t).set(ticks() + 1); }
+                    false
+                },
+            }
+        },
+        None => false,
+    }
+}
+
+/// Make a timer that will send SIGALRM to the given process.
+pub fn alarm_timer(pid: ProcId) -> Timer {
+    let ProcId(p) = pid;
+    Timer::new(send_alarm, p as usize)
+}
+
+/// Perform the alarm syscall. SIGALRM is sent to the current process after `seconds` seconds,
+/// replacing any alarm that is already set. 0 just cancels the old one. Returns the number of
+/// seconds that were left on the old alarm.
+pub fn alarm(seconds: u32) -> u32 {
+    let t = (current_proc!()).alarm_timer();
+    let t = unsafe { &mut *t };
+    let left = block_interrupts!({
+        let now = ticks();
+        let left = if t.cancel() && t.deadline() > now {
+            // Round up so that an alarm that is still pending is never reported as 0.
+            ((t.deadline() - now + (HZ as u64) - 1) / (HZ as u64)) as u32
+        } else {
+            0
+        };
+        if seconds != 0 {
+            t.set(now + (seconds as u64) * (HZ as u64));
+        }
+        left
+    });
+    dbg!(debug::PROC, "alarm({}) for {:?}, {} seconds were left", seconds, current_proc!(), left);
+    left
+}
@@ -0,0 +1,78 @@
+// TODO Copyright Header
+
+//! Thread local storage for user threads.
+//!
+//! Each thread may have one segment of its own, the `gdt::USER_TLS` entry, which user code reaches
+//! through %fs. The entry is swapped when the thread is switched to, so libc can keep `errno` and
+//! pthread specific data at fixed offsets from %fs and have each thread see its own copy.
+
+use base::errno::{self, KResult};
+use mm::page;
+use startup::gdt;
+
+/// A request to set up a TLS segment, laid out like `struct user_desc`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserDesc {
+    /// Which GDT entry to use. Passing `!0` asks us to pick one and we fill it in.
+    pub entry_number : u32,
+    pub base_addr    : u32,
+    pub limit        : u32,
+    pub flags        : u32,
+}
+
+/// The segment is 32 bit. We do not support anything else.
+pub const SEG_32BIT       : u32 = 0x01;
+/// Two bits for what the segment holds. Only data, which is 0, is supported.
+pub const CONTENTS_MASK   : u32 = 0x06;
+pub const READ_EXEC_ONLY  : u32 = 0x08;
+/// The limit is in pages rather than bytes.
+pub const LIMIT_IN_PAGES  : u32 = 0x10;
+/// Get rid of the segment.
+pub const SEG_NOT_PRESENT : u32 = 0x20;
+pub const USEABLE         : u32 = 0x40;
+
+/// The only entry number user threads can have.
+pub const TLS_ENTRY : u32 = gdt::USER_TLS / 8;
+
+/// The largest limit a segment can have, in pages.
+const MAX_LIMIT : u32 = 0xFFFFF;
+
+/// Perform the set_thread_area syscall. The segment described by `desc` becomes the current
+/// thread's TLS segment and `desc.entry_number` is set to the entry to load into %fs, as
+/// `(entry << 3) | 3`. Segments are always sized in pages so a byte limit is rounded up.
+pub fn set_thread_area(desc: &mut UserDesc) -> KResult<()> {
+    if desc.entry_number != !0 && desc.entry_number != TLS_ENTRY {
+        return Err(errno::EINVAL);
+    }
+    let tls = if desc.flags & SEG_NOT_PRESENT != 0 {
+        None
+    } else {
+        if desc.flags & SEG_32BIT == 0 || desc.flags & (CONTENTS_MASK | READ_EXEC_ONLY) != 0 {
+            return Err(errno::EINVAL);
+        }
+        let limit = if desc.flags & LIMIT_IN_PAGES != 0 { desc.limit } else { desc.limit / (page::SIZE as u32) };
+        if limit > MAX_LIMIT {
+            return Err(errno::EINVAL);
+        }
+        Some((desc.base_addr as usize, limit))
+    };
+    desc.entry_number = TLS_ENTRY;
+    dbg!(debug::THR, "{:?} set its TLS segment to {:?}", current_thread!(), tls);
+    (current_thread!()).ctx.set_tls(tls);
+    Ok(())
+}
+
+/// Perform the get_thread_area syscall. `desc.entry_number` must name the TLS entry and the rest
+/// is filled in from the current thread's segment.
+pub fn get_thread_area(desc: &mut UserDesc) -> KResult<()> {
+    if desc.entry_number != TLS_ENTRY {
+        return Err(errno::EINVAL);
+    }
+    *desc = match (current_thread!()).ctx.tls {
+        Some((base, limit)) => UserDesc { entry_number: TLS_ENTRY, base_addr: base as u32, limit: limit,
+                                          flags: SEG_32BIT | LIMIT_IN_PAGES | USEABLE },
+        None => UserDesc { entry_number: TLS_ENTRY, base_addr: 0, limit: 0, flags: SEG_NOT_PRESENT },
+    };
+    Ok(())
+}
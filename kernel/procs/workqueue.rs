@@ -0,0 +1,284 @@
+// TODO Copyright Header
+
+//! Deferred work run by kernel threads.
+//!
+//! Interrupt handlers run at high IPL and may not block, so anything slow they need done is put on
+//! a `WorkQueue` to be run later by one of its worker processes. Like `time::Timer`, a `Work` item is
+//! owned by whoever queues it and the queue only links it in, so queueing never allocates and is
+//! safe to do from interrupt handlers.
+
+use std::cell::UnsafeCell;
+use std::{fmt, ptr};
+use std::mem::transmute;
+use libc::c_void;
+use base::errno::KResult;
+use mm::Allocation;
+use kproc::{self, KProc, ProcId};
+use kqueue::WQueue;
+use signal::SIGKILL;
+use sync::{Wait, Wakeup};
+
+/// How many workers the system queue has.
+pub const SYSTEM_WORKERS : usize = 2;
+
+static mut SYSTEM : *mut WorkQueue = 0 as *mut WorkQueue;
+
+pub fn init_stage1() {}
+pub fn init_stage2() {}
+
+/// Start the system workqueue. This is done by init once it is running, so the workers are its
+/// children and do not take any of the pids the boot code expects.
+pub fn start() -> Allocation<()> {
+    assert!(!is_running(), "system workqueue started twice");
+    let wq = try!(WorkQueue::new("system workqueue", SYSTEM_WORKERS));
+    unsafe { SYSTEM = transmute(wq); }
+    Ok(())
+}
+
+/// Whether the system workqueue has been started. Until it is, those that would queue work from
+/// interrupt handlers have to do it right away instead.
+pub fn is_running() -> bool { unsafe { !SYSTEM.is_null() } }
+
+/// Whether `pid` is one of the system workqueue's workers.
+pub fn is_system_worker(pid: ProcId) -> bool {
+    unsafe { SYSTEM.as_ref() }.map(|wq| wq.inner().workers.contains(&pid)).unwrap_or(false)
+}
+
+/// Stop the system workqueue. Any work still queued is not run. This must be called by whoever
+/// started it, since it reaps the workers.
+pub fn shutdown() {
+    unsafe {
+        if !SYSTEM.is_null() {
+            let wq : Box<WorkQueue> = transmute(SYSTEM);
+            SYSTEM = ptr::null_mut();
+            wq.destroy();
+        }
+    }
+}
+
+/// The workqueue everyone shares.
+pub fn system() -> &'static WorkQueue { unsafe { SYSTEM.as_ref().expect("system workqueue is not running") } }
+
+/// Put `w` on the system workqueue. Returns false if it was already queued or the system workqueue
+/// is not running.
+pub fn queue_work(w: &mut Work) -> bool {
+    match unsafe { SYSTEM.as_ref() } {
+        Some(wq) => wq.queue(w),
+        None => false,
+    }
+}
+
+/// Something to be done later. `func` is called with `data` on one of the queue's workers.
+pub struct Work {
+    func    : fn(usize),
+    data    : usize,
+    next    : *mut Work,
+    pending : bool,
+    running : bool,
+}
+
+impl Work {
+    pub fn new(func: fn(usize), data: usize) -> Work {
+        Work { func: func, data: data, next: ptr::null_mut(), pending: false, running: false }
+    }
+
+    /// Whether this is waiting to be run.
+    pub fn is_pending(&self) -> bool { self.pending }
+
+    /// Whether a worker is running this right now.
+    pub fn is_running(&self) -> bool { self.running }
+}
+
+impl Drop for Work {
+    fn drop(&mut self) {
+        assert!(!self.pending && !self.running, "Work dropped while still queued or running");
+    }
+}
+
+impl fmt::Debug for Work {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Work {{ pending: {}, running: {} }}", self.pending, self.running)
+    }
+}
+
+struct Inner {
+    head    : *mut Work,
+    tail    : *mut Work,
+    running : usize,
+    workers : Vec<ProcId>,
+}
+
+/// A queue of work and the processes that do it.
+pub struct WorkQueue {
+    name    : &'static str,
+    inner   : UnsafeCell<Inner>,
+    /// Where workers wait for something to do.
+    ready   : WQueue,
+    /// Signaled whenever a work item finishes.
+    done    : WQueue,
+}
+
+impl WorkQueue {
+    /// Make a new queue with `nworkers` processes running work from it. The processes are children
+    /// of the current one.
+    pub fn new(name: &'static str, nworkers: usize) -> Allocation<Box<WorkQueue>> {
+        assert!(nworkers > 0);
+        let wq = try!(alloc!(try box WorkQueue {
+            name: name,
+            inner: UnsafeCell::new(Inner { head: ptr::null_mut(), tail: ptr::null_mut(), running: 0, workers: Vec::new() }),
+            ready: WQueue::new(),
+            done: WQueue::new(),
+        }));
+        for i in 0..nworkers {
+            let arg = &*wq as *const WorkQueue as *mut c_void;
+            match KProc::new(format!("{} worker {}", name, i), worker_run, 0, arg) {
+                Ok(pid) => { try!(alloc!(try wq.inner().workers.push(pid))); },
+                Err(e) => {
+                    dbg!(debug::PROC, "Unable to start worker {} for {}", i, name);
+                    wq.destroy();
+                    return Err(e);
+                },
+            }
+        }
+        dbg!(debug::PROC, "started {:?}", wq);
+        Ok(wq)
+    }
+
+    #[inline]
+    fn inner<'a>(&'a self) -> &'a mut Inner { unsafe { transmute(self.inner.get()) } }
+
+    /// Add `w` to the end of the queue. Returns false if it was already queued. This may be called
+    /// from interrupt handlers. `w` must not be moved or dropped until it has run or been cancelled.
+    pub fn queue(&self, w: &mut Work) -> bool {
+        let queued = block_interrupts!({
+            if w.pending {
+                false
+            } else {
+                let me = w as *mut Work;
+                w.pending = true;
+                w.next = ptr::null_mut();
+                let inner = self.inner();
+                match unsafe { inner.tail.as_mut() } {
+                    Some(t) => { t.next = me; },
+                    None => { inner.head = me; },
+                }
+                inner.tail = me;
+                true
+            }
+        });
+        if queued { self.ready.signal(); }
+        queued
+    }
+
+    /// Take `w` off the queue if it has not started running yet. Returns true if it was removed.
+    pub fn cancel(&self, w: &mut Work) -> bool {
+        block_interrupts!({
+            if !w.pending {
+                false
+            } else {
+                let me = w as *mut Work;
+                let inner = self.inner();
+                let mut prev : *mut Work = ptr::null_mut();
+                let mut cur = inner.head;
+                while cur != me {
+                    assert!(!cur.is_null(), "pending work is not on this queue");
+                    prev = cur;
+                    cur = unsafe { (*cur).next };
+                }
+                match unsafe { prev.as_mut() } {
+                    Some(p) => { p.next = w.next; },
+                    None => { inner.head = w.next; },
+                }
+                if inner.tail == me { inner.tail = prev; }
+                w.next = ptr::null_mut();
+                w.pending = false;
+                true
+            }
+        })
+    }
+
+    /// Wait until `w` is neither queued nor running. Returns EINTR or ECANCELED if a signal or
+    /// cancellation cuts the wait short.
+    pub fn flush_work(&self, w: &Work) -> KResult<()> {
+        self.wait_done(|_| { w.pending || w.running })
+    }
+
+    /// Wait until there is nothing left on the queue and no worker is busy.
+    pub fn flush(&self) -> KResult<()> {
+        self.wait_done(|i| { !i.head.is_null() || i.running != 0 })
+    }
+
+    fn wait_done<F>(&self, busy: F) -> KResult<()> where F: Fn(&Inner) -> bool {
+        block_interrupts!({
+            let mut res = Ok(());
+            while busy(self.inner()) {
+                if self.done.wait().is_err() {
+                    res = Err((current_thread!()).interrupted_errno());
+                    break;
+                }
+            }
+            res
+        })
+    }
+
+    /// Stop all the workers and wait for them to exit. Work left on the queue is not run.
+    pub fn destroy(self: Box<WorkQueue>) {
+        dbg!(debug::PROC, "stopping {:?}", self);
+        let workers : Vec<ProcId> = self.inner().workers.drain().collect();
+        for &pid in workers.iter() {
+            if let Some(p) = KProc::get_proc(&pid) {
+                p.borrow_mut().post_signal(SIGKILL);
+            }
+        }
+        for pid in workers.into_iter() {
+            if let Err(e) = KProc::waitpid(kproc::Pid(pid), 0) {
+                dbg!(debug::PROC, "Unable to reap worker {:?}: {:?}", pid, e);
+            }
+        }
+    }
+
+    /// Take the next item off the queue, waiting for one if there is none. Returns None if we were
+    /// cancelled.
+    fn next_work(&self) -> Option<*mut Work> {
+        block_interrupts!({
+            let mut res = None;
+            while !(current_thread!()).cancelled {
+                let inner = self.inner();
+                if let Some(w) = unsafe { inner.head.as_mut() } {
+                    inner.head = w.next;
+                    if inner.head.is_null() { inner.tail = ptr::null_mut(); }
+                    w.next = ptr::null_mut();
+                    w.pending = false;
+                    w.running = true;
+                    inner.running += 1;
+                    res = Some(w as *mut Work);
+                    break;
+                }
+                let _ = self.ready.wait();
+            }
+            res
+        })
+    }
+}
+
+impl fmt::Debug for WorkQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        block_interrupts!(write!(f, "WorkQueue {{ name: {:?}, workers: {:?}, running: {} }}",
+                                 self.name, self.inner().workers, self.inner().running))
+    }
+}
+
+extern "C" fn worker_run(_: i32, wq: *mut c_void) -> *mut c_void {
+    let wq = unsafe { &*(wq as *const WorkQueue) };
+    while let Some(w) = wq.next_work() {
+        let (func, data) = unsafe { ((*w).func, (*w).data) };
+        func(data);
+        block_interrupts!({
+            unsafe { (*w).running = false; }
+            wq.inner().running -= 1;
+        });
+        wq.done.signal();
+    }
+    dbg!(debug::PROC, "{:?} worker exiting", wq.name);
+    0 as *mut c_void
+}
@@ -27,6 +27,8 @@ pub fn init_stage1() {
     pci::init_stage1();
     acpi::init_stage1();
     tsd::init_stage1();
+    cmdline::init_stage1();
+    rtc::init_stage1();
 
     if cfg!(UPREEMPT) { pit::init_stage1(); }
 }
@@ -36,6 +38,8 @@ pub fn init_stage2() {
     pci::init_stage2();
     acpi::init_stage2();
     tsd::init_stage2();
+    cmdline::init_stage2();
+    rtc::init_stage2();
 
     if cfg!(UPREEMPT) { pit::init_stage2(); }
 }
@@ -60,11 +64,188 @@ pub mod pci {
     pub fn init_stage2() {}
 }
 
+/// The programmable interval timer. The local APIC timer is what actually drives scheduling (it is
+/// calibrated off of PIT channel 2 in apic.c), so all we do here is make sure channel 0 is in a
+/// known state instead of whatever the BIOS left it in.
 #[allow(dead_code)]
 pub mod pit {
+    use base::io;
     pub const INTERRUPT : u8 = 0xf1;
-    pub fn init_stage1() { not_yet_implemented!(pit::init_stage1) }
-    pub fn init_stage2() { not_yet_implemented!(pit::init_stage2) }
+    /// The frequency of the PIT's oscillator in Hz.
+    pub const BASE_FREQUENCY : u32 = 1193182;
+
+    const CHANNEL0 : u16 = 0x40;
+    const COMMAND  : u16 = 0x43;
+    /// Channel 0, lobyte/hibyte access, mode 2 (rate generator).
+    const RATE_GENERATOR : u8 = 0x34;
+
+    /// Have channel 0 fire `hz` times a second.
+    pub fn set_frequency(hz: u32) {
+        assert!(hz > 18 && hz <= BASE_FREQUENCY, "PIT frequency {} is out of range", hz);
+        let div = BASE_FREQUENCY / hz;
+        unsafe {
+            io::outb(COMMAND, RATE_GENERATOR);
+            io::outb(CHANNEL0, (div & 0xff) as u8);
+            io::outb(CHANNEL0, ((div >> 8) & 0xff) as u8);
+        }
+    }
+
+    pub fn init_stage1() { set_frequency(100); }
+    pub fn init_stage2() {}
+}
+
+/// The CMOS real time clock. We only read it once at boot to find out what the wall-clock time is,
+/// after that time is kept by counting timer ticks.
+pub mod rtc {
+    use base::io;
+
+    const ADDRESS : u16 = 0x70;
+    const DATA    : u16 = 0x71;
+
+    const SECONDS  : u8 = 0x00;
+    const MINUTES  : u8 = 0x02;
+    const HOURS    : u8 = 0x04;
+    const DAY      : u8 = 0x07;
+    const MONTH    : u8 = 0x08;
+    const YEAR     : u8 = 0x09;
+    const STATUS_A : u8 = 0x0A;
+    const STATUS_B : u8 = 0x0B;
+
+    /// Set in status A while the clock is in the middle of updating its registers.
+    const UPDATE_IN_PROGRESS : u8 = 0x80;
+    /// Set in status B if the registers are in binary instead of BCD.
+    const BINARY_MODE : u8 = 0x04;
+    /// Set in status B if the hours are 24 hour instead of 12 hour.
+    const HOUR_24 : u8 = 0x02;
+    /// Set on the hours register for PM times in 12 hour mode.
+    const HOUR_PM : u8 = 0x80;
+
+    static mut BOOT_TIME : u64 = 0;
+
+    fn read_reg(reg: u8) -> u8 {
+        unsafe {
+            io::outb(ADDRESS, reg);
+            io::inb(DATA)
+        }
+    }
+
+    fn updating() -> bool { (read_reg(STATUS_A) & UPDATE_IN_PROGRESS) != 0 }
+
+    fn read_raw() -> [u8; 6] {
+        while updating() {}
+        [read_reg(SECONDS), read_reg(MINUTES), read_reg(HOURS), read_reg(DAY), read_reg(MONTH), read_reg(YEAR)]
+    }
+
+    fn from_bcd(v: u8) -> u8 { (v & 0x0f) + ((v >> 4) * 10) }
+
+    /// Days since 1970-01-01 of the given date in the proleptic gregorian calendar.
+    fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+        let y = if month <= 2 { year - 1 } else { year };
+        let era = if y >= 0 { y } else { y - 399 } / 400;
+        let yoe = y - era * 400;
+        let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    /// Read the clock, returning the number of seconds since the epoch. The clock has no idea what
+    /// time zone it is in so we assume it is set to UTC, and that it is the 21st century.
+    pub fn read() -> u64 {
+        // The registers might change while we are reading them so read until we get the same
+        // thing twice in a row.
+        let mut regs = read_raw();
+        loop {
+            let again = read_raw();
+            if again == regs { break; }
+            regs = again;
+        }
+        let status = read_reg(STATUS_B);
+        let pm = (regs[2] & HOUR_PM) != 0;
+        regs[2] &= !HOUR_PM;
+        if (status & BINARY_MODE) == 0 {
+            for r in regs.iter_mut() { *r = from_bcd(*r); }
+        }
+        if (status & HOUR_24) == 0 {
+            regs[2] = (regs[2] % 12) + if pm { 12 } else { 0 };
+        }
+        let (sec, min, hour, day, month, year) =
+            (regs[0] as i64, regs[1] as i64, regs[2] as i64, regs[3] as i64, regs[4] as i64, regs[5] as i64 + 2000);
+        let days = days_from_civil(year, month, day);
+        (((days * 24 + hour) * 60 + min) * 60 + sec) as u64
+    }
+
+    /// The wall-clock time when we booted in seconds since the epoch.
+    pub fn boot_time() -> u64 { unsafe { BOOT_TIME } }
+
+    pub fn init_stage1() {
+        unsafe { BOOT_TIME = read(); }
+    }
+    pub fn init_stage2() {
+        dbg!(debug::CORE, "Booted at {} seconds since the epoch", boot_time());
+    }
+}
+
+/// The command line the bootloader passed us, for example `multiboot /boot/kernel.bin sched=fifo`
+/// in grub.cfg. It is made up of space seperated `key=value` options.
+pub mod cmdline {
+    use std::str::from_utf8;
+    use libc::uintptr_t;
+    use mm::{page, pagetable};
+
+    extern "C" {
+        /// The physical address of the multiboot information, saved by boot.S.
+        static boot_info : usize;
+    }
+
+    const MULTIBOOT_INFO_CMDLINE : u32 = 0x00000004;
+    const FLAGS_OFFSET   : usize = 0;
+    const CMDLINE_OFFSET : usize = 16;
+    /// The longest command line we will keep, anything after this is dropped.
+    pub const MAX_LEN : usize = 256;
+
+    static mut CMDLINE : [u8; MAX_LEN] = [0; MAX_LEN];
+    static mut CMDLINE_LEN : usize = 0;
+
+    unsafe fn read_phys<T: Copy>(paddr: usize) -> T {
+        let vaddr = pagetable::phys_tmp_map((paddr & page::MASK) as uintptr_t) as usize;
+        *((vaddr + (paddr & !page::MASK)) as *const T)
+    }
+
+    /// We copy this out now since we do not know how long the bootloader's copy will stay around.
+    pub fn init_stage1() {
+        unsafe {
+            if read_phys::<u32>(boot_info + FLAGS_OFFSET) & MULTIBOOT_INFO_CMDLINE == 0 {
+                return;
+            }
+            let addr = read_phys::<u32>(boot_info + CMDLINE_OFFSET) as usize;
+            while CMDLINE_LEN < MAX_LEN {
+                let c = read_phys::<u8>(addr + CMDLINE_LEN);
+                if c == 0 { break; }
+                CMDLINE[CMDLINE_LEN] = c;
+                CMDLINE_LEN += 1;
+            }
+        }
+    }
+
+    pub fn init_stage2() {
+        dbg!(debug::CORE, "kernel command line is {:?}", get());
+    }
+
+    /// The whole command line.
+    pub fn get() -> &'static str {
+        from_utf8(unsafe { &CMDLINE[..CMDLINE_LEN] }).unwrap_or("")
+    }
+
+    /// Get the value of a `key=value` option. An option given as just `key` has a value of "".
+    pub fn get_option(key: &str) -> Option<&'static str> {
+        for opt in get().split(' ') {
+            let mut parts = opt.splitn(2, '=');
+            if parts.next() == Some(key) {
+                return Some(parts.next().unwrap_or(""));
+            }
+        }
+        None
+    }
 }
 
 /// Thread specific data support.
@@ -136,18 +317,35 @@ pub mod gdt {
     pub const USER_TEXT   : u16 = 0x18;
     pub const USER_DATA   : u16 = 0x20;
     pub const TSS         : u16 = 0x28;
+    /// The task we switch to on a double fault.
+    pub const DOUBLE_FAULT_TSS : u16 = 0x30;
+    /// Thread specific data with no stack limit, for the double fault task to use.
+    pub const EMERGENCY_TSD : u32 = 0x38;
     pub const THREAD_SPECIFIC : u32 = 0x40;
+    /// The per thread segment user code keeps its thread local storage in, through %fs.
+    pub const USER_TLS : u32 = 0x48;
     extern "C" {
         fn gdt_init();
 
         #[link_name = "gdt_set_kernel_stack"]
         pub fn set_kernel_stack(addr: *mut c_void);
 
+        /// Make the double fault task start at `entry` running on `stack` with the given gs.
+        #[link_name = "gdt_set_double_fault_task"]
+        fn c_set_double_fault_task(entry: *const c_void, stack: *mut c_void, gs: u16);
+
+        /// Get the eip and esp of whatever was running when we switched to the double fault task.
+        #[link_name = "gdt_get_interrupted_task"]
+        pub fn get_interrupted_task(eip: *mut u32, esp: *mut u32);
+
         #[link_name = "gdt_set_entry"]
         fn set_entry(segment: u32, base: *const c_void, limit: u32, ring: u8, exec: c_int, dir: c_int, rw: c_int);
 
         #[link_name = "gdt_get_entry_base"]
         fn get_entry_base(segment: u32) -> *const c_void;
+
+        #[link_name = "gdt_clear"]
+        fn clear(segment: u32);
     }
 
     extern "rust-intrinsic" {
@@ -167,6 +365,13 @@ pub mod gdt {
 
     pub fn init_stage2() {}
 
+    /// Set up the double fault task to run `entry` on `stack`. It uses a thread specific data
+    /// segment with no stack limit since it is not on the stack of the current thread.
+    pub unsafe fn set_double_fault_task(entry: *const c_void, stack: *mut c_void) {
+        set_entry(EMERGENCY_TSD, transmute(&::tsd::INITIAL_TSD), 0x1, 0, 0, 0, 0);
+        c_set_double_fault_task(entry, stack, EMERGENCY_TSD as u16);
+    }
+
     #[no_stack_check]
     pub fn set_tsd(ptr: *const ::tsd::TSDInfo) {
         unsafe {
@@ -175,6 +380,32 @@ pub mod gdt {
         }
     }
 
+    /// Make the user TLS segment start at `base` and cover `limit + 1` pages, or make it unusable if
+    /// given None. Then load `fs`, since the processor only looks at the table when a segment
+    /// register is loaded. If `fs` names the TLS segment and there is none it is loaded with 0.
+    #[no_stack_check]
+    pub fn set_user_tls(tls: Option<(usize, u32)>, fs: u16) {
+        let fs = match tls {
+            Some((base, limit)) => {
+                unsafe { set_entry(USER_TLS, base as *const c_void, limit, 3, 0, 0, 1); }
+                fs
+            },
+            None => {
+                unsafe { clear(USER_TLS); }
+                if (fs as u32) & !0x7 == USER_TLS { ZERO } else { fs }
+            },
+        };
+        unsafe { asm!("mov $0, %fs" : : "r"(fs) : : "volatile"); }
+    }
+
+    /// The selector in %fs right now.
+    #[no_stack_check]
+    pub fn get_fs() -> u16 {
+        let fs : u16;
+        unsafe { asm!("mov %fs, $0" : "=r"(fs) : : : "volatile"); }
+        fs
+    }
+
     pub fn get_tsd() -> &'static mut ::tsd::TSDInfo {
         unsafe {
             let ret : *mut ::tsd::TSDInfo = get_entry_base(0x40) as *mut ::tsd::TSDInfo;
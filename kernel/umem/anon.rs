@@ -0,0 +1,254 @@
+// TODO Copyright Header
+
+//! Anonymous memory objects.
+//!
+//! An anonymous object is memory that is not backed by anything, such as the heap and the stack.
+//! Its pages start out full of zeros. There is nowhere to write them back to so every page it has
+//! ever handed out is kept pinned until the object goes away, or until it is swapped out.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use base::devices::DeviceId;
+use base::errno::{self, KResult};
+use util::pinnable_cache::PinnedValue;
+use mmobj::{MMObj, MMObjId};
+use pframe::{PFrame, PFrameId};
+use swap;
+
+/// The made up device anonymous objects are numbered on.
+pub const ANON_DEVID : DeviceId = DeviceId_static!(0xFF, 0);
+
+static mut NEXT_ANON_ID : u32 = 0;
+
+/// The pages an object keeps pinned because it is the only place their contents are kept, along
+/// with the swap slots of the ones that have been swapped out.
+pub struct PageSet {
+    pages   : RefCell<BTreeMap<usize, PinnedValue<'static, PFrameId, PFrame>>>,
+    swapped : RefCell<BTreeMap<usize, usize>>,
+    /// The object we belong to. It is set, and we are registered with swap, the first time we are
+    /// given a page.
+    owner   : RefCell<Option<Weak<Box<MMObj + 'static>>>>,
+}
+
+impl PageSet {
+    pub fn new() -> PageSet {
+        PageSet { pages: RefCell::new(BTreeMap::new()), swapped: RefCell::new(BTreeMap::new()), owner: RefCell::new(None) }
+    }
+
+    /// Our page `pagenum`, if it is in memory.
+    pub fn get(&self, pagenum: usize) -> Option<PinnedValue<'static, PFrameId, PFrame>> {
+        loop {
+            let pf = match self.pages.borrow().get(&pagenum) {
+                Some(pf) => pf.pin(),
+                None => { return None; },
+            };
+            if !pf.is_busy() {
+                return Some(pf);
+            }
+            // It might be getting swapped out, so look again once it is done.
+            let _ = pf.wait_busy();
+        }
+    }
+
+    /// Page `pagenum` if we have it in memory and it is busy.
+    fn get_busy(&self, pagenum: usize) -> Option<PinnedValue<'static, PFrameId, PFrame>> {
+        match self.pages.borrow().get(&pagenum) {
+            Some(pf) if pf.is_busy() => Some(pf.pin()),
+            _ => None,
+        }
+    }
+
+    /// Whether we have page `pagenum`, in memory or swapped out.
+    pub fn contains(&self, pagenum: usize) -> bool {
+        self.pages.borrow().contains_key(&pagenum) || self.swapped.borrow().contains_key(&pagenum)
+    }
+
+    /// The page numbers we have, in memory or swapped out.
+    pub fn pagenums(&self) -> Vec<usize> {
+        let mut out : Vec<usize> = self.pages.borrow().keys().chain(self.swapped.borrow().keys()).map(|&n| n).collect();
+        out.sort();
+        out
+    }
+
+    /// How many pages we have in memory.
+    pub fn len(&self) -> usize { self.pages.borrow().len() }
+
+    /// How many of our pages are swapped out.
+    pub fn swapped_len(&self) -> usize { self.swapped.borrow().len() }
+
+    /// The object we belong to, if we have been given any pages and it is still around.
+    pub fn owner(&self) -> Option<Rc<Box<MMObj + 'static>>> { self.owner.borrow().as_ref().and_then(|o| o.upgrade()) }
+
+    /// Throw away our pages from `start` up to `end`, whether they are in memory or swapped out.
+    pub fn forget(&self, start: usize, end: usize) {
+        let gone : Vec<usize> = self.pages.borrow().keys().map(|&n| n).filter(|&n| start <= n && n < end).collect();
+        for n in gone.into_iter() {
+            // A page being swapped out has to finish first. It ends up in swapped, which we look at
+            // after this.
+            while let Some(pf) = self.get_busy(n) {
+                let _ = pf.wait_busy();
+            }
+            if let Some(pf) = self.pages.borrow_mut().remove(&n) {
+                pf.discard();
+            }
+        }
+        let gone : Vec<usize> = self.swapped.borrow().keys().map(|&n| n).filter(|&n| start <= n && n < end).collect();
+        for n in gone.into_iter() {
+            if let Some(slot) = self.swapped.borrow_mut().remove(&n) {
+                swap::free_slot(slot);
+            }
+        }
+    }
+
+    /// Get page `pagenum` of `this`, which owns this set, making it or bringing it back in from swap
+    /// if we do not have it in memory.
+    pub fn lookup(&self, this: Rc<Box<MMObj + 'static>>, pagenum: usize) -> KResult<PinnedValue<'static, PFrameId, PFrame>> {
+        if let Some(pf) = self.get(pagenum) {
+            return Ok(pf);
+        }
+        if self.owner.borrow().is_none() {
+            try!(swap::register(self as *const PageSet));
+            *self.owner.borrow_mut() = Some(this.downgrade());
+        }
+        let pf = try!(PFrame::get(this, pagenum));
+        try!(alloc!(try self.pages.borrow_mut().insert(pagenum, pf.pin())).or(Err(errno::ENOMEM)));
+        // It is only kept in memory now.
+        if let Some(slot) = self.swapped.borrow_mut().remove(&pagenum) {
+            swap::free_slot(slot);
+        }
+        Ok(pf)
+    }
+
+    /// Fill `pf` from swap if it is one of ours that was swapped out. Returns None if it was not.
+    pub fn fill(&self, pf: &mut PFrame) -> Option<KResult<()>> {
+        let slot = match self.swapped.borrow().get(&pf.get_pagenum()) {
+            Some(&slot) => slot,
+            None => { return None; },
+        };
+        Some(swap::read_page(slot, pf.get_page_mut()))
+    }
+
+    /// Write `pf` out to swap and remember where it went.
+    pub fn write_out(&self, pf: &PFrame) -> KResult<()> {
+        let slot = try!(swap::write_page(pf.get_page()));
+        if let Some(old) = self.swapped.borrow_mut().insert(pf.get_pagenum(), slot) {
+            swap::free_slot(old);
+        }
+        Ok(())
+    }
+
+    /// Swap out up to `npages` of our pages, returning how many were.
+    pub fn swap_out(&self, npages: usize) -> usize {
+        let resident : Vec<usize> = self.pages.borrow().keys().map(|&n| n).collect();
+        let mut cnt = 0;
+        for n in resident.into_iter() {
+            if cnt == npages {
+                break;
+            }
+            if self.swap_out_page(n) {
+                cnt += 1;
+            }
+        }
+        cnt
+    }
+
+    /// Write page `pagenum` out to swap and let its frame go.
+    fn swap_out_page(&self, pagenum: usize) -> bool {
+        let pf = match self.pages.borrow().get(&pagenum) {
+            Some(pf) => pf.pin(),
+            None => { return false; },
+        };
+        if pf.is_busy() || (!pf.is_dirty() && pf.dirty().is_err()) {
+            return false;
+        }
+        // Cleaning calls our object's clean_page, which writes it out.
+        if pf.clean().is_err() {
+            return false;
+        }
+        if pf.is_dirty() {
+            // It was written to while we were writing it out, so what is in swap is already old.
+            if let Some(slot) = self.swapped.borrow_mut().remove(&pagenum) {
+                swap::free_slot(slot);
+            }
+            return false;
+        }
+        pf.remove_from_pts();
+        pf.discard();
+        self.pages.borrow_mut().remove(&pagenum);
+        true
+    }
+
+    /// Bring all of our swapped out pages back into memory. `this` is the object we belong to.
+    pub fn swap_in_all(&self, this: Rc<Box<MMObj + 'static>>) -> KResult<()> {
+        let swapped : Vec<usize> = self.swapped.borrow().keys().map(|&n| n).collect();
+        for n in swapped.into_iter() {
+            try!(self.lookup(this.clone(), n));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PageSet {
+    fn drop(&mut self) {
+        // Nobody can ever see these again so there is no point in keeping them.
+        for (_, pf) in self.pages.borrow_mut().iter() {
+            pf.discard();
+        }
+        for (_, &slot) in self.swapped.borrow().iter() {
+            swap::free_slot(slot);
+        }
+        if self.owner.borrow().is_some() {
+            swap::unregister(self as *const PageSet);
+        }
+    }
+}
+
+/// Memory that starts out zeroed and is not backed by anything.
+pub struct AnonObj {
+    id    : u32,
+    pages : PageSet,
+}
+
+impl AnonObj {
+    pub fn new() -> AnonObj {
+        let id = unsafe { NEXT_ANON_ID += 1; NEXT_ANON_ID };
+        dbg!(debug::ANON, "making anonymous object {}", id);
+        AnonObj { id: id, pages: PageSet::new() }
+    }
+
+    /// Make a new anonymous object ready to go in a `VMArea`.
+    pub fn create() -> KResult<Rc<Box<MMObj + 'static>>> {
+        alloc!(try Rc::new(box AnonObj::new() as Box<MMObj + 'static>)).or(Err(errno::ENOMEM))
+    }
+}
+
+impl MMObj for AnonObj {
+    fn get_id(&self) -> MMObjId { MMObjId::new(ANON_DEVID, self.id) }
+    fn fill_page(&self, pf: &mut PFrame) -> KResult<()> {
+        if let Some(res) = self.pages.fill(pf) {
+            return res;
+        }
+        for b in pf.get_page_mut().iter_mut() { *b = 0; }
+        Ok(())
+    }
+    fn dirty_page(&self, _pf: &PFrame) -> KResult<()> { Ok(()) }
+    /// Our pages are only ever cleaned to swap them out.
+    fn clean_page(&self, pf: &PFrame) -> KResult<()> { self.pages.write_out(pf) }
+    fn lookup_page(&self, this: Rc<Box<MMObj + 'static>>, pagenum: usize, _forwrite: bool) -> KResult<PinnedValue<'static, PFrameId, PFrame>> {
+        self.pages.lookup(this, pagenum)
+    }
+    fn forget_pages(&self, start: usize, end: usize) { self.pages.forget(start, end); }
+    fn resident(&self) -> usize { self.pages.len() }
+}
+
+impl fmt::Debug for AnonObj {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AnonObj {{ id: {}, resident: {}, swapped: {} }}", self.id, self.pages.len(), self.pages.swapped_len())
+    }
+}
+
+impl Drop for AnonObj {
+    fn drop(&mut self) { dbg!(debug::ANON, "destroying {:?}", self); }
+}
@@ -0,0 +1,100 @@
+// TODO Copyright Header
+
+//! Handling page faults on user memory.
+//!
+//! User pages are only put in the page table when they are first touched. When that happens we
+//! look up the area the address is in, make sure the access is allowed and then map in the page
+//! its object has for it. Read faults map the page read only, even in writable areas, so that the
+//! first write faults again and the page gets dirtied, or copied if it is copy on write.
+
+use base::errno::{self, KResult, Errno};
+use mm::{page, pagetable};
+use mm::memman::prot;
+use mm::user::{MEM_LOW, MEM_HIGH};
+use procs::interrupt::Registers;
+use procs::{resource, signal};
+use mmobj;
+use pframe::PFrame;
+use rmap;
+use shadow;
+use vmmap;
+
+/// The fault was on a page that was present, so it was a protection problem.
+pub const FAULT_PRESENT : u32 = 0x01;
+/// The fault was caused by a write.
+pub const FAULT_WRITE   : u32 = 0x02;
+/// The fault happened in user mode.
+pub const FAULT_USER    : u32 = 0x04;
+/// The fault was caused by fetching an instruction.
+pub const FAULT_EXEC    : u32 = 0x10;
+
+/// The address that caused the last page fault.
+fn fault_addr() -> usize {
+    let cr2 : usize;
+    unsafe { asm!("movl %cr2, $0" : "=r"(cr2) : : : "volatile"); }
+    cr2
+}
+
+/// Make the page at `addr` available for the access described by `err`. Returns whether the page
+/// had to be brought in, which is what counts as a major fault.
+fn resolve(addr: usize, err: u32) -> KResult<bool> {
+    let write = err & FAULT_WRITE != 0;
+    let need = if write { prot::WRITE } else if err & FAULT_EXEC != 0 { prot::EXEC } else { prot::READ };
+    let vfn = addr >> page::SHIFT;
+    let map = try!(vmmap::current().or(Err(errno::ENOMEM)));
+    let area = try!(map.lookup(vfn).ok_or(errno::EFAULT));
+    if area.prot & need == 0 {
+        dbg!(debug::VMMAP, "{:?} needs {:#x} at {:#x} but {:?} does not allow it", current_pid!(), need, addr, area);
+        return Err(errno::EACCES);
+    }
+    // Whoever we shared the chain with may be gone, so shorten it before walking it. If there is not
+    // enough memory to copy the pages up we just walk the long chain.
+    if shadow::is_collapsible(&area.obj) {
+        if let Err(e) = shadow::collapse(&area.obj) {
+            dbg!(debug::VMMAP, "unable to collapse the shadows under {:?}: {:?}", area, e);
+        }
+    }
+    let pagenum = area.obj_page(vfn);
+    let major = PFrame::get_resident(area.obj.clone(), pagenum).is_none();
+    let pf = try!(mmobj::lookup_page(area.obj.clone(), pagenum, write));
+    let kaddr = if write {
+        try!(pf.dirty()) as *mut [u8; page::SIZE] as usize
+    } else {
+        pf.get_page() as *const [u8; page::SIZE] as usize
+    };
+    let ptflags = pagetable::PRESENT | pagetable::USER | if write { pagetable::WRITE } else { 0 };
+    let pdflags = pagetable::PRESENT | pagetable::USER | pagetable::WRITE;
+    let vaddr = vfn << page::SHIFT;
+    let pd = (current_proc_mut!()).get_pagedir_mut();
+    let paddr = pd.virt_to_phys(kaddr);
+    try!(unsafe { rmap::map(pd, vaddr, paddr, pdflags as u32, ptflags as u32) });
+    Ok(major)
+}
+
+/// The page fault handler used once we have virtual memory.
+pub fn handle_pagefault(regs: &mut Registers) {
+    let addr = fault_addr();
+    let user = regs.cs & 3 == 3;
+    dbg!(debug::VMMAP, "page fault at {:#x} with error {:#x} from {:#x}", addr, regs.err, regs.eip);
+    let res = if addr < MEM_LOW || addr >= MEM_HIGH { Err(errno::EFAULT) } else { resolve(addr, regs.err) };
+    match res {
+        Ok(major) => { resource::count_fault(major); },
+        Err(e) if user => {
+            dbg!(debug::VMMAP, "{:?} made a bad access at {:#x} from {:#x}: {:?}", current_proc!(), addr, regs.eip, e);
+            signal::force_signal(signal_for(e));
+        },
+        Err(e) => {
+            kpanic!("Page fault in the kernel at {:#x} from {:#x} could not be handled: {:?}. regs {:?}, thr {:?}",
+                    addr, regs.eip, e, regs, current_thread!());
+        },
+    }
+}
+
+/// The signal to send for a fault we could not resolve. Touching memory that is not there or is
+/// not allowed is SIGSEGV, the object failing to give us the page is SIGBUS.
+fn signal_for(e: Errno) -> signal::Signal {
+    match e {
+        errno::EFAULT | errno::EACCES => signal::SIGSEGV,
+        _ => signal::SIGBUS,
+    }
+}
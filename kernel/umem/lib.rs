@@ -21,11 +21,19 @@
 extern crate startup;
 extern crate libc;
 
-pub use pframe::pageout::{wakeup, pageoutd_run};
+pub use pframe::pageout::wakeup;
 
 // TODO We should have a MaybePinnedList that uses a LRUCache under the hood...
 pub mod mmobj;
 pub mod pframe;
+pub mod rmap;
+pub mod anon;
+pub mod shadow;
+pub mod swap;
+pub mod oom;
+pub mod vmmap;
+pub mod fault;
+pub mod mman;
 //pub mod vnode;
 
 pub fn init_stage1() {
@@ -38,8 +46,19 @@ extern "Rust" fn handle_pagefault(regs: &mut procs::interrupt::Registers) {
     panic!("Pagefault found! regs were {:?}", regs);
 }
 
+#[cfg(VM)]
+extern "Rust" fn handle_pagefault(regs: &mut procs::interrupt::Registers) {
+    fault::handle_pagefault(regs);
+}
+
 pub fn init_stage2() {
+    rmap::init_stage2();
+    swap::init_stage2();
     pframe::init_stage2();
+    // Without VM nothing can fault in pages that are mapped but not touched yet.
+    if cfg!(VM) {
+        procs::futex::set_key_hook(vmmap::futex_key);
+    }
 }
 
 pub fn init_stage3() {
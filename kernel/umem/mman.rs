@@ -0,0 +1,185 @@
+// TODO Copyright Header
+
+//! The memory mapping syscalls.
+//!
+//! These only change the `VMMap` of the current process and throw away page table entries that no
+//! longer match it. Pages are put back in the page table by the fault handler the next time they
+//! are touched, which checks the new protections.
+//!
+//! There are no file descriptors yet, so mmap is given the object to map rather than a file. Whoever
+//! turns a descriptor into that object, the file's vnode or a device such as `memdev::ZeroDev`, is
+//! responsible for checking the file was opened in a way that allows the mapping.
+
+use std::cmp::{min, max};
+use std::rc::Rc;
+use base::errno::{self, KResult};
+use mm::page;
+use mm::memman::{prot, map};
+use mm::user::MEM_HIGH;
+use procs::resource::{self, RLIMIT_AS};
+use mmobj::MMObj;
+use anon::AnonObj;
+use shadow::ShadowObj;
+use vmmap::{self, VMArea, VMMap, Dir, LOW_PAGE, HIGH_PAGE};
+
+/// The number of pages needed to hold `len` bytes.
+fn npages_for(len: usize) -> usize { (len + page::SIZE - 1) / page::SIZE }
+
+/// Check that `[addr, addr + len)` is a page aligned, non-empty range of user memory and return its
+/// first page and length in pages.
+fn user_range(addr: usize, len: usize) -> KResult<(usize, usize)> {
+    if len == 0 || addr % page::SIZE != 0 {
+        return Err(errno::EINVAL);
+    }
+    let (start, npages) = (addr / page::SIZE, npages_for(len));
+    if start < LOW_PAGE || start > HIGH_PAGE || HIGH_PAGE - start < npages {
+        return Err(errno::EINVAL);
+    }
+    Ok((start, npages))
+}
+
+/// How many pages of `[start, start + npages)` are mapped in `map`.
+fn mapped_in(map: &VMMap, start: usize, npages: usize) -> usize {
+    let end = start + npages;
+    map.areas().iter().filter(|a| a.overlaps(start, end))
+                      .map(|a| min(a.end, end) - max(a.start, start))
+                      .fold(0, |t, n| t + n)
+}
+
+/// Whether mapping `npages` more pages keeps the current process within its address space limit.
+fn within_limit(vmm: &VMMap, npages: usize) -> bool {
+    let limit = (current_proc!()).get_rlimit(RLIMIT_AS).cur;
+    let total = (vmm.total_pages() + npages) * page::SIZE;
+    if limit != resource::RLIM_INFINITY && total > limit {
+        dbg!(debug::VMMAP, "{:?} would have {} bytes mapped, over its limit of {}", current_pid!(), total, limit);
+        false
+    } else {
+        true
+    }
+}
+
+/// Perform the mmap syscall. `obj` is what to map, starting `off` bytes into it, and is ignored for
+/// MAP_ANON mappings. Private mappings get a shadow object over `obj` so that writes to them are not
+/// seen by anyone else. Returns the address of the mapping.
+pub fn mmap(addr: usize, len: usize, prot: isize, flags: isize, obj: Option<Rc<Box<MMObj + 'static>>>, off: usize) -> KResult<usize> {
+    let kind = flags & map::MASK;
+    if kind != map::SHARED && kind != map::PRIVATE {
+        return Err(errno::EINVAL);
+    }
+    if len == 0 || off % page::SIZE != 0 || prot & !prot::MASK != 0 {
+        return Err(errno::EINVAL);
+    }
+    let npages = npages_for(len);
+    let vmm = try!(vmmap::current().or(Err(errno::ENOMEM)));
+    let fixed = if flags & map::FIXED != 0 { Some(try!(user_range(addr, len)).0) } else { None };
+
+    let replaced = fixed.map(|s| mapped_in(vmm, s, npages)).unwrap_or(0);
+    if !within_limit(vmm, npages - replaced) {
+        return Err(errno::ENOMEM);
+    }
+
+    let obj = if flags & map::ANON != 0 {
+        try!(AnonObj::create())
+    } else {
+        match obj {
+            Some(o) if kind == map::PRIVATE => try!(ShadowObj::create(o)),
+            Some(o) => o,
+            None => { return Err(errno::EBADF); },
+        }
+    };
+    let off = if flags & map::ANON != 0 { 0 } else { off / page::SIZE };
+
+    let start = match fixed {
+        Some(s) => {
+            try!(vmm.remove(s, npages).or(Err(errno::ENOMEM)));
+            vmm.unmap_pages(s, npages);
+            s
+        },
+        None => {
+            // Take the hint if it is free, otherwise go wherever there is room.
+            let hint = addr / page::SIZE;
+            if addr % page::SIZE == 0 && hint >= LOW_PAGE && hint <= HIGH_PAGE && HIGH_PAGE - hint >= npages &&
+               vmm.is_range_empty(hint, npages) {
+                hint
+            } else {
+                try!(vmm.find_range(npages, Dir::HighLow).ok_or(errno::ENOMEM))
+            }
+        },
+    };
+    try!(vmm.insert(VMArea::new(start, npages, off, prot, flags & !map::FIXED, obj)).or(Err(errno::ENOMEM)));
+    dbg!(debug::VMMAP, "{:?} mapped {:#x} bytes at {:#x}", current_pid!(), len, start << page::SHIFT);
+    Ok(start << page::SHIFT)
+}
+
+/// Perform the munmap syscall. Areas only partly in the range are cut down or split. It is fine for
+/// none of the range to be mapped.
+pub fn munmap(addr: usize, len: usize) -> KResult<()> {
+    let (start, npages) = try!(user_range(addr, len));
+    let vmm = try!(vmmap::current().or(Err(errno::ENOMEM)));
+    try!(vmm.remove(start, npages).or(Err(errno::ENOMEM)));
+    vmm.unmap_pages(start, npages);
+    Ok(())
+}
+
+/// Perform the mprotect syscall. Every page in the range must be mapped.
+pub fn mprotect(addr: usize, len: usize, prot: isize) -> KResult<()> {
+    if prot & !prot::MASK != 0 {
+        return Err(errno::EINVAL);
+    }
+    let (start, npages) = try!(user_range(addr, len));
+    let vmm = try!(vmmap::current().or(Err(errno::ENOMEM)));
+    if mapped_in(vmm, start, npages) != npages {
+        return Err(errno::ENOMEM);
+    }
+    try!(vmm.set_prot(start, npages, prot).or(Err(errno::ENOMEM)));
+    // The fault handler puts back whatever the new protections allow.
+    vmm.unmap_pages(start, npages);
+    Ok(())
+}
+
+/// Perform the brk syscall, moving the end of the heap to `addr` and returning the new end. An `addr`
+/// of 0 just returns where it is now. The heap is an anonymous area from the page after the data
+/// segment up to the page holding the end, so it cannot grow into anything else that is mapped.
+/// sbrk is done by libc on top of this.
+pub fn brk(addr: usize) -> KResult<usize> {
+    let (start_brk, cur) = ((current_proc!()).get_start_brk(), (current_proc!()).get_brk());
+    if addr == 0 {
+        return Ok(cur);
+    }
+    if addr < start_brk || addr > MEM_HIGH {
+        dbg!(debug::BRK, "{:?} asked for a break of {:#x}, outside of {:#x}-{:#x}", current_pid!(), addr, start_brk, MEM_HIGH);
+        return Err(errno::ENOMEM);
+    }
+    let (old_end, new_end) = (npages_for(cur), npages_for(addr));
+    if npages_for(start_brk) < LOW_PAGE {
+        dbg!(debug::BRK, "{:?} has no heap", current_pid!());
+        return Err(errno::ENOMEM);
+    }
+    let vmm = try!(vmmap::current().or(Err(errno::ENOMEM)));
+    if new_end > old_end {
+        let more = new_end - old_end;
+        if !vmm.is_range_empty(old_end, more) {
+            dbg!(debug::BRK, "{:?} cannot grow its heap to {:#x}, something else is mapped there", current_pid!(), addr);
+            return Err(errno::ENOMEM);
+        }
+        if !within_limit(vmm, more) {
+            return Err(errno::ENOMEM);
+        }
+        // Grow the heap area we already have if there is one, it ends right at the old break.
+        let grown = match vmm.lookup_mut(old_end - 1) {
+            Some(a) => if old_end > npages_for(start_brk) && a.flags & map::ANON != 0 { a.end = new_end; true } else { false },
+            None => false,
+        };
+        if !grown {
+            let obj = try!(AnonObj::create());
+            try!(vmm.insert(VMArea::new(old_end, more, 0, prot::READ | prot::WRITE, map::PRIVATE | map::ANON, obj))
+                    .or(Err(errno::ENOMEM)));
+        }
+    } else if new_end < old_end {
+        try!(vmm.remove(new_end, old_end - new_end).or(Err(errno::ENOMEM)));
+        vmm.unmap_pages(new_end, old_end - new_end);
+    }
+    dbg!(debug::BRK, "{:?} moved its break from {:#x} to {:#x}", current_pid!(), cur, addr);
+    (current_proc_mut!()).set_brk(addr);
+    Ok(addr)
+}
@@ -6,6 +6,7 @@ use base::devices::*;
 use base::cell::*;
 use base::errno::*;
 use pframe;
+use shadow::ShadowObj;
 use std::cmp::Ordering;
 use std::fmt;
 use std::rc::*;
@@ -21,6 +22,12 @@ pub struct MMObjId(DeviceId, u32);
 
 impl MMObjId {
     pub fn new(dev: DeviceId, n: u32) -> MMObjId { MMObjId(dev, n) }
+
+    /// The id as a single number, for code below us that only needs to tell objects apart.
+    pub fn as_u64(&self) -> u64 {
+        let &MMObjId(DeviceId(dev), n) = self;
+        ((dev as u64) << 32) | (n as u64)
+    }
 }
 
 impl PartialOrd for MMObjId { fn partial_cmp(&self, other: &MMObjId) -> Option<Ordering> { Some(self.cmp(other)) } }
@@ -96,6 +103,24 @@ pub trait MMObj : fmt::Debug {
      * Return 0 on success and -errno otherwise.
      */
     fn clean_page(&self, pf: &pframe::PFrame) -> KResult<()>;
+
+    /// Find the page frame for `pagenum` of `this`, which must be this object. See `lookup_page`,
+    /// which is how this should be called. Most objects just use their own pages.
+    fn lookup_page(&self, this: Rc<Box<MMObj + 'static>>, pagenum: usize, _forwrite: bool) -> KResult<PinnedValue<'static, pframe::PFrameId, pframe::PFrame>> {
+        pframe::PFrame::get(this, pagenum)
+    }
+
+    /// Throw away any pages from `start` up to `end` that this object is the only keeper of, since
+    /// nothing maps them any more. Mapping them again then gives fresh pages. Most objects have
+    /// nothing to forget.
+    fn forget_pages(&self, _start: usize, _end: usize) {}
+
+    /// How many pages of memory only this object is keeping around. Objects whose pages can just be
+    /// written back and dropped by pageoutd have none.
+    fn resident(&self) -> usize { 0 }
+
+    /// This object if it is a shadow object.
+    fn as_shadow(&self) -> Option<&ShadowObj> { None }
 }
 
 /**
@@ -114,8 +139,9 @@ pub trait MMObj : fmt::Debug {
     */
 // TODO This isn't the best interface Maybe a holder that will unpin when we leave, might be
 // better. Using this stuff is annoying.
-pub fn lookup_page(this: Rc<Box<MMObj + 'static>>, pagenum: usize, _writable: bool) -> KResult<PinnedValue<'static, pframe::PFrameId, pframe::PFrame>> {
-    pframe::PFrame::get(this, pagenum)
+pub fn lookup_page(this: Rc<Box<MMObj + 'static>>, pagenum: usize, writable: bool) -> KResult<PinnedValue<'static, pframe::PFrameId, pframe::PFrame>> {
+    let obj = this.clone();
+    obj.lookup_page(this, pagenum, writable)
 }
 
 impl<'a, T: MMObj + 'a> PartialOrd<T> for MMObj + 'a { fn partial_cmp(&self, o: &T) -> Option<Ordering> { self.get_id().partial_cmp(&o.get_id()) } }
@@ -0,0 +1,81 @@
+// TODO Copyright Header
+
+//! Killing a process when we are out of memory.
+//!
+//! When pageoutd and swap cannot free up a page for someone who needs one we pick the process that
+//! would give back the most memory by dying and kill it. A process is scored by the pages its
+//! anonymous and shadow objects keep in memory, plus half of those of its children since they are
+//! likely to be doing the same thing it is. Idle and init are never picked.
+
+use std::rc::Rc;
+use procs::kproc::{KProc, ProcId, ProcStatus};
+use procs::pcell::ProcRefCell;
+use procs::signal::SIGKILL;
+use vmmap::VMMap;
+
+/// The pages `p` keeps in memory.
+fn resident_pages(p: &KProc) -> usize {
+    p.get_vmmap().and_then(|m| m.downcast_ref::<VMMap>()).map(|m| m.resident_pages()).unwrap_or(0)
+}
+
+/// Call `f` on the process `p`, which is `pid`, unless somebody is using it.
+fn with_proc<F, R>(pid: ProcId, p: &Rc<ProcRefCell<KProc>>, f: F) -> Option<R> where F: FnOnce(&KProc) -> R {
+    if pid == current_pid!() {
+        Some(f(&*current_proc!()))
+    } else {
+        p.try_borrow().map(|p| f(&*p))
+    }
+}
+
+/// How good a choice `p` would be to kill when we are out of memory. Higher is better.
+pub fn score(p: &KProc) -> usize {
+    let mut children = 0;
+    p.each_child(|pid, c| {
+        children += with_proc(pid, c, |c| if c.is_dead() { 0 } else { resident_pages(c) }).unwrap_or(0);
+    });
+    resident_pages(p) + children / 2
+}
+
+/// Kill the process using the most memory. Returns false if there was nobody to kill.
+///
+/// If the process picked is the current one it keeps running until it is on its way back out to
+/// user space, so the caller will see its allocation fail and can clean up after itself.
+pub fn kill_something() -> bool {
+    let mut victim : Option<(ProcId, usize)> = None;
+    KProc::each_proc(|pid, p| {
+        let s = with_proc(pid, p, |p| if p.is_system() || p.is_dead() || p.is_killed() { 0 } else { score(p) });
+        match s {
+            Some(s) if s > 0 && victim.map(|(_, best)| s > best).unwrap_or(true) => { victim = Some((pid, s)); },
+            Some(_) => {},
+            None => { dbg!(debug::MM, "{:?} is busy, not considering it for the oom killer", pid); },
+        }
+    });
+    let (pid, s) = match victim {
+        Some(v) => v,
+        None => {
+            dbg!(debug::MM, "out of memory and there is nobody left to kill");
+            return false;
+        },
+    };
+    dbg!(debug::MM, "out of memory, killing {:?} which has a score of {}", pid, s);
+    let status = ProcStatus::signaled(SIGKILL);
+    if pid == current_pid!() {
+        (current_proc_mut!()).kill_deferred(status);
+        return true;
+    }
+    let p = match KProc::get_proc(&pid) {
+        Some(p) => p,
+        None => {
+            dbg!(debug::MM, "{:?} went away before we could kill it", pid);
+            return false;
+        },
+    };
+    let killed = match p.try_borrow_mut() {
+        Some(mut p) => { p.kill(status); true },
+        None => {
+            dbg!(debug::MM, "{:?} got busy before we could kill it", pid);
+            false
+        },
+    };
+    killed
+}
@@ -4,8 +4,10 @@
 use base::errno::{self, KResult, Errno};
 use base::make::*;
 use libc::c_void;
-use mm::{AllocError, page, tlb};
+use mm::{AllocError, page, pagetable, tlb};
 use mmobj::*;
+use rmap;
+use oom;
 use procs::sync::*;
 use std::cell::*;
 use std::fmt;
@@ -18,21 +20,23 @@ use util::pinnable_cache::{self, PinnableCache, InsertError, PinnedValue};
 
 pub type PageNum = usize;
 
-#[derive(Debug, Clone)]
-pub struct PFrameId { mmobj: Rc<Box<MMObj + 'static>>, page: PageNum, }
+/// The key for a page frame. It only holds a weak reference to its object so that an object may
+/// keep its own pages pinned without keeping itself alive.
+#[derive(Clone)]
+pub struct PFrameId { id: MMObjId, mmobj: Weak<Box<MMObj + 'static>>, page: PageNum, }
 impl PFrameId {
     /// Create a pframe id.
-    pub fn new(mmo: Rc<Box<MMObj + 'static >>, page: PageNum) -> PFrameId { PFrameId { mmobj: mmo, page: page } }
+    pub fn new(mmo: Rc<Box<MMObj + 'static >>, page: PageNum) -> PFrameId { PFrameId { id: mmo.get_id(), mmobj: mmo.downgrade(), page: page } }
 }
 
 impl PartialEq for PFrameId {
     fn eq(&self, o: &PFrameId) -> bool {
-        self.page == o.page && self.mmobj.get_id().eq(&o.mmobj.get_id())
+        self.page == o.page && self.id.eq(&o.id)
     }
 }
 impl PartialOrd for PFrameId {
     fn partial_cmp(&self, o: &PFrameId) -> Option<Ordering> {
-        match self.mmobj.get_id().cmp(&o.mmobj.get_id()) {
+        match self.id.cmp(&o.id) {
             Ordering::Less => Some(Ordering::Less),
             Ordering::Greater => Some(Ordering::Greater),
             Ordering::Equal => Some(self.page.cmp(&o.page)),
@@ -42,12 +46,21 @@ impl PartialOrd for PFrameId {
 impl Ord for PFrameId { fn cmp(&self, o: &PFrameId) -> Ordering { self.partial_cmp(o).unwrap() } }
 impl Eq for PFrameId { }
 
+impl fmt::Debug for PFrameId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PFrameId {{ mmobj: {:?}, page: {} }}", self.id, self.page)
+    }
+}
+
 impl Make<(Rc<Box<MMObj + 'static >>, PageNum)> for PFrameId {
     fn make(v: (Rc<Box<MMObj + 'static>>, PageNum)) -> PFrameId {
         PFrameId::new(v.0.clone(), v.1)
     }
 }
 
+/// How many times we wait for pageoutd when there is no memory for a new pframe.
+const ALLOC_TRIES : usize = 3;
+
 static mut PFRAME_CACHE : *mut PinnableCache<PFrameId, PFrame> = 0 as *mut PinnableCache<PFrameId, PFrame>;
 
 pub fn init_stage1() {
@@ -61,50 +74,116 @@ pub fn init_stage2() {
     let pfcache : Box<PinnableCache<PFrameId, PFrame>> = box PinnableCache::new().unwrap();
     unsafe { PFRAME_CACHE = transmute(pfcache); }
     pageout::init_pageoutd();
+    page::set_low_memory_hook(pageout::low_memory);
 }
 
 pub fn init_stage3() {
     // TODO
 }
 
-/// Module holding the pageoutd stuff.
+/// Module holding the pageoutd stuff. Pageout is done on the system workqueue whenever free memory
+/// drops below `LOW_WATER` pages, and keeps going until there are `HIGH_WATER` free pages or it runs
+/// out of things to free.
 pub mod pageout {
-    use libc::c_void;
-    use procs::sync::*;
+    use mm::{alloc, page};
+    use procs::kqueue::WQueue;
+    use procs::sync::Wakeup;
+    use procs::time;
+    use procs::workqueue::{self, Work};
     use super::get_cache;
+    use swap;
     use std::mem::transmute;
 
+    /// When there are fewer free pages than this pageoutd is woken up.
+    pub const LOW_WATER  : u32 = page::LOW_WATER;
+    /// Pageoutd stops freeing things once there are this many free pages.
+    pub const HIGH_WATER : u32 = 256;
+    /// How long someone who could not get a page waits for pageoutd before trying again.
+    pub const WAIT_TICKS : u64 = time::HZ as u64 / 10;
+
     pub fn init_pageoutd() {
-        let pd : Box<PageOutD> = box PageOutD { queue: WQueue::new() };
+        let pd : Box<PageOutD> = box PageOutD { work: Work::new(pageout, 0), done: WQueue::new() };
         unsafe { PAGEOUTD = transmute(pd); }
     }
 
-    /// the pageoutd's queue.
-    struct PageOutD { pub queue: WQueue, }
+    /// The work item that runs the pageoutd.
+    struct PageOutD {
+        pub work: Work,
+        /// Threads waiting for pageoutd to free something up.
+        pub done: WQueue,
+    }
     /// The pagetoutd
     static mut PAGEOUTD : *mut PageOutD = 0 as *mut PageOutD;
     /// Get the Pageoutd
-    fn get_pageoutd() -> &'static PageOutD { unsafe { PAGEOUTD.as_ref().expect("pageoutd is null!") } }
+    fn get_pageoutd() -> &'static mut PageOutD { unsafe { PAGEOUTD.as_mut().expect("pageoutd is null!") } }
+
+    fn free_pages() -> u32 { page::free_count() }
 
     /// is pageoutd needed.
-    // TODO  BETTER WAY
-    pub fn needed() -> bool { true }
-    /// Wakeup the pageoutd.
-    pub fn wakeup() { dbg!(debug::PCACHE, "pageoutd being signaled by {:?}", current_thread!()); get_pageoutd().queue.signal(); }
-    pub extern "C" fn pageoutd_run(_: i32, _: *mut c_void) -> *mut c_void {
-        // TODO This might be totally bad.
-        while !(current_thread!()).cancelled {
-            if let Err(_) = get_pageoutd().queue.wait() { break; }
-            if (current_thread!()).cancelled { break; }
-            dbg!(debug::PCACHE, "pageoutd woken up!");
-            let removed = get_cache().clean_unpinned();
-            dbg!(debug::PCACHE, "Removed {:?} items from page cache", removed);
-            if removed == 0 {
-                // TODO Should I do this?
-                get_cache().clear_unpinned();
+    pub fn needed() -> bool { free_pages() < LOW_WATER }
+    /// Wakeup the pageoutd. If it is already waiting to run this does nothing.
+    pub fn wakeup() {
+        dbg!(debug::PCACHE, "pageoutd being signaled by {:?}", current_thread!());
+        workqueue::queue_work(&mut get_pageoutd().work);
+    }
+
+    /// Wake up pageoutd and wait a little while for it to free something. Used by those who could
+    /// not get memory before they try again.
+    pub fn wait_for_memory() {
+        wakeup();
+        dbg!(debug::PCACHE, "{:?} waiting for pageoutd, {} pages free", current_thread!(), free_pages());
+        // Whether we were woken or timed out we just try again.
+        let _ = get_pageoutd().done.wait_until(time::ticks() + WAIT_TICKS);
+    }
+
+    /// Called by the page allocator when memory is low. If the caller can wait we give pageoutd a
+    /// moment to free something, unless we are pageoutd, which would just be waiting on itself.
+    pub fn low_memory(wait: bool) {
+        if !workqueue::is_running() {
+            // There is no pageoutd to ask yet.
+            return;
+        }
+        if wait && !workqueue::is_system_worker(current_pid!()) {
+            wait_for_memory();
+        } else if !get_pageoutd().work.is_running() {
+            wakeup();
+        }
+    }
+
+    /// Free clean unpinned pages, and those of objects that are gone, least recently used first,
+    /// until there are enough free pages. Returns how many were freed.
+    fn evict_clean() -> usize {
+        get_cache().pop_unpinned_until(|pf| !pf.is_dirty() || pf.obj.upgrade().is_none(), || free_pages() >= HIGH_WATER)
+    }
+
+    /// Write out the dirty unpinned pages, least recently used first, so that they can be evicted.
+    fn clean_dirty() {
+        // Cleaning can block so keep each page pinned while we are at it.
+        let keys = get_cache().unpinned_keys(|pf| pf.is_dirty() && pf.obj.upgrade().is_some()).unwrap_or(Vec::new());
+        for k in keys.iter() {
+            if let Some(pf) = get_cache().get(k) {
+                if pf.is_dirty() && !pf.is_busy() {
+                    if let Err(e) = pf.clean() {
+                        dbg!(debug::PCACHE, "unable to clean {:?}: {:?}", *pf, e);
+                    }
+                }
             }
         }
-        0 as *mut c_void
+    }
+
+    fn pageout(_: usize) {
+        dbg!(debug::PCACHE, "pageoutd woken up with {} pages free", free_pages());
+        let mut removed = evict_clean();
+        if free_pages() < HIGH_WATER {
+            clean_dirty();
+            removed += evict_clean();
+        }
+        if free_pages() < HIGH_WATER {
+            removed += swap::swap_out((HIGH_WATER - free_pages()) as usize);
+        }
+        alloc::reclaim_memory();
+        dbg!(debug::PCACHE, "Removed {:?} items from page cache, {} pages free", removed, free_pages());
+        get_pageoutd().done.signal();
     }
 }
 
@@ -125,7 +204,9 @@ pub mod pfstate {
             #[doc = "this pframe is busy, meaning it is currently being modified"]
             BUSY    = 1,
             #[doc = "this pframe is still being initialized. No pframe should ever have this state after being returned."]
-            INITING = 2
+            INITING = 2,
+            #[doc = "this pframe's contents are not wanted any more so it should be freed once nobody is using it."]
+            DISCARDED = 3
         }
     );
 }
@@ -212,7 +293,7 @@ impl PFrame {
                 obj : mmo.downgrade(),
                 pagenum : page_num,
 
-                page : try!(unsafe { page::alloc::<[u8; page::SIZE]>().map_err(|v| Alloc(v)) }),
+                page : try!(PFrame::alloc_page().map_err(|v| Alloc(v))),
 
                 flags : Cell::new(pfstate::NORMAL | pfstate::INITING),
                 queue : WQueue::new(),
@@ -225,6 +306,24 @@ impl PFrame {
         })
     }
 
+    /// Get a page of memory for a new pframe. If there are none we give pageoutd a few chances to
+    /// free some up, then kill whoever is using the most memory and try once more before giving up.
+    fn alloc_page() -> Result<*mut [u8; page::SIZE], AllocError> {
+        for _ in 0..ALLOC_TRIES {
+            if let Ok(p) = unsafe { page::alloc::<[u8; page::SIZE]>() } {
+                return Ok(p);
+            }
+            pageout::wait_for_memory();
+        }
+        if let Ok(p) = unsafe { page::alloc::<[u8; page::SIZE]>() } {
+            return Ok(p);
+        }
+        if oom::kill_something() {
+            pageout::wait_for_memory();
+        }
+        unsafe { page::alloc::<[u8; page::SIZE]>() }
+    }
+
     /**
      * Fills the contents of the page (using the mmobj's fillpage op).
      * Make sure to mark the page busy while it's being filled.
@@ -292,9 +391,19 @@ impl PFrame {
     }
 
 
+    /// Forget that this page was changed. Objects whose contents are going away do this to their
+    /// pages so that they are freed once unpinned rather than cleaned.
+    pub fn discard(&self) {
+        assert!(!self.is_busy());
+        self.flags.set((self.flags.get() & !pfstate::DIRTY) | pfstate::DISCARDED);
+    }
+
     #[inline]
     pub fn get_pagenum(&self) -> PageNum { self.pagenum }
 
+    /// The physical address of our page.
+    fn paddr(&self) -> usize { unsafe { pagetable::base_virt_to_phys(self.page as usize as u32) as usize } }
+
     /**
      * Clean a dirty page by writing it back to disk. Removes the dirty
      * bit of the page and updates the MMU entry.
@@ -309,8 +418,7 @@ impl PFrame {
 
         self.flags.set(self.flags.get() & !pfstate::DIRTY);
         /* Make sure a future write to the page will fault (and hence dirty it) */
-        unsafe { tlb::flush(self.page as *mut c_void) };
-        self.remove_from_pts();
+        self.write_protect();
 
         self.set_busy();
         let ret = self.get_mmo().clean_page(self);
@@ -323,26 +431,27 @@ impl PFrame {
 
     fn get_mmo(&self) -> Rc<Box<MMObj + 'static>> { self.obj.upgrade().expect("mmobj shouldn't be destroyed while pframes still present") }
 
-    /// Remove this pframe from the page frame tables of all the procs it is loaded in.
-    fn remove_from_pts(&self) {
-        // TODO figure out how to do this.
-        kpanic!("not yet implemented remove from pts called");
-    }
+    /// Make this pframe read only in the page tables of all the procs it is loaded in.
+    fn write_protect(&self) { rmap::write_protect_all(self.paddr()); }
+
+    /// Remove this pframe from the page tables of all the procs it is loaded in.
+    pub fn remove_from_pts(&self) { rmap::unmap_all(self.paddr()); }
 }
 
 impl Cacheable for PFrame {
+    /// Pages are worth keeping around, clean or not, for as long as their object is. Pageout
+    /// frees the clean ones when memory gets low.
     fn is_still_useful(&self) -> bool {
-        // TODO Fix this.
-        //self.get_mmo().deref().is_still_useful() || self.is_dirty()
-        self.is_dirty()
+        self.flags.get() & pfstate::DISCARDED == pfstate::NORMAL && (self.is_dirty() || self.obj.upgrade().is_some())
     }
 }
 
 #[doc(hidden)]
 impl TryMake<PFrameId, Errno> for PFrame {
     fn try_make(a: PFrameId) -> Result<PFrame, Errno> {
-        let PFrameId { mmobj, page } = a.clone();
-        PFrame::create(mmobj, page).map_err(|e| {
+        // Whoever is asking for the page holds on to the object so it is still around.
+        let mmobj = a.mmobj.upgrade().expect("making a pframe for an object that is gone");
+        PFrame::create(mmobj, a.page).map_err(|e| {
             match e {
                 PFError::Alloc(_) => { dbg!(debug::PFRAME, "Unable to allocate memory for {:?}", a); Errno::ENOMEM },
                 PFError::Sys(e)   => { dbg!(debug::PFRAME, "unable to create {:?} because of {:?}", a, e); e },
@@ -368,7 +477,7 @@ impl Drop for PFrame {
         assert!(!self.is_busy());
         // TODO Not sure if this is good enough.
         dbg!(debug::PFRAME, "uncaching {:?}", self);
-        // We have already been removed from pagetables.
+        self.remove_from_pts();
         unsafe { tlb::flush(self.page as *mut c_void) };
         unsafe { page::free(self.page as *mut c_void) };
     }
@@ -377,6 +486,6 @@ impl Drop for PFrame {
 impl fmt::Debug for PFrame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "PFrame {{ page: {}, flags: {:?}, obj: {:?} }}",
-               self.pagenum, self.flags.get(), self.get_mmo())
+               self.pagenum, self.flags.get(), self.obj.upgrade().map(|o| o.get_id()))
     }
 }
@@ -0,0 +1,138 @@
+// TODO Copyright Header
+
+//! Reverse mappings from physical pages to the user page tables they are mapped in.
+//!
+//! Whenever a user page is put in a page table through `map` we remember which page directory and
+//! address it went in, keyed by the physical page. Whoever is done with a page can then take it out
+//! of every process that has it mapped, or make it read only everywhere, without searching through
+//! every process. All user mappings must be made and removed through here for this to work.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use base::errno::{self, KResult};
+use libc::c_void;
+use mm::{page, pagetable, tlb};
+use mm::pagetable::PageDir;
+
+/// One place a page is mapped.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Mapping {
+    pd    : *mut PageDir,
+    vaddr : usize,
+}
+
+impl fmt::Debug for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mapping {{ pd: {:p}, vaddr: {:#x} }}", self.pd, self.vaddr)
+    }
+}
+
+static mut RMAP : *mut BTreeMap<usize, Vec<Mapping>> = 0 as *mut BTreeMap<usize, Vec<Mapping>>;
+
+pub fn init_stage2() {
+    use std::mem::transmute;
+    let rmap : Box<BTreeMap<usize, Vec<Mapping>>> = box BTreeMap::new();
+    unsafe { RMAP = transmute(rmap); }
+}
+
+/// Get the reverse mappings, keyed by physical page.
+fn get_rmap() -> &'static mut BTreeMap<usize, Vec<Mapping>> {
+    unsafe { RMAP.as_mut().expect("reverse mappings should not be null") }
+}
+
+/// Note that `paddr` is mapped at `m`. Nothing happens if we already knew.
+fn remember(paddr: usize, m: Mapping) -> KResult<()> {
+    let rmap = get_rmap();
+    if !rmap.contains_key(&paddr) {
+        try!(alloc!(try rmap.insert(paddr, Vec::new())).or(Err(errno::ENOMEM)));
+    }
+    let ms = rmap.get_mut(&paddr).expect("we just made sure it is there");
+    if !ms.contains(&m) {
+        try!(alloc!(try ms.push(m)).or(Err(errno::ENOMEM)));
+    }
+    Ok(())
+}
+
+/// Note that `paddr` is no longer mapped at `m`.
+fn forget(paddr: usize, m: Mapping) {
+    let rmap = get_rmap();
+    let empty = match rmap.get_mut(&paddr) {
+        Some(ms) => { ms.retain(|&o| o != m); ms.len() == 0 },
+        None => false,
+    };
+    if empty {
+        rmap.remove(&paddr);
+    }
+}
+
+/// Map the physical page `paddr` at `vaddr` in `pd`, replacing whatever was there before.
+pub unsafe fn map(pd: &mut PageDir, vaddr: usize, paddr: usize, pdflags: u32, ptflags: u32) -> KResult<()> {
+    let (vaddr, paddr) = (vaddr & page::MASK, paddr & page::MASK);
+    let m = Mapping { pd: pd as *mut PageDir, vaddr: vaddr };
+    let old = pd.lookup(vaddr).map(|(p, _)| p & page::MASK);
+    try!(remember(paddr, m));
+    if let Err(e) = pd.map(vaddr, paddr, pdflags, ptflags) {
+        if old != Some(paddr) {
+            forget(paddr, m);
+        }
+        return Err(e);
+    }
+    if let Some(o) = old {
+        if o != paddr {
+            forget(o, m);
+        }
+    }
+    dbg!(debug::RMAP, "mapped {:#x} at {:?}", paddr, m);
+    tlb::flush(vaddr as *mut c_void);
+    Ok(())
+}
+
+/// Unmap everything from `low` to `high` in `pd`.
+pub unsafe fn unmap_range(pd: &mut PageDir, low: usize, high: usize) {
+    let mut vaddr = low;
+    while vaddr < high {
+        if let Some((p, _)) = pd.lookup(vaddr) {
+            forget(p & page::MASK, Mapping { pd: pd as *mut PageDir, vaddr: vaddr });
+        }
+        vaddr += page::SIZE;
+    }
+    pd.unmap_range(low, high);
+    tlb::flush_range(low as *mut c_void, (high - low) / page::SIZE);
+}
+
+/// Take the physical page `paddr` out of every page table it is mapped in.
+pub fn unmap_all(paddr: usize) {
+    if let Some(ms) = get_rmap().remove(&paddr) {
+        for m in ms.iter() {
+            dbg!(debug::RMAP, "unmapping {:#x} from {:?}", paddr, m);
+            unsafe {
+                (*m.pd).unmap(m.vaddr);
+                tlb::flush(m.vaddr as *mut c_void);
+            }
+        }
+    }
+}
+
+/// Make every mapping of the physical page `paddr` read only, so the next write to it through any of
+/// them faults.
+pub fn write_protect_all(paddr: usize) {
+    if let Some(ms) = get_rmap().get(&paddr) {
+        for m in ms.iter() {
+            let pd = unsafe { &mut *m.pd };
+            let flags = match pd.lookup(m.vaddr) {
+                Some((_, f)) if f & pagetable::WRITE != 0 => f,
+                _ => { continue; },
+            };
+            dbg!(debug::RMAP, "write protecting {:#x} at {:?}", paddr, m);
+            let pdflags = pagetable::PRESENT | pagetable::USER | pagetable::WRITE;
+            unsafe {
+                pd.map(m.vaddr, paddr, pdflags as u32, (flags & !pagetable::WRITE) as u32)
+                  .ok().expect("changing the flags of a mapping that is already there should not need memory");
+                tlb::flush(m.vaddr as *mut c_void);
+            }
+        }
+    }
+}
+
+/// How many places the physical page `paddr` is mapped.
+pub fn count(paddr: usize) -> usize { get_rmap().get(&paddr).map(|ms| ms.len()).unwrap_or(0) }
@@ -0,0 +1,163 @@
+// TODO Copyright Header
+
+//! Shadow objects, which give private mappings and forked processes their own copies of pages.
+//!
+//! A shadow object sits on top of another object and holds copies of just the pages that have been
+//! written through it. Reads walk down the chain of shadows until they find someone with the page,
+//! ending up at the bottom object if nobody has written it. Writes copy the page up into the top
+//! shadow first. After a fork the parent and child each get a new shadow over what they had before,
+//! so the pages they share stay read only until one of them writes.
+//!
+//! When a shadow in the middle of a chain is only used by the one above it, such as once the other
+//! side of a fork has exited, the next fault through the chain moves its pages up and drops it out
+//! of the chain, so chains do not grow forever across many forks.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::ptr;
+use std::rc::{self, Rc};
+use base::devices::DeviceId;
+use base::errno::{self, KResult};
+use mm::page;
+use util::pinnable_cache::PinnedValue;
+use mmobj::{self, MMObj, MMObjId};
+use pframe::{PFrame, PFrameId};
+use anon::PageSet;
+
+/// The made up device shadow objects are numbered on.
+pub const SHADOW_DEVID : DeviceId = DeviceId_static!(0xFF, 1);
+
+static mut NEXT_SHADOW_ID : u32 = 0;
+
+pub struct ShadowObj {
+    id       : u32,
+    /// The object right below us.
+    shadowed : RefCell<Rc<Box<MMObj + 'static>>>,
+    /// The object at the bottom of the chain, which is never a shadow.
+    bottom   : Rc<Box<MMObj + 'static>>,
+    pages    : PageSet,
+}
+
+impl ShadowObj {
+    pub fn new(shadowed: Rc<Box<MMObj + 'static>>) -> ShadowObj {
+        let bottom = match shadowed.as_shadow() {
+            Some(s) => s.bottom.clone(),
+            None => shadowed.clone(),
+        };
+        let id = unsafe { NEXT_SHADOW_ID += 1; NEXT_SHADOW_ID };
+        dbg!(debug::ANON, "making shadow object {} over {:?}", id, shadowed);
+        ShadowObj { id: id, shadowed: RefCell::new(shadowed), bottom: bottom, pages: PageSet::new() }
+    }
+
+    /// Make a new shadow over `shadowed` ready to go in a `VMArea`.
+    pub fn create(shadowed: Rc<Box<MMObj + 'static>>) -> KResult<Rc<Box<MMObj + 'static>>> {
+        alloc!(try Rc::new(box ShadowObj::new(shadowed) as Box<MMObj + 'static>)).or(Err(errno::ENOMEM))
+    }
+
+    /// The object right below us.
+    pub fn shadowed(&self) -> Rc<Box<MMObj + 'static>> { self.shadowed.borrow().clone() }
+
+    /// The object at the bottom of our chain.
+    pub fn bottom(&self) -> Rc<Box<MMObj + 'static>> { self.bottom.clone() }
+}
+
+impl MMObj for ShadowObj {
+    fn get_id(&self) -> MMObjId { MMObjId::new(SHADOW_DEVID, self.id) }
+
+    /// We only ever make pages to copy them up, so fill them from whoever is below us unless we have
+    /// swapped them out.
+    fn fill_page(&self, pf: &mut PFrame) -> KResult<()> {
+        if let Some(res) = self.pages.fill(pf) {
+            return res;
+        }
+        let src = try!(mmobj::lookup_page(self.shadowed(), pf.get_pagenum(), false));
+        unsafe { ptr::copy_nonoverlapping(src.get_page() as *const [u8; page::SIZE], pf.get_page_mut() as *mut [u8; page::SIZE], 1); }
+        Ok(())
+    }
+    fn dirty_page(&self, _pf: &PFrame) -> KResult<()> { Ok(()) }
+    /// Our pages are only ever cleaned to swap them out.
+    fn clean_page(&self, pf: &PFrame) -> KResult<()> { self.pages.write_out(pf) }
+
+    fn lookup_page(&self, this: Rc<Box<MMObj + 'static>>, pagenum: usize, forwrite: bool) -> KResult<PinnedValue<'static, PFrameId, PFrame>> {
+        if forwrite {
+            return self.pages.lookup(this, pagenum);
+        }
+        if self.pages.contains(pagenum) {
+            return self.pages.lookup(this, pagenum);
+        }
+        let mut cur = self.shadowed();
+        loop {
+            let next = match cur.as_shadow() {
+                Some(s) => if s.pages.contains(pagenum) {
+                    return s.pages.lookup(cur.clone(), pagenum);
+                } else {
+                    s.shadowed()
+                },
+                None => { return mmobj::lookup_page(cur.clone(), pagenum, false); },
+            };
+            cur = next;
+        }
+    }
+
+    fn forget_pages(&self, start: usize, end: usize) { self.pages.forget(start, end); }
+    fn resident(&self) -> usize { self.pages.len() }
+
+    fn as_shadow(&self) -> Option<&ShadowObj> { Some(self) }
+}
+
+impl fmt::Debug for ShadowObj {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ShadowObj {{ id: {}, resident: {}, swapped: {}, bottom: {:?} }}",
+               self.id, self.pages.len(), self.pages.swapped_len(), self.bottom.get_id())
+    }
+}
+
+impl Drop for ShadowObj {
+    fn drop(&mut self) { dbg!(debug::ANON, "destroying {:?}", self); }
+}
+
+/// Whether `collapse` would drop anything out of the chain under `top`.
+pub fn is_collapsible(top: &Rc<Box<MMObj + 'static>>) -> bool {
+    let mut cur = top.clone();
+    loop {
+        let lower = match cur.as_shadow() {
+            Some(s) => s.shadowed(),
+            None => { return false; },
+        };
+        // One reference is the pointer to it from above and the other is ours.
+        if lower.as_shadow().is_some() && rc::strong_count(&lower) == 2 {
+            return true;
+        }
+        cur = lower;
+    }
+}
+
+/// Drop shadows out of the chain below `top` that only the shadow above them uses, moving any pages
+/// they have that the one above does not up into it. Nothing happens if `top` is not a shadow. The
+/// pages of the dropped shadows are freed, which unmaps them from anywhere they were mapped.
+pub fn collapse(top: &Rc<Box<MMObj + 'static>>) -> KResult<()> {
+    let mut upper = top.clone();
+    loop {
+        let lower = match upper.as_shadow() {
+            Some(s) => s.shadowed(),
+            None => { return Ok(()); },
+        };
+        let l = match lower.as_shadow() {
+            Some(l) => l,
+            None => { return Ok(()); },
+        };
+        if rc::strong_count(&lower) != 2 {
+            upper = lower.clone();
+            continue;
+        }
+        let u = upper.as_shadow().expect("upper must be a shadow");
+        dbg!(debug::ANON, "collapsing {:?} into {:?}", l, u);
+        for n in l.pages.pagenums().into_iter() {
+            if !u.pages.contains(n) {
+                // Filling the page copies it up from `lower`, which has it.
+                try!(u.pages.lookup(upper.clone(), n));
+            }
+        }
+        *u.shadowed.borrow_mut() = l.shadowed();
+    }
+}
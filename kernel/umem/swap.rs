@@ -0,0 +1,190 @@
+// TODO Copyright Header
+
+//! Swapping anonymous memory out to a block device.
+//!
+//! Anonymous and shadow objects have nowhere to write their pages back to, so they keep them all
+//! pinned. When memory gets low pageoutd asks them, through `swap_out`, to write some of their pages
+//! to a slot on the swap device and let the page frame go. The object remembers the slot and reads
+//! the page back in the next time someone looks it up, usually the fault handler.
+//!
+//! There is at most one swap device. It is just a run of page sized slots, a bitmap says which are
+//! in use. We live below the drivers so whoever turns it on gives us the device to use.
+
+use std::fmt;
+use base::devices::{Device, DeviceId};
+use base::errno::{self, KResult};
+use mm::page;
+use anon::PageSet;
+
+/// A swap device and which of its slots are in use.
+struct Swap {
+    id    : DeviceId,
+    dev   : Box<Device<[u8; page::SIZE]> + 'static>,
+    /// One bit per slot, set if it is in use.
+    used  : Vec<u32>,
+    nslots: usize,
+    nused : usize,
+}
+
+impl Swap {
+    fn is_used(&self, slot: usize) -> bool { self.used[slot / 32] & (1 << (slot % 32)) != 0 }
+
+    fn alloc_slot(&mut self) -> Option<usize> {
+        let word = match self.used.iter().position(|&w| w != !0) {
+            Some(w) => w,
+            None => { return None; },
+        };
+        let slot = word * 32 + (!self.used[word]).trailing_zeros() as usize;
+        if slot >= self.nslots {
+            return None;
+        }
+        self.used[word] |= 1 << (slot % 32);
+        self.nused += 1;
+        Some(slot)
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        assert!(slot < self.nslots && self.is_used(slot), "freeing swap slot {} which is not in use", slot);
+        self.used[slot / 32] &= !(1 << (slot % 32));
+        self.nused -= 1;
+    }
+}
+
+impl fmt::Debug for Swap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Swap {{ dev: {:?}, used: {}/{} }}", self.id, self.nused, self.nslots)
+    }
+}
+
+static mut SWAP : *mut Swap = 0 as *mut Swap;
+
+/// The page sets that might have pages for us to swap out.
+static mut SETS : *mut Vec<*const PageSet> = 0 as *mut Vec<*const PageSet>;
+
+/// Where in `SETS` the next `swap_out` starts, just past the last set the previous one used, so
+/// that the same sets are not picked on every time.
+static mut NEXT_SET : usize = 0;
+
+pub fn init_stage2() {
+    use std::mem::transmute;
+    let sets : Box<Vec<*const PageSet>> = box Vec::new();
+    unsafe { SETS = transmute(sets); }
+}
+
+fn get_swap() -> Option<&'static mut Swap> { unsafe { SWAP.as_mut() } }
+
+fn get_sets() -> &'static mut Vec<*const PageSet> { unsafe { SETS.as_mut().expect("swap page sets should not be null") } }
+
+/// Start swapping to `dev`, which has `nslots` pages of room and is called `id`.
+pub fn swapon(id: DeviceId, dev: Box<Device<[u8; page::SIZE]> + 'static>, nslots: usize) -> KResult<()> {
+    if get_swap().is_some() {
+        dbg!(debug::SWAP, "not swapping to {:?}, already swapping to {:?}", id, get_swap());
+        return Err(errno::EBUSY);
+    }
+    if nslots == 0 {
+        return Err(errno::EINVAL);
+    }
+    let mut used = try!(alloc!(try Vec::with_capacity((nslots + 31) / 32)).or(Err(errno::ENOMEM)));
+    for _ in 0..((nslots + 31) / 32) {
+        used.push(0);
+    }
+    // The bits past the end of the device are never free.
+    if nslots % 32 != 0 {
+        used[nslots / 32] = !0 << (nslots % 32);
+    }
+    let swap = try!(alloc!(try box Swap { id: id, dev: dev, used: used, nslots: nslots, nused: 0 }).or(Err(errno::ENOMEM)));
+    dbg!(debug::SWAP, "swapping to {:?}", swap);
+    unsafe { SWAP = ::std::mem::transmute(swap); }
+    Ok(())
+}
+
+/// Stop swapping, bringing everything that is swapped out back into memory first. If there is not
+/// enough memory for that we stay on with whatever is still swapped out.
+pub fn swapoff() -> KResult<()> {
+    if get_swap().is_none() {
+        return Err(errno::EINVAL);
+    }
+    let mut i = 0;
+    while i < get_sets().len() {
+        let set = unsafe { &*get_sets()[i] };
+        // Hold on to the owner so the set stays around while we wait for the disk.
+        if let Some(owner) = set.owner() {
+            try!(set.swap_in_all(owner.clone()));
+        }
+        i += 1;
+    }
+    let swap = get_swap().expect("swap went away while turning it off");
+    if swap.nused != 0 {
+        dbg!(debug::SWAP, "unable to bring everything back in from {:?}", swap);
+        return Err(errno::EBUSY);
+    }
+    dbg!(debug::SWAP, "no longer swapping to {:?}", swap);
+    let swap : Box<Swap> = unsafe { ::std::mem::transmute(SWAP) };
+    unsafe { SWAP = 0 as *mut Swap; }
+    drop(swap);
+    Ok(())
+}
+
+/// The device we are swapping to along with how many slots are in use and how many there are.
+pub fn usage() -> Option<(DeviceId, usize, usize)> { get_swap().map(|s| (s.id, s.nused, s.nslots)) }
+
+/// Write `buf` out to a free slot, returning the slot.
+pub fn write_page(buf: &[u8; page::SIZE]) -> KResult<usize> {
+    use std::slice::ref_slice;
+    let swap = try!(get_swap().ok_or(errno::ENOSPC));
+    let slot = try!(swap.alloc_slot().ok_or(errno::ENOSPC));
+    dbg!(debug::SWAP, "writing a page out to swap slot {}", slot);
+    if let Err(e) = swap.dev.write_to(slot, ref_slice(buf)) {
+        dbg!(debug::SWAP, "unable to write swap slot {}: {:?}", slot, e);
+        swap.free_slot(slot);
+        return Err(e);
+    }
+    Ok(slot)
+}
+
+/// Read the page in `slot` into `buf`. The slot stays in use.
+pub fn read_page(slot: usize, buf: &mut [u8; page::SIZE]) -> KResult<()> {
+    use std::slice::mut_ref_slice;
+    let swap = get_swap().expect("reading from swap when there is none");
+    assert!(swap.is_used(slot), "reading swap slot {} which is not in use", slot);
+    dbg!(debug::SWAP, "reading a page in from swap slot {}", slot);
+    swap.dev.read_from(slot, mut_ref_slice(buf)).map(|_| ())
+}
+
+/// We no longer need what is in `slot`.
+pub fn free_slot(slot: usize) {
+    get_swap().expect("freeing a swap slot when there is no swap").free_slot(slot);
+}
+
+/// Let `set` have its pages swapped out. It must not move until it is unregistered.
+pub fn register(set: *const PageSet) -> KResult<()> {
+    alloc!(try get_sets().push(set)).or(Err(errno::ENOMEM))
+}
+
+/// `set` is going away.
+pub fn unregister(set: *const PageSet) {
+    get_sets().retain(|&s| s != set);
+}
+
+/// Swap out up to `npages` pages, returning how many were. Each call carries on going through the
+/// sets from where the last one stopped.
+pub fn swap_out(npages: usize) -> usize {
+    if get_swap().is_none() {
+        return 0;
+    }
+    let mut cnt = 0;
+    let mut tried = 0;
+    // Writing pages out blocks, so sets may come and go while we are at it.
+    while cnt < npages && tried < get_sets().len() {
+        let i = unsafe { NEXT_SET } % get_sets().len();
+        unsafe { NEXT_SET = i + 1; }
+        let set = unsafe { &*get_sets()[i] };
+        if let Some(owner) = set.owner() {
+            cnt += set.swap_out(npages - cnt);
+            drop(owner);
+        }
+        tried += 1;
+    }
+    dbg!(debug::SWAP, "swapped out {} pages", cnt);
+    cnt
+}
@@ -0,0 +1,320 @@
+// TODO Copyright Header
+
+//! The virtual memory map of a user process.
+//!
+//! A `VMMap` is a sorted list of non-overlapping `VMArea`s, each of which says what object backs some
+//! range of pages and how they may be used. Everything here is in page numbers, `start` is the first
+//! page of an area and `end` is one past the last one. The map only describes the address space,
+//! whoever changes it must also use `unmap_pages` to take care of any page table entries for the
+//! pages involved.
+//!
+//! `KProc` lives below us so it holds its map as a `Box<Any>`, use `current` to get at it.
+
+use std::any::Any;
+use std::cmp;
+use std::fmt;
+use std::rc::Rc;
+use mm::{page, Allocation};
+use mm::memman::{prot, map};
+use mm::user::{MEM_LOW, MEM_HIGH};
+use mm::pagetable::PageDir;
+use base::errno::{self, KResult};
+use procs::futex;
+use rmap;
+use mmobj::{MMObj, MMObjId};
+
+/// The lowest page user memory may be in.
+pub const LOW_PAGE  : usize = MEM_LOW >> page::SHIFT;
+/// One past the highest page user memory may be in.
+pub const HIGH_PAGE : usize = MEM_HIGH >> page::SHIFT;
+
+/// Which end of user memory `find_range` should look at first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir { LowHigh, HighLow }
+
+/// A range of pages mapped from one object.
+pub struct VMArea {
+    /// The first page in the area.
+    pub start : usize,
+    /// One past the last page in the area.
+    pub end   : usize,
+    /// The page of `obj` that `start` maps.
+    pub off   : usize,
+    /// What may be done to the pages, from `memman::prot`.
+    pub prot  : isize,
+    /// How the pages are mapped, from `memman::map`.
+    pub flags : isize,
+    pub obj   : Rc<Box<MMObj + 'static>>,
+}
+
+impl VMArea {
+    pub fn new(start: usize, npages: usize, off: usize, prot: isize, flags: isize, obj: Rc<Box<MMObj + 'static>>) -> VMArea {
+        assert!(npages > 0);
+        VMArea { start: start, end: start + npages, off: off, prot: prot, flags: flags, obj: obj }
+    }
+
+    pub fn npages(&self) -> usize { self.end - self.start }
+
+    /// Whether `vfn` is in this area.
+    pub fn contains(&self, vfn: usize) -> bool { self.start <= vfn && vfn < self.end }
+
+    /// Whether this area has any pages in `[start, end)`.
+    pub fn overlaps(&self, start: usize, end: usize) -> bool { self.start < end && start < self.end }
+
+    /// The page of the backing object mapped at `vfn`.
+    pub fn obj_page(&self, vfn: usize) -> usize {
+        assert!(self.contains(vfn));
+        self.off + (vfn - self.start)
+    }
+
+    pub fn is_private(&self) -> bool { self.flags & map::PRIVATE != 0 }
+
+    /// A copy of the part of this area from `start` to `end`, sharing the object.
+    fn slice(&self, start: usize, end: usize) -> VMArea {
+        assert!(self.start <= start && start < end && end <= self.end);
+        VMArea { start: start, end: end, off: self.off + (start - self.start), prot: self.prot, flags: self.flags, obj: self.obj.clone() }
+    }
+}
+
+impl fmt::Debug for VMArea {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#010x}-{:#010x} {}{}{} {} off {:#x} {:?}",
+               self.start << page::SHIFT, self.end << page::SHIFT,
+               if self.prot & prot::READ != 0 { "r" } else { "-" },
+               if self.prot & prot::WRITE != 0 { "w" } else { "-" },
+               if self.prot & prot::EXEC != 0 { "x" } else { "-" },
+               if self.is_private() { "PRIVATE" } else { "SHARED" },
+               self.off, self.obj)
+    }
+}
+
+/// The areas making up a process's address space.
+pub struct VMMap {
+    /// Sorted by `start`, no two overlap.
+    areas   : Vec<VMArea>,
+    /// The page directory of the process we belong to, which lives as long as we do.
+    pagedir : *mut PageDir,
+}
+
+impl VMMap {
+    pub fn new(pd: &mut PageDir) -> VMMap { VMMap { areas: Vec::new(), pagedir: pd as *mut PageDir } }
+
+    pub fn areas(&self) -> &[VMArea] { &self.areas[..] }
+
+    /// The area holding the page `vfn`, if any.
+    pub fn lookup(&self, vfn: usize) -> Option<&VMArea> {
+        self.position(vfn).map(|i| &self.areas[i])
+    }
+
+    pub fn lookup_mut(&mut self, vfn: usize) -> Option<&mut VMArea> {
+        match self.position(vfn) {
+            Some(i) => Some(&mut self.areas[i]),
+            None => None,
+        }
+    }
+
+    fn position(&self, vfn: usize) -> Option<usize> {
+        self.areas.iter().position(|a| a.contains(vfn))
+    }
+
+    /// Whether none of the `npages` pages starting at `start` are mapped.
+    pub fn is_range_empty(&self, start: usize, npages: usize) -> bool {
+        let end = start + npages;
+        !self.areas.iter().any(|a| a.overlaps(start, end))
+    }
+
+    /// Find `npages` unmapped pages in a row in user memory, returning the first one. With
+    /// `Dir::HighLow` we pick the highest such range, otherwise the lowest.
+    pub fn find_range(&self, npages: usize, dir: Dir) -> Option<usize> {
+        if npages == 0 || npages > HIGH_PAGE - LOW_PAGE {
+            return None;
+        }
+        // The gaps between areas, including before the first and after the last.
+        let mut gaps = Vec::with_capacity(self.areas.len() + 1);
+        let mut prev = LOW_PAGE;
+        for a in self.areas.iter() {
+            gaps.push((prev, a.start));
+            prev = a.end;
+        }
+        gaps.push((prev, HIGH_PAGE));
+        let fits = |&&(lo, hi): &&(usize, usize)| { hi > lo && hi - lo >= npages };
+        match dir {
+            Dir::LowHigh => gaps.iter().find(fits).map(|&(lo, _)| lo),
+            Dir::HighLow => gaps.iter().rev().find(fits).map(|&(_, hi)| hi - npages),
+        }
+    }
+
+    /// Add `area` to the map. The pages it covers must not already be mapped.
+    pub fn insert(&mut self, area: VMArea) -> Allocation<()> {
+        assert!(LOW_PAGE <= area.start && area.start < area.end && area.end <= HIGH_PAGE,
+                "area {:?} is outside of user memory", area);
+        assert!(self.is_range_empty(area.start, area.npages()), "area {:?} overlaps an existing one", area);
+        let pos = self.areas.iter().position(|a| a.start > area.start).unwrap_or(self.areas.len());
+        dbg!(debug::VMMAP, "adding {:?}", area);
+        try!(alloc!(try self.areas.insert(pos, area)));
+        Ok(())
+    }
+
+    /// Unmap the `npages` pages starting at `start`. Areas only partly in the range are cut down,
+    /// and one that sticks out of both ends is split in two. It is fine for some or all of the range
+    /// to be unmapped already. Private areas have their own objects, so the pages those objects
+    /// keep for the range are thrown away; nothing can map them again.
+    pub fn remove(&mut self, start: usize, npages: usize) -> Allocation<()> {
+        let end = start + npages;
+        // Make room first so we cannot fail part way through.
+        try!(alloc!(try self.areas.reserve(1)));
+        let mut i = 0;
+        while i < self.areas.len() {
+            if !self.areas[i].overlaps(start, end) {
+                i += 1;
+                continue;
+            }
+            let (astart, aend) = (self.areas[i].start, self.areas[i].end);
+            dbg!(debug::VMMAP, "removing {:#x}-{:#x} from {:?}", start << page::SHIFT, end << page::SHIFT, self.areas[i]);
+            if self.areas[i].is_private() {
+                let a = &self.areas[i];
+                let (first, last) = (a.obj_page(cmp::max(astart, start)), a.obj_page(cmp::min(aend, end) - 1));
+                a.obj.forget_pages(first, last + 1);
+            }
+            if astart < start && end < aend {
+                let tail = self.areas[i].slice(end, aend);
+                self.areas[i].end = start;
+                self.areas.insert(i + 1, tail);
+                break;
+            } else if astart < start {
+                self.areas[i].end = start;
+                i += 1;
+            } else if end < aend {
+                let a = &mut self.areas[i];
+                a.off += end - a.start;
+                a.start = end;
+                break;
+            } else {
+                self.areas.remove(i);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the protection of the `npages` pages starting at `start` to `prot`, splitting areas that
+    /// are only partly in the range. The pages should all be mapped, any that are not are skipped.
+    pub fn set_prot(&mut self, start: usize, npages: usize, prot: isize) -> Allocation<()> {
+        let end = start + npages;
+        // At most two areas get split.
+        try!(alloc!(try self.areas.reserve(2)));
+        let mut i = 0;
+        while i < self.areas.len() {
+            if !self.areas[i].overlaps(start, end) || self.areas[i].prot == prot {
+                i += 1;
+                continue;
+            }
+            let (astart, aend) = (self.areas[i].start, self.areas[i].end);
+            if astart < start {
+                let tail = self.areas[i].slice(start, aend);
+                self.areas[i].end = start;
+                self.areas.insert(i + 1, tail);
+                i += 1;
+            }
+            if end < self.areas[i].end {
+                let tail = self.areas[i].slice(end, self.areas[i].end);
+                self.areas[i].end = end;
+                self.areas.insert(i + 1, tail);
+            }
+            dbg!(debug::VMMAP, "changing protection of {:?} to {:#x}", self.areas[i], prot);
+            self.areas[i].prot = prot;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// The number of pages mapped.
+    pub fn total_pages(&self) -> usize { self.areas.iter().fold(0, |t, a| t + a.npages()) }
+
+    /// The number of pages kept in memory by the objects we map, including those further down their
+    /// shadow chains. Pages of objects shared with other processes are counted for each of them.
+    pub fn resident_pages(&self) -> usize {
+        // Objects are often in more than one area, such as when munmap splits one. If there is no
+        // memory to remember which we have seen we count them twice, which is fine for a guess.
+        let mut seen : Vec<MMObjId> = Vec::new();
+        let mut total = 0;
+        for a in self.areas.iter() {
+            let mut cur = a.obj.clone();
+            loop {
+                let id = cur.get_id();
+                if !seen.contains(&id) {
+                    total += cur.resident();
+                    let _ = alloc!(try seen.push(id));
+                }
+                let next = match cur.as_shadow() {
+                    Some(s) => s.shadowed(),
+                    None => { break; },
+                };
+                cur = next;
+            }
+        }
+        total
+    }
+
+    /// Make a copy of this map for a new process with the page directory `pd`. The copy maps the
+    /// same objects, it is up to the caller to give private areas their own copies of the pages.
+    pub fn clone_map(&self, pd: &mut PageDir) -> Allocation<VMMap> {
+        let mut areas = try!(alloc!(try Vec::with_capacity(self.areas.len())));
+        for a in self.areas.iter() {
+            areas.push(a.slice(a.start, a.end));
+        }
+        Ok(VMMap { areas: areas, pagedir: pd as *mut PageDir })
+    }
+
+    /// Throw away the page table entries for the `npages` pages starting at `start`. They are put
+    /// back by the fault handler the next time they are touched.
+    pub fn unmap_pages(&mut self, start: usize, npages: usize) {
+        unsafe { rmap::unmap_range(&mut *self.pagedir, start << page::SHIFT, (start + npages) << page::SHIFT); }
+    }
+}
+
+impl Drop for VMMap {
+    fn drop(&mut self) {
+        // Our page directory goes away with us so none of our pages may remember being mapped in it.
+        let pd = self.pagedir;
+        for a in self.areas.iter() {
+            unsafe { rmap::unmap_range(&mut *pd, a.start << page::SHIFT, a.end << page::SHIFT); }
+        }
+    }
+}
+
+impl fmt::Debug for VMMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "VMMap {{"));
+        for a in self.areas.iter() {
+            try!(write!(f, "\n    {:?}", a));
+        }
+        write!(f, "\n}}")
+    }
+}
+
+/// Name the word at `addr` in the current process for the futex code, by the object it is mapped
+/// from and where in the object it is. That way the name does not change when the page is swapped
+/// out and is the same for everyone sharing the object.
+pub fn futex_key(addr: usize) -> Option<KResult<futex::Key>> {
+    let map = match current() { Ok(m) => m, Err(_) => { return None; } };
+    let vfn = addr >> page::SHIFT;
+    map.lookup(vfn).map(|a| {
+        if a.prot & prot::READ == 0 {
+            Err(errno::EFAULT)
+        } else {
+            let off = (a.obj_page(vfn) << page::SHIFT) + (addr & (page::SIZE - 1));
+            Ok(futex::Key::Object(a.obj.get_id().as_u64(), off))
+        }
+    })
+}
+
+/// The map of the current process, making it an empty one if it has none yet. Processes that only
+/// ever run in the kernel never need one.
+pub fn current() -> Allocation<&'static mut VMMap> {
+    let p = current_proc_mut!();
+    if p.get_vmmap().is_none() {
+        let m = try!(alloc!(try box VMMap::new(p.get_pagedir_mut())));
+        p.set_vmmap(Some(m as Box<Any>));
+    }
+    Ok(p.get_vmmap_mut().and_then(|m| m.downcast_mut::<VMMap>()).expect("process has something other than a VMMap as its vmmap"))
+}
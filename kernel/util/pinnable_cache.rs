@@ -233,6 +233,52 @@ impl<K: Ord, V: Cacheable> PinnableCache<K, V> {
             false
         }
     }
+
+    /// Removes the least-recently-used unpinned value for which `f` is true and free's it. Returns
+    /// true if a value was destroyed.
+    pub fn pop_unpinned_where<F: Fn(&V) -> bool>(&mut self, f: F) -> bool {
+        let found = self.unpinned_mut().iter_modify_least().find(|m| f(m.1.value()));
+        if let Some(m) = found {
+            let (_, v) = m.remove_entry();
+            assert!(v.pin_count() == 0);
+            drop(v);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Removes and free's the unpinned values for which `f` is true, least recently used first,
+    /// until `done` returns true. This only goes through the values once. Returns the number of
+    /// values destroyed.
+    pub fn pop_unpinned_until<F: Fn(&V) -> bool, D: Fn() -> bool>(&mut self, f: F, done: D) -> usize {
+        let mut cnt = 0;
+        for m in self.unpinned_mut().iter_modify_least() {
+            if done() {
+                break;
+            }
+            if f(m.1.value()) {
+                let (_, v) = m.remove_entry();
+                assert!(v.pin_count() == 0);
+                drop(v);
+                cnt += 1;
+            }
+        }
+        cnt
+    }
+}
+
+impl<K: Ord + Clone, V: Cacheable> PinnableCache<K, V> {
+    /// The keys of the unpinned values for which `f` is true, least recently used first.
+    pub fn unpinned_keys<F: Fn(&V) -> bool>(&self, f: F) -> Allocation<Vec<K>> {
+        let mut out = try!(alloc!(try Vec::with_capacity(self.num_unpinned())));
+        for (_, v) in self.unpinned().iter_least() {
+            if f(v.value()) {
+                out.push(v.key().clone());
+            }
+        }
+        Ok(out)
+    }
 }
 
 impl<'a, K, V:'a> PinnableCache<K, V> where K: Ord + Clone, V: TryMake<K, Errno> + Cacheable {
@@ -51,12 +51,16 @@ impl<U: Id> UIDSource<U> {
         })
     }
 
-    /// Try and get an identifier. Returns None if we could not find one.
+    /// Try and get an identifier. Returns None if we could not find one or are out of memory.
     pub fn get(&mut self) -> Option<U> {
         let init = self.cur.clone();
         while self.heap.contains(&self.cur) { self.cur.successor(); if self.cur == init { return None; } }
         let ret = self.cur.clone();
-        self.heap.insert(ret.clone());
+        if alloc!(try self.heap.insert(ret.clone())).is_err() {
+            // It might have gone in even though memory is now low.
+            self.heap.remove(&ret);
+            return None;
+        }
         self.cur.successor();
         return Some(ret)
     }
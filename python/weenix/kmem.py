@@ -91,13 +91,7 @@ def pagesize():
 
 def freepages():
 	freepages = dict()
-	for pagegroup in weenix.list.load("pagegroup_list", "struct pagegroup", "pg_link"):
-		freelist = pagegroup.item()["pg_freelist"]
-		for order in xrange(freelist.type.sizeof / freelist.type.target().sizeof):
-			psize = (1 << order) * PAGE_SIZE
-			count = len(weenix.list.load(freelist[order]))
-			if (order in freepages):
-				freepages[order] += count
-			else:
-				freepages[order] = count
+	counts = gdb.parse_and_eval("PAGE_FREE_BLOCKS")
+	for order in xrange(counts.type.sizeof / counts.type.target().sizeof):
+		freepages[order] = int(counts[order])
 	return freepages